@@ -2,27 +2,100 @@
 extern crate log;
 
 use std::convert::TryFrom;
+use std::fs::metadata;
 use std::path::PathBuf;
-use std::thread::spawn;
+use std::process::exit;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime};
 
+use crossbeam::{bounded, Sender};
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+use structopt::StructOpt;
+
+use config::args::{Args, Command};
+use config::error::ConfigError;
 use config::{env::Config as EnvConfig, raw::Config as RawConfig};
 use config::Config;
+use fs::offset::OffsetStore;
 use fs::tail::Tailer;
-use fs::watch::Watcher;
+use fs::watch::{Reload, Watcher};
 use http::client::Client;
 use http::retry::Retry;
+use http::types::request::RequestTemplate;
+use k8s::container_log::ContainerLog;
 use k8s::K8s;
+use metrics::health::HEALTH;
+use metrics::server::Server as MetricsServer;
+use middleware::multiline::MultilineMerger;
 use middleware::Executor;
 
 fn main() {
-    env_logger::init();
+    // the version is the agent's, not the config crate's where Args is defined
+    let args = Args::from_clap(&Args::clap().version(env!("CARGO_PKG_VERSION")).get_matches());
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(ref level) = args.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
 
-    let config = match Config::new() {
-        Ok(v) => v,
+    match args.command() {
+        Command::Run => run(args),
+        Command::Validate => validate(&args),
+        Command::PrintDefaultConfig => {
+            print!("{}", serde_yaml::to_string(&RawConfig::default()).expect("serde_yaml::to_string()"))
+        }
+        Command::Version => println!("{}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+// loads the config the agent would run with, reporting every problem and exiting with an error if it's invalid
+fn validate(args: &Args) {
+    let validation = Config::validate(args);
+    if let Some(ref path) = validation.missing_file {
+        eprintln!("warning: {}: doesn't exist, using the default config", path.display());
+    }
+    for key in &validation.unknown_keys {
+        eprintln!("warning: {}: unknown key, it's ignored", key);
+    }
+    let errors = match validation.config {
+        Ok(_) => {
+            println!("config is valid");
+            return;
+        }
+        Err(ConfigError::Invalid(errors)) => errors,
+        Err(e) => vec![e],
+    };
+    for e in errors {
+        eprintln!("error: {}", e);
+    }
+    exit(1);
+}
+
+fn run(args: Args) {
+    let validation = Config::validate(&args);
+    if let Some(ref path) = validation.missing_file {
+        warn!("config file {} doesn't exist, using the default config", path.display());
+    }
+    for key in &validation.unknown_keys {
+        warn!("ignoring unknown config key {}", key);
+    }
+    let config = match validation.config {
+        Ok(v) => {
+            HEALTH.set_config_loaded(true);
+            v
+        }
+        Err(e) if !args.default_config_fallback => {
+            error!("failed to load config, run with validate for details:\n{}", e);
+            exit(1);
+        }
         Err(e) => {
             error!("failed to load config: {}", e);
             warn!("falling back to default config!");
-            match Config::try_from((EnvConfig::parse(), RawConfig::default())) {
+            let mut env_config = EnvConfig::parse();
+            let mut raw_config = RawConfig::default();
+            match args.merge(&mut env_config, &mut raw_config).and_then(|_| Config::try_from((env_config, raw_config))) {
                 Ok(v) => v,
                 Err(e) => {
                     error!("falling back to default failed: {}", e);
@@ -32,33 +105,180 @@ fn main() {
         }
     };
 
-    let watcher = Watcher::builder()
+    if let Some(timeout) = config.health.stall_timeout {
+        HEALTH.set_stall_timeout(timeout);
+    }
+    if let Some(window) = config.health.ingest_window {
+        HEALTH.set_ingest_window(window);
+    }
+    if let Some(addr) = config.metrics {
+        match MetricsServer::bind(addr) {
+            Ok(server) => {
+                info!("serving metrics on {}", addr);
+                spawn(move || server.run());
+            }
+            Err(e) => error!("failed to serve metrics on {}: {}", addr, e),
+        }
+    }
+
+    // dropped on SIGTERM/SIGINT, which starts the shutdown of each stage in turn
+    // the watcher stops, the tailer, executor and client drain whatever they hold, then the retry queue spools the rest
+    let (shutdown_sender, shutdown_receiver) = bounded::<()>(0);
+    let (reload_sender, reload_receiver) = bounded(1);
+
+    let mut watcher = Watcher::builder()
+        .shutdown(shutdown_receiver)
+        .reload(reload_receiver)
         .add_all(config.log.dirs)
-        .append_all(config.log.rules)
-        .build()
-        .unwrap();
+        .poll_all(config.log.poll_dirs)
+        .append_all(config.log.rules);
+    if let Some(interval) = config.log.poll_interval {
+        watcher = watcher.poll_interval(interval);
+    }
+    let watcher = watcher.build().unwrap();
 
-    let tailer = Tailer::new();
+    let mut tailer = Tailer::new();
+    tailer.set_offset_store(OffsetStore::new(config.log.offset_file));
     let tailer_sender = tailer.sender();
 
     let mut client = Client::new(config.http.template);
     client.set_max_buffer_size(config.http.body_size);
     client.set_timeout(config.http.timeout);
+    if let Some(statuses) = config.http.retry_statuses {
+        client.set_retry_statuses(statuses);
+    }
+    if let Some(statuses) = config.http.permanent_statuses {
+        client.set_permanent_statuses(statuses);
+    }
     let (client_sender, client_retry_sender) = client.sender();
+    let template_sender = client.template_sender();
 
     let mut executor = Executor::new();
     let executor_sender = executor.sender();
-    executor.add_sender(client_sender.clone());
+    executor.add_sender(client_sender);
+    // before K8s, so it's line exclusions match the message the container logged
+    executor.register(ContainerLog::new());
+    // after the container log, so container lines are merged by the message the container logged
+    let multiline = MultilineMerger::new(config.log.multiline);
+    if !multiline.is_empty() {
+        executor.register(multiline);
+    }
     if PathBuf::from("/var/log/containers/").exists() {
-        executor.register(K8s::new());
+        let mut k8s = K8s::new();
+        k8s.set_namespace_defaults(config.log.k8s.namespaces);
+        k8s.set_namespaces(config.log.k8s.include_namespaces, config.log.k8s.exclude_namespaces);
+        k8s.set_selector(config.log.k8s.selector);
+        executor.register(k8s);
+    }
+    if !config.log.line_filter.is_empty() {
+        executor.register(config.log.line_filter);
+    }
+    // after the line filter, so dropped lines aren't scanned
+    if !config.log.redact.is_empty() {
+        executor.register(config.log.redact);
+    }
+    if !config.log.json.is_empty() {
+        executor.register(config.log.json);
+    }
+    // after the json parser, which sets the level of json lines
+    if !config.log.level.is_empty() {
+        executor.register(config.log.level);
+    }
+    // after the container log and json parsers, whose timestamps take precedence
+    if !config.log.timestamp.is_empty() {
+        executor.register(config.log.timestamp);
     }
 
-    let retry = Retry::new();
+    let mut retry = Retry::new();
+    if let Some(dir) = config.http.retry.dir {
+        retry.set_dir(dir);
+    }
+    if let Some(bytes) = config.http.retry.max_bytes {
+        retry.set_max_bytes(bytes);
+    }
+    if let Some(files) = config.http.retry.max_files {
+        retry.set_max_files(files);
+    }
+    if let Some(eviction) = config.http.retry.eviction {
+        retry.set_eviction(eviction);
+    }
+    if let Some(dir) = config.http.retry.dead_letter_dir {
+        retry.set_dead_letter_dir(dir);
+    }
+    if let Some(delay) = config.http.retry.base_delay {
+        retry.set_base_delay(delay);
+    }
+    if let Some(delay) = config.http.retry.max_delay {
+        retry.set_max_delay(delay);
+    }
+    if let Some(age) = config.http.retry.max_age {
+        retry.set_max_age(age);
+    }
+    if let Some(attempts) = config.http.retry.max_attempts {
+        retry.set_max_attempts(attempts);
+    }
     let retry_sender = retry.sender();
 
+    let signals = Signals::new([SIGTERM, SIGINT]).expect("failed to register signal handlers");
+    spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("received signal {}, shutting down", signal);
+            drop(shutdown_sender);
+        }
+        if signals.next().is_some() {
+            warn!("received second signal, exiting without flushing");
+            exit(1);
+        }
+    });
+
     spawn(move || tailer.run(executor_sender));
     spawn(move || executor.run());
-    spawn(move || retry.run(client_retry_sender));
+    let retry = spawn(move || retry.run(client_retry_sender));
     spawn(move || watcher.run(tailer_sender));
+    spawn(move || reload(args, reload_sender, template_sender));
     client.run(retry_sender);
-}
\ No newline at end of file
+    retry.join().expect("retry thread panicked");
+    info!("shutdown complete");
+}
+
+// reloads the config on SIGHUP or when the config file changes, applying the new dirs, rules and request template
+// a config that fails to load is logged and the running config is kept
+fn reload(args: Args, watcher: Sender<Reload>, client: Sender<RequestTemplate>) {
+    let signals = Signals::new([SIGHUP]).expect("failed to register signal handlers");
+    let config_file = args.config_file.clone().unwrap_or_else(|| EnvConfig::parse().config_file);
+    let modified = || metadata(&config_file).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified();
+
+    loop {
+        sleep(Duration::from_secs(1));
+        let hangup = signals.pending().next().is_some();
+        let current = modified();
+        if !hangup && current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        info!("reloading config from {:?}", config_file);
+        // the agent isn't ready while it's config doesn't load, even though the running config is kept
+        let config = match Config::load(&args) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to reload config, keeping the running config: {}", e);
+                HEALTH.set_config_loaded(false);
+                continue;
+            }
+        };
+        HEALTH.set_config_loaded(true);
+
+        let reload = Reload {
+            dirs: config.log.dirs,
+            poll_dirs: config.log.poll_dirs,
+            rules: config.log.rules,
+        };
+        // both senders only fail once the agent is shutting down
+        if watcher.send(reload).is_err() || client.send(config.http.template).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs::write;
+use std::process::{Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+use config::raw::Config as RawConfig;
+
+mod common;
+
+use common::{config, write_config};
+
+// runs the agent with args, without an ingestion key in the environment
+fn agent(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_logdna-agent"))
+        .args(args)
+        .env_remove("LOGDNA_INGESTION_KEY")
+        .env_remove("LOGDNA_AGENT_KEY")
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn prints_version_and_default_config() {
+    let output = agent(&["version"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).trim(), env!("CARGO_PKG_VERSION"));
+
+    let output = agent(&["print-default-config"]);
+    assert!(output.status.success());
+    let printed: RawConfig = serde_yaml::from_str(&stdout(&output)).unwrap();
+    assert_eq!(printed, RawConfig::default());
+}
+
+#[test]
+fn checks_config_with_flags() {
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.yaml");
+    write_config(&config_path, &config(dir.path(), "localhost:80".to_string()));
+    let config_path = config_path.to_str().unwrap();
+
+    // the ingestion key is required
+    let output = agent(&["check-config", "-c", config_path]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let key_file = dir.path().join("key");
+    write(&key_file, "key\n").unwrap();
+    let output = agent(&["validate", "-c", config_path, "--ingestion-key-file", key_file.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout(&output).trim(), "config is valid");
+
+    write(dir.path().join("invalid.yaml"), "http: [").unwrap();
+    let invalid = dir.path().join("invalid.yaml");
+    let output = agent(&["check-config", "-c", invalid.to_str().unwrap(), "--ingestion-key-file", key_file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn validate_reports_every_problem() {
+    let dir = tempdir().unwrap();
+    let key_file = dir.path().join("key");
+    write(&key_file, "key").unwrap();
+    let config_path = dir.path().join("config.yaml");
+    let mut raw = config(dir.path(), "localhost:80".to_string());
+    raw.log.exclude.as_mut().unwrap().glob = vec!["*.gz".to_string(), "[".to_string()];
+    raw.log.exclude.as_mut().unwrap().regex = vec!["(unclosed".to_string()];
+    let mut yaml = serde_yaml::to_string(&raw).unwrap();
+    yaml.push_str("\nlvl: debug\n");
+    write(&config_path, yaml).unwrap();
+
+    let output = agent(&["validate", "-c", config_path.to_str().unwrap(), "--ingestion-key-file", key_file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: lvl: unknown key"), "{}", stderr);
+    assert!(stderr.contains("error: log.exclude.glob[1]:"), "{}", stderr);
+    assert!(stderr.contains("error: log.exclude.regex[0]:"), "{}", stderr);
+
+    // a missing file is the default config, which is valid once there's a key
+    let missing = dir.path().join("missing.yaml");
+    let output = agent(&["validate", "-c", missing.to_str().unwrap(), "--ingestion-key-file", key_file.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("warning: {}: doesn't exist", missing.display())), "{}", stderr);
+}
+
+#[test]
+fn runs_without_config_file() {
+    let dir = tempdir().unwrap();
+    let missing = dir.path().join("missing.yaml");
+
+    // the key is only in the environment, like the k8s manifest sets it
+    let output = Command::new(env!("CARGO_BIN_EXE_logdna-agent"))
+        .arg("validate")
+        .env("LOGDNA_CONFIG_FILE", &missing)
+        .env("LOGDNA_INGESTION_KEY", "key")
+        .env_remove("LOGDNA_AGENT_KEY")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout(&output).trim(), "config is valid");
+
+    let mut agent = Command::new(env!("CARGO_BIN_EXE_logdna-agent"))
+        .env("LOGDNA_CONFIG_FILE", &missing)
+        .env("LOGDNA_INGESTION_KEY", "key")
+        .env("LOGDNA_LOG_DIRS", dir.path())
+        .env("LOGDNA_HOST", "127.0.0.1:1")
+        .env("LOGDNA_USE_SSL", "false")
+        .env("LOGDNA_OFFSET_FILE", dir.path().join("offsets"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(1));
+    // still running rather than exiting on the missing file
+    assert!(agent.try_wait().unwrap().is_none());
+    agent.kill().unwrap();
+    agent.wait().unwrap();
+}
+
+#[test]
+fn exits_on_invalid_config() {
+    let dir = tempdir().unwrap();
+    let config_path = dir.path().join("config.yaml");
+    write(&config_path, "http: [").unwrap();
+
+    let output = agent(&["-c", config_path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,162 @@
+// each test binary only uses some of these helpers
+#![allow(dead_code)]
+
+use std::fs::{read_dir, read_to_string, write};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use config::raw::Config as RawConfig;
+
+// accepts ingest requests, recording the body of each one
+pub fn mock_server(bodies: Arc<Mutex<Vec<String>>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    spawn(move || {
+        for stream in listener.incoming() {
+            let bodies = bodies.clone();
+            spawn(move || handle(stream.unwrap(), bodies));
+        }
+    });
+    addr
+}
+
+// answers every request on a connection until the client closes it
+fn handle(mut stream: TcpStream, bodies: Arc<Mutex<Vec<String>>>) {
+    let mut buf = Vec::new();
+    loop {
+        let body = match read_request(&mut stream, &mut buf) {
+            Some(v) => v,
+            None => return,
+        };
+        bodies.lock().unwrap().push(body);
+        if stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").is_err() {
+            return;
+        }
+    }
+}
+
+// reads a single request from the stream, returning it's body with any chunked encoding removed
+fn read_request(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Option<String> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some((body, len)) = parse_request(buf) {
+            buf.drain(..len);
+            return Some(body);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// parses a complete request, returning the body and the length of the request
+fn parse_request(buf: &[u8]) -> Option<(String, usize)> {
+    let text = String::from_utf8_lossy(buf);
+    let head_end = text.find("\r\n\r\n")? + 4;
+    let head = text[..head_end].to_lowercase();
+    let content_length = head.lines()
+        .find(|l| l.starts_with("content-length:"))
+        .and_then(|l| l["content-length:".len()..].trim().parse::<usize>().ok());
+
+    if let Some(len) = content_length {
+        let body = buf.get(head_end..head_end + len)?;
+        return Some((String::from_utf8_lossy(body).into_owned(), head_end + len));
+    }
+
+    let mut body = Vec::new();
+    let mut pos = head_end;
+    loop {
+        let size_end = pos + find(&buf[pos..], b"\r\n")?;
+        let size = usize::from_str_radix(std::str::from_utf8(&buf[pos..size_end]).ok()?.trim(), 16).ok()?;
+        let data_start = size_end + 2;
+        body.extend_from_slice(buf.get(data_start..data_start + size)?);
+        pos = data_start + size + 2;
+        if pos > buf.len() {
+            return None;
+        }
+        if size == 0 {
+            return Some((String::from_utf8_lossy(&body).into_owned(), pos));
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// returns the lines of a serialized ingest body
+pub fn lines(body: &str) -> Vec<String> {
+    let body: serde_json::Value = serde_json::from_str(body).unwrap();
+    body["lines"].as_array().unwrap()
+        .iter()
+        .map(|l| l["line"].as_str().unwrap().to_string())
+        .collect()
+}
+
+// returns the lines of every body spooled in dir
+pub fn spooled(dir: &Path) -> Vec<String> {
+    match read_dir(dir) {
+        Ok(entries) => entries
+            .map(|e| read_to_string(e.unwrap().path()).unwrap())
+            .flat_map(|b| lines(&b))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// returns a config that sends to addr without tls or compression, spooling retries in dir/retry
+pub fn config(dir: &Path, addr: String) -> RawConfig {
+    let mut config = RawConfig::default();
+    config.log.offset_file = Some(dir.join("offsets"));
+    config.http.use_ssl = Some(false);
+    config.http.use_compression = Some(false);
+    config.http.host = Some(addr);
+    config.http.retry.as_mut().unwrap().dir = Some(dir.join("retry"));
+    config
+}
+
+pub fn write_config(path: &Path, config: &RawConfig) {
+    write(path, serde_yaml::to_string(config).unwrap()).unwrap();
+}
+
+// starts the agent with the config at path, giving it time to start watching
+pub fn start_agent(config_path: &Path) -> Child {
+    let agent = Command::new(env!("CARGO_BIN_EXE_logdna-agent"))
+        .env("LOGDNA_CONFIG_FILE", config_path)
+        .env("LOGDNA_INGESTION_KEY", "key")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(1));
+    agent
+}
+
+// sends a signal to the agent
+pub fn signal(agent: &Child, signal: &str) {
+    let status = Command::new("kill").arg(format!("-{}", signal)).arg(agent.id().to_string()).status().unwrap();
+    assert!(status.success());
+}
+
+// sends SIGTERM and waits for the agent to exit successfully
+pub fn stop_agent(mut agent: Child) {
+    signal(&agent, "TERM");
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = agent.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > Duration::from_secs(30) {
+            agent.kill().unwrap();
+            panic!("agent didn't shut down");
+        }
+        sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+}
@@ -0,0 +1,117 @@
+use std::fs::{create_dir, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use tempfile::tempdir;
+
+use config::raw::{HealthConfig, MetricsConfig};
+
+mod common;
+
+use common::{config, lines, mock_server, start_agent, stop_agent, write_config};
+
+const LINES: usize = 10;
+
+// returns the response to a GET of path
+fn get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn serves_pipeline_metrics() {
+    let dir = tempdir().unwrap();
+    let log_dir = dir.path().join("logs");
+    create_dir(&log_dir).unwrap();
+    let port = free_port();
+
+    let bodies = Arc::new(Mutex::new(Vec::new()));
+    let mut config = config(dir.path(), mock_server(bodies.clone()));
+    config.log.dirs = vec![log_dir.clone()];
+    config.metrics = Some(MetricsConfig { host: Some("127.0.0.1".to_string()), port: Some(port) });
+    let config_path = dir.path().join("config.yaml");
+    write_config(&config_path, &config);
+
+    let agent = start_agent(&config_path);
+    let file = log_dir.join("app.log");
+    let mut writer = OpenOptions::new().create(true).append(true).open(&file).unwrap();
+    for i in 0..LINES {
+        writeln!(writer, "line {}", i).unwrap();
+    }
+
+    let start = Instant::now();
+    while bodies.lock().unwrap().iter().flat_map(|b| lines(b)).count() < LINES {
+        assert!(start.elapsed() < Duration::from_secs(10), "lines weren't sent");
+        sleep(Duration::from_millis(50));
+    }
+    // responses are counted after the mock server has the body
+    sleep(Duration::from_millis(200));
+    let metrics = get(port, "/metrics");
+    let requests = bodies.lock().unwrap().len();
+    let healthz = get(port, "/healthz");
+    let readyz = get(port, "/readyz");
+    stop_agent(agent);
+
+    assert!(metrics.starts_with("HTTP/1.1 200 OK"), "{}", metrics);
+    assert!(healthz.starts_with("HTTP/1.1 200 OK"), "{}", healthz);
+    assert!(readyz.starts_with("HTTP/1.1 200 OK"), "{}", readyz);
+
+    let file = file.to_str().unwrap();
+    for expected in &[
+        format!("logdna_agent_fs_lines_total{{file=\"{}\"}} {}", file, LINES),
+        "logdna_agent_fs_files 1".to_string(),
+        format!("logdna_agent_middleware_lines_total{{result=\"sent\"}} {}", LINES),
+        format!("logdna_agent_http_buffered_lines_total {}", LINES),
+        format!("logdna_agent_http_requests_total{{result=\"ok\"}} {}", requests),
+        format!("logdna_agent_http_request_duration_seconds_count {}", requests),
+        "logdna_agent_channel_capacity{stage=\"client\"} 256".to_string(),
+        "logdna_agent_retry_spool_bodies 0".to_string(),
+    ] {
+        assert!(metrics.contains(expected.as_str()), "missing {} in\n{}", expected, metrics);
+    }
+}
+
+#[test]
+fn becomes_unready_when_ingest_fails() {
+    let dir = tempdir().unwrap();
+    let log_dir = dir.path().join("logs");
+    create_dir(&log_dir).unwrap();
+    let port = free_port();
+
+    // nothing listens on the ingest port, so every request fails
+    let mut config = config(dir.path(), format!("127.0.0.1:{}", free_port()));
+    config.log.dirs = vec![log_dir.clone()];
+    config.metrics = Some(MetricsConfig { host: Some("127.0.0.1".to_string()), port: Some(port) });
+    config.health = Some(HealthConfig { stall_timeout: None, ingest_window: Some(500) });
+    let config_path = dir.path().join("config.yaml");
+    write_config(&config_path, &config);
+
+    let agent = start_agent(&config_path);
+    let mut writer = OpenOptions::new().create(true).append(true).open(log_dir.join("app.log")).unwrap();
+    writeln!(writer, "line").unwrap();
+
+    let start = Instant::now();
+    let readyz = loop {
+        let readyz = get(port, "/readyz");
+        if readyz.starts_with("HTTP/1.1 503") || start.elapsed() > Duration::from_secs(10) {
+            break readyz;
+        }
+        sleep(Duration::from_millis(100));
+    };
+    let healthz = get(port, "/healthz");
+    stop_agent(agent);
+
+    assert!(readyz.contains("no successful ingest request"), "{}", readyz);
+    // the agent is still alive, it just can't ship
+    assert!(healthz.starts_with("HTTP/1.1 200 OK"), "{}", healthz);
+}
@@ -0,0 +1,47 @@
+use std::fs::{create_dir, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+mod common;
+
+use common::{config, lines, mock_server, signal, start_agent, stop_agent, write_config};
+
+#[test]
+fn reloads_config_on_sighup() {
+    let dir = tempdir().unwrap();
+    let old_dir = dir.path().join("old");
+    let new_dir = dir.path().join("new");
+    create_dir(&old_dir).unwrap();
+    create_dir(&new_dir).unwrap();
+
+    let bodies = Arc::new(Mutex::new(Vec::new()));
+    let mut config = config(dir.path(), mock_server(bodies.clone()));
+    config.log.dirs = vec![old_dir.clone()];
+    let config_path = dir.path().join("config.yaml");
+    write_config(&config_path, &config);
+    let agent = start_agent(&config_path);
+
+    // an invalid config is rejected and the old one keeps running
+    std::fs::write(&config_path, "http: [").unwrap();
+    signal(&agent, "HUP");
+    sleep(Duration::from_secs(2));
+
+    config.log.dirs = vec![new_dir.clone()];
+    write_config(&config_path, &config);
+    signal(&agent, "HUP");
+    sleep(Duration::from_secs(2));
+
+    for dir in &[&old_dir, &new_dir] {
+        let mut file = OpenOptions::new().create(true).append(true).open(dir.join("app.log")).unwrap();
+        writeln!(file, "{} line", dir.file_name().unwrap().to_str().unwrap()).unwrap();
+    }
+    sleep(Duration::from_millis(200));
+    stop_agent(agent);
+
+    let received: Vec<String> = bodies.lock().unwrap().iter().flat_map(|b| lines(b)).collect();
+    assert_eq!(received, vec!["new line".to_string()]);
+}
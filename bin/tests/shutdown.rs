@@ -0,0 +1,43 @@
+use std::fs::{create_dir, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use tempfile::tempdir;
+
+mod common;
+
+use common::{config, lines, mock_server, spooled, start_agent, stop_agent, write_config};
+
+const LINES: usize = 1000;
+
+#[test]
+fn flushes_lines_on_sigterm() {
+    let dir = tempdir().unwrap();
+    let log_dir = dir.path().join("logs");
+    create_dir(&log_dir).unwrap();
+
+    let bodies = Arc::new(Mutex::new(Vec::new()));
+    let mut config = config(dir.path(), mock_server(bodies.clone()));
+    config.log.dirs = vec![log_dir.clone()];
+    // a large buffer means the lines are still buffered when the signal arrives
+    config.http.body_size = Some(16 * 1024 * 1024);
+    let config_path = dir.path().join("config.yaml");
+    write_config(&config_path, &config);
+
+    let agent = start_agent(&config_path);
+    let mut file = OpenOptions::new().create(true).append(true).open(log_dir.join("app.log")).unwrap();
+    for i in 0..LINES {
+        writeln!(file, "line {}", i).unwrap();
+    }
+    sleep(Duration::from_millis(200));
+    stop_agent(agent);
+
+    let mut received: Vec<String> = bodies.lock().unwrap().iter().flat_map(|b| lines(b)).collect();
+    received.extend(spooled(&dir.path().join("retry")));
+    received.sort();
+    let mut expected: Vec<String> = (0..LINES).map(|i| format!("line {}", i)).collect();
+    expected.sort();
+    assert_eq!(received, expected);
+}
@@ -93,7 +93,7 @@ fn generate_env_vars(
             tokens.append_all(quote!(#env_var,))
         }
 
-        if let Some(default) = default_map.get(&field) {
+        if let Some(default) = default_map.get(field) {
             fields.append_all(quote! {
                 #field: parse_value(&[#tokens])
                     .unwrap_or_else(|| std::str::FromStr::from_str(#default).unwrap()),
@@ -123,7 +123,7 @@ fn generate_env_vars(
         });
     }
 
-    return quote! {
+    quote! {
 
         impl #name {
             pub fn parse() -> Self {
@@ -149,7 +149,7 @@ fn generate_env_vars(
             first_non_empty(envs).and_then(|s| T::from_str(&s).ok())
         }
 
-    };
+    }
 }
 
 fn generate_tests(
@@ -166,8 +166,8 @@ fn generate_tests(
             tokens.append_all(quote!(#env_var,))
         }
 
-        if let Some(test_data) = example_map.get(&field) {
-            if let Some(_) = default_map.get(&field) {
+        if let Some(test_data) = example_map.get(field) {
+            if default_map.get(field).is_some() {
                 tests.append_all(quote! {
                     for env in &[#tokens] {
                         // reset env vars
@@ -193,7 +193,7 @@ fn generate_tests(
         }
     }
 
-    return quote! {
+    quote! {
 
         #[cfg(test)]
         mod env_config_tests {
@@ -205,5 +205,5 @@ fn generate_tests(
             }
         }
 
-    };
+    }
 }
\ No newline at end of file
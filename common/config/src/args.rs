@@ -0,0 +1,124 @@
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::env::Config as EnvConfig;
+use crate::error::ConfigError;
+use crate::raw::Config as RawConfig;
+
+/// The command line of the agent, flags take precedence over env vars and the config file
+#[derive(StructOpt, Debug, Default, Clone, PartialEq)]
+#[structopt(name = "logdna-agent", about = "Ships log files to LogDNA")]
+pub struct Args {
+    /// The config file, defaults to the file in LOGDNA_CONFIG_FILE
+    #[structopt(short, long = "config", global = true, parse(from_os_str))]
+    pub config_file: Option<PathBuf>,
+    /// A dir to watch for logs, replaces the dirs from env vars and the config file, can be repeated
+    #[structopt(short = "d", long = "log-dir", global = true, parse(from_os_str), number_of_values = 1)]
+    pub log_dirs: Vec<PathBuf>,
+    /// A file holding the ingestion key
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub ingestion_key_file: Option<PathBuf>,
+    /// The log level of the agent, e.g info or debug, in the same format as RUST_LOG
+    #[structopt(short, long, global = true)]
+    pub log_level: Option<String>,
+    /// Runs with the default config when the config fails to load, instead of exiting
+    #[structopt(long, global = true)]
+    pub default_config_fallback: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Runs the agent, the default
+    Run,
+    /// Loads the config and reports every error along with the keys that are ignored
+    #[structopt(alias = "check-config")]
+    Validate,
+    /// Prints the default config file
+    PrintDefaultConfig,
+    /// Prints the version of the agent
+    Version,
+}
+
+impl Args {
+    /// Returns the command to run
+    pub fn command(&self) -> Command {
+        self.command.clone().unwrap_or(Command::Run)
+    }
+    /// Applies the flags over the values from env vars and the config file
+    pub fn merge(&self, env: &mut EnvConfig, raw: &mut RawConfig) -> Result<(), ConfigError> {
+        if let Some(ref path) = self.config_file {
+            env.config_file = path.clone();
+        }
+        if let Some(ref path) = self.ingestion_key_file {
+            let key = read_to_string(path)?;
+            env.ingestion_key = Some(key.trim().to_string());
+        }
+        if !self.log_dirs.is_empty() {
+            env.log_dirs = None;
+            raw.log.dirs = self.log_dirs.clone();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    fn args(args: &[&str]) -> Args {
+        Args::from_iter_safe(Some("logdna-agent").iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn parses_args() {
+        assert_eq!(args(&[]), Args::default());
+        assert_eq!(args(&[]).command(), Command::Run);
+
+        let parsed = args(&["validate", "-c", "/etc/agent.yaml", "-d", "/var/log", "--log-dir", "/opt/log"]);
+        assert_eq!(parsed.command(), Command::Validate);
+        assert_eq!(parsed.config_file, Some(PathBuf::from("/etc/agent.yaml")));
+        assert_eq!(parsed.log_dirs, vec![PathBuf::from("/var/log"), PathBuf::from("/opt/log")]);
+
+        assert_eq!(args(&["check-config"]).command(), Command::Validate);
+        assert!(!args(&[]).default_config_fallback);
+        assert!(args(&["--default-config-fallback"]).default_config_fallback);
+        assert!(Args::from_iter_safe(&["logdna-agent", "restart"]).is_err());
+    }
+
+    #[test]
+    fn takes_precedence() {
+        let dir = tempdir().unwrap();
+        let key_file = dir.path().join("key");
+        write(&key_file, "flagkey\n").unwrap();
+
+        let mut env = EnvConfig::parse();
+        env.ingestion_key = Some("envkey".to_string());
+        env.log_dirs = Some(crate::env::EnvList(vec![PathBuf::from("/env/log")]));
+        let mut raw = RawConfig::default();
+
+        let parsed = args(&["--ingestion-key-file", key_file.to_str().unwrap(), "-d", "/flag/log"]);
+        parsed.merge(&mut env, &mut raw).unwrap();
+        assert_eq!(env.ingestion_key.as_deref(), Some("flagkey"));
+        assert_eq!(env.log_dirs, None);
+        assert_eq!(raw.log.dirs, vec![PathBuf::from("/flag/log")]);
+
+        // without flags the env vars and file are left alone
+        let mut env = EnvConfig::parse();
+        env.ingestion_key = Some("envkey".to_string());
+        let mut raw = RawConfig::default();
+        args(&[]).merge(&mut env, &mut raw).unwrap();
+        assert_eq!(env.ingestion_key.as_deref(), Some("envkey"));
+        assert_eq!(raw.log.dirs, RawConfig::default().log.dirs);
+
+        let missing = args(&["--ingestion-key-file", dir.path().join("missing").to_str().unwrap()]);
+        assert!(missing.merge(&mut env, &mut raw).is_err());
+    }
+}
@@ -35,6 +35,9 @@ pub struct Config {
     pub mac: Option<String>,
     #[env(LOGDNA_LOG_DIRS,LOG_DIRS)]
     pub log_dirs: Option<EnvList<PathBuf>>,
+    #[env(LOGDNA_OFFSET_FILE)]
+    #[example("/var/lib/logdna/offsets")]
+    pub offset_file: Option<PathBuf>,
     #[env(LOGDNA_EXCLUSION_RULES,LOGDNA_EXCLUDE)]
     pub exclusion_rules: Option<EnvList<String>>,
     #[env(LOGDNA_EXCLUSION_REGEX_RULES,LOGDNA_EXCLUDE_REGEX)]
@@ -43,6 +46,17 @@ pub struct Config {
     pub inclusion_rules: Option<EnvList<String>>,
     #[env(LOGDNA_INCLUSION_REGEX_RULES,LOGDNA_INCLUDE_REGEX)]
     pub inclusion_regex_rules: Option<EnvList<String>>,
+    #[env(LOGDNA_LINE_EXCLUSION_RULES)]
+    pub line_exclusion_rules: Option<EnvList<String>>,
+    #[env(LOGDNA_LINE_EXCLUSION_REGEX_RULES)]
+    pub line_exclusion_regex_rules: Option<EnvList<String>>,
+    #[env(LOGDNA_LINE_INCLUSION_RULES)]
+    pub line_inclusion_rules: Option<EnvList<String>>,
+    #[env(LOGDNA_LINE_INCLUSION_REGEX_RULES)]
+    pub line_inclusion_regex_rules: Option<EnvList<String>>,
+    #[env(LOGDNA_METRICS_PORT)]
+    #[example("9090")]
+    pub metrics_port: Option<u16>,
 }
 
 #[derive(Deserialize, Debug, Ord, PartialOrd, Eq, PartialEq)]
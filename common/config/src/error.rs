@@ -10,6 +10,17 @@ pub enum ConfigError {
     Template(http::types::error::TemplateError),
     Glob(globber::Error),
     Regex(regex::Error),
+    UnknownPreset(String),
+    UnknownEviction(String),
+    UnknownDetector(String),
+    UnknownLevelPattern(String),
+    UnknownTimestampFormat(String),
+    Selector(k8s::selector::Error),
+    InvalidAddress(String),
+    // an error with the field at a path, e.g log.exclude.glob[3]
+    At(String, Box<ConfigError>),
+    // every problem found with a config
+    Invalid(Vec<ConfigError>),
 }
 
 impl Display for ConfigError {
@@ -25,6 +36,18 @@ impl Display for ConfigError {
             ConfigError::Template(e) => write!(f, "{}", e),
             ConfigError::Glob(e) => write!(f, "{}", e),
             ConfigError::Regex(e) => write!(f, "{}", e),
+            ConfigError::UnknownPreset(p) => write!(f, "{} is not a known multiline preset", p),
+            ConfigError::UnknownEviction(e) => write!(f, "{} is not a known eviction policy", e),
+            ConfigError::UnknownDetector(d) => write!(f, "{} is not a known redaction detector", d),
+            ConfigError::UnknownLevelPattern(p) => write!(f, "{} is not a known level pattern", p),
+            ConfigError::UnknownTimestampFormat(t) => write!(f, "{} is not a known timestamp format", t),
+            ConfigError::Selector(e) => write!(f, "{}", e),
+            ConfigError::InvalidAddress(a) => write!(f, "{} is not a valid address", a),
+            ConfigError::At(path, e) => write!(f, "{}: {}", path, e),
+            ConfigError::Invalid(errors) => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", errors.join("\n"))
+            }
         }
     }
 }
@@ -53,6 +76,12 @@ impl From<globber::Error> for ConfigError {
     }
 }
 
+impl From<k8s::selector::Error> for ConfigError {
+    fn from(e: k8s::selector::Error) -> Self {
+        ConfigError::Selector(e)
+    }
+}
+
 impl From<regex::Error> for ConfigError {
     fn from(e: regex::Error) -> Self {
         ConfigError::Regex(e)
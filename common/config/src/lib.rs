@@ -1,20 +1,34 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::CString;
 use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::time::Duration;
 
 use flate2::Compression;
 
+use fs::multiline::{Multiline, MultilineRules};
 use fs::rule::{GlobRule, RegexRule, Rules};
+use http::retry::Eviction;
 use http::types::params::{Params, Tags};
 use http::types::request::{Encoding, RequestTemplate, Schema};
+use k8s::exclusion::Exclusion;
+use k8s::selector::Selector;
+use middleware::json::{JsonParser, JsonRule};
+use middleware::level::{Builtin, LevelDetector, LevelPattern, BUILTINS};
+use middleware::line_filter::{LineFilter, LineRule};
+use middleware::redact::{Redact, RedactRule, Replacement};
+use middleware::timestamp::{Format, TimestampParser, TimestampRule};
+use regex::Regex;
 
+use crate::args::Args;
 use crate::env::Config as EnvConfig;
 use crate::error::ConfigError;
-use crate::raw::{Config as RawConfig, Rules as RawRules};
-use std::io::Read;
+use crate::raw::{Config as RawConfig, LineRule as RawLineRule, MetricsConfig as RawMetricsConfig, Rules as RawRules};
+use std::io::{ErrorKind, Read};
 
+pub mod args;
 pub mod env;
 pub mod error;
 pub mod raw;
@@ -23,6 +37,15 @@ pub mod raw;
 pub struct Config {
     pub http: HttpConfig,
     pub log: LogConfig,
+    // where metrics and health checks are served, if set
+    pub metrics: Option<SocketAddr>,
+    pub health: HealthConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct HealthConfig {
+    pub stall_timeout: Option<Duration>,
+    pub ingest_window: Option<Duration>,
 }
 
 #[derive(Debug)]
@@ -30,19 +53,110 @@ pub struct HttpConfig {
     pub template: RequestTemplate,
     pub timeout: Duration,
     pub body_size: usize,
+    pub retry_statuses: Option<Vec<u16>>,
+    pub permanent_statuses: Option<Vec<u16>>,
+    pub retry: RetryConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct RetryConfig {
+    pub dir: Option<PathBuf>,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<usize>,
+    pub eviction: Option<Eviction>,
+    pub dead_letter_dir: Option<PathBuf>,
+    pub base_delay: Option<Duration>,
+    pub max_delay: Option<Duration>,
+    pub max_age: Option<Duration>,
+    pub max_attempts: Option<u32>,
 }
 
 #[derive(Debug)]
 pub struct LogConfig {
     pub dirs: Vec<PathBuf>,
+    pub poll_dirs: Vec<PathBuf>,
+    pub poll_interval: Option<Duration>,
+    pub offset_file: PathBuf,
     pub rules: Rules,
+    pub multiline: MultilineRules,
+    pub line_filter: LineFilter,
+    pub redact: Redact,
+    pub json: JsonParser,
+    pub level: LevelDetector,
+    pub timestamp: TimestampParser,
+    pub k8s: K8sConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct K8sConfig {
+    pub namespaces: HashMap<String, Exclusion>,
+    pub include_namespaces: Option<Vec<String>>,
+    pub exclude_namespaces: Vec<String>,
+    pub selector: Selector,
 }
 
 impl Config {
     pub fn new() -> Result<Self, ConfigError> {
-        let env_config: EnvConfig = EnvConfig::parse();
-        let raw_config: RawConfig = serde_yaml::from_reader(File::open(&env_config.config_file)?)?;
-        Config::try_from((env_config, raw_config))
+        Config::load(&Args::default())
+    }
+    /// Loads the config, command line flags take precedence over env vars which take precedence over the config file
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        Config::validate(args).config
+    }
+    /// Loads the config like [load](#method.load), also returning the keys of the config file that were ignored
+    pub fn validate(args: &Args) -> Validation {
+        let mut env_config: EnvConfig = EnvConfig::parse();
+        if let Some(ref path) = args.config_file {
+            env_config.config_file = path.clone();
+        }
+        let mut unknown_keys = Vec::new();
+        let mut missing_file = None;
+        let config = match File::open(&env_config.config_file) {
+            // the agent can be configured entirely with env vars, so a missing file means the defaults
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                missing_file = Some(env_config.config_file.clone());
+                Ok(RawConfig::default())
+            }
+            file => file.map_err(ConfigError::from).and_then(|file| read(file, &mut unknown_keys)),
+        };
+        let config = config
+            // name the file, neither io nor yaml errors do
+            .map_err(|e| ConfigError::At(env_config.config_file.display().to_string(), Box::new(e)))
+            .and_then(|mut raw_config| {
+                args.merge(&mut env_config, &mut raw_config)?;
+                Config::try_from((env_config, raw_config))
+            });
+        Validation { config, unknown_keys, missing_file }
+    }
+}
+
+/// A loaded config along with the keys of the config file that aren't part of the config
+#[derive(Debug)]
+pub struct Validation {
+    pub config: Result<Config, ConfigError>,
+    pub unknown_keys: Vec<String>,
+    /// The config file, if it didn't exist and the default config was used in its place
+    pub missing_file: Option<PathBuf>,
+}
+
+// parses a config file, recording the path of every key that was ignored
+fn read<R: Read>(file: R, unknown_keys: &mut Vec<String>) -> Result<RawConfig, ConfigError> {
+    let deserializer = serde_yaml::Deserializer::from_reader(file);
+    Ok(serde_ignored::deserialize(deserializer, |path| unknown_keys.push(yaml_path(&path)))?)
+}
+
+// formats a path the same way errors refer to fields, e.g log.exclude.glob[3]
+fn yaml_path(path: &serde_ignored::Path) -> String {
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent, index } => format!("{}[{}]", yaml_path(parent), index),
+        serde_ignored::Path::Map { parent, key } => match yaml_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{}.{}", parent, key),
+        },
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => yaml_path(parent),
     }
 }
 
@@ -58,10 +172,11 @@ impl TryFrom<(EnvConfig, RawConfig)> for Config {
             raw_config.http.endpoint = env_config.endpoint;
         }
 
-        raw_config.http.ingestion_key = Some(
-            env_config.ingestion_key
-                .ok_or(ConfigError::MissingEnvVar(EnvConfig::ingestion_key_vars()))?
-        );
+        // the key is usually set by env var, so that's what is asked for when there isn't one
+        let missing_key = env_config.ingestion_key.is_none() && raw_config.http.ingestion_key.is_none();
+        if env_config.ingestion_key.is_some() {
+            raw_config.http.ingestion_key = env_config.ingestion_key;
+        }
 
         if env_config.use_ssl.is_some() {
             raw_config.http.use_ssl = env_config.use_ssl;
@@ -111,6 +226,10 @@ impl TryFrom<(EnvConfig, RawConfig)> for Config {
             raw_config.log.dirs.append(&mut v)
         }
 
+        if env_config.offset_file.is_some() {
+            raw_config.log.offset_file = env_config.offset_file;
+        }
+
         if let Some(mut v) = env_config.exclusion_rules {
             match raw_config.log.exclude {
                 Some(ref mut rules) => {
@@ -175,7 +294,34 @@ impl TryFrom<(EnvConfig, RawConfig)> for Config {
             }
         }
 
-        Config::try_from(raw_config)
+        if let Some(v) = env_config.line_exclusion_rules {
+            raw_config.log.line_exclude.get_or_insert_with(Vec::new).extend(v.0.into_iter().map(RawLineRule::glob));
+        }
+
+        if let Some(v) = env_config.line_exclusion_regex_rules {
+            raw_config.log.line_exclude.get_or_insert_with(Vec::new).extend(v.0.into_iter().map(RawLineRule::regex));
+        }
+
+        if let Some(v) = env_config.line_inclusion_rules {
+            raw_config.log.line_include.get_or_insert_with(Vec::new).extend(v.0.into_iter().map(RawLineRule::glob));
+        }
+
+        if let Some(v) = env_config.line_inclusion_regex_rules {
+            raw_config.log.line_include.get_or_insert_with(Vec::new).extend(v.0.into_iter().map(RawLineRule::regex));
+        }
+
+        if env_config.metrics_port.is_some() {
+            raw_config.metrics.get_or_insert(RawMetricsConfig { host: None, port: None }).port = env_config.metrics_port;
+        }
+
+        match Config::try_from(raw_config) {
+            Err(ConfigError::Invalid(mut errors)) if missing_key => {
+                errors.retain(|e| !matches!(e, ConfigError::MissingField("http.ingestion_key")));
+                errors.insert(0, ConfigError::MissingEnvVar(EnvConfig::ingestion_key_vars()));
+                Err(ConfigError::Invalid(errors))
+            }
+            result => result,
+        }
     }
 }
 
@@ -183,87 +329,358 @@ impl TryFrom<RawConfig> for Config {
     type Error = ConfigError;
 
     fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        let mut errors = Errors::default();
         let mut template_builder = RequestTemplate::builder();
 
-        template_builder.api_key(
-            raw.http.ingestion_key
-                .ok_or(ConfigError::MissingField("http.ingestion_key"))?
-        );
+        if let Some(key) = errors.required(raw.http.ingestion_key, "http.ingestion_key") {
+            template_builder.api_key(key);
+        }
+
+        match errors.required(raw.http.use_ssl, "http.use_ssl") {
+            Some(true) => { template_builder.schema(Schema::Https); }
+            Some(false) => { template_builder.schema(Schema::Http); }
+            None => {}
+        };
+
+        let use_compression = errors.required(raw.http.use_compression, "http.use_compression");
+        let gzip_level = errors.required(raw.http.gzip_level, "http.gzip_level");
+        match (use_compression, gzip_level) {
+            (Some(true), Some(level)) => { template_builder.encoding(Encoding::GzipJson(Compression::new(level))); }
+            (Some(false), _) => { template_builder.encoding(Encoding::Json); }
+            _ => {}
+        };
+
+        if let Some(host) = errors.required(raw.http.host, "http.host") {
+            template_builder.host(host);
+        }
+
+        if let Some(endpoint) = errors.required(raw.http.endpoint, "http.endpoint") {
+            template_builder.endpoint(endpoint);
+        }
 
-        let use_ssl = raw.http.use_ssl
-            .ok_or(ConfigError::MissingField("http.use_ssl"))?;
-        match use_ssl {
-            true => template_builder.schema(Schema::Https),
-            false => template_builder.schema(Schema::Http),
+        if let Some(params) = errors.required(raw.http.params, "http.params") {
+            template_builder.params(params);
+        }
+
+        // a missing field is already reported, so only build the template once they are all there
+        let template = match errors.is_empty() {
+            true => errors.check("http", template_builder.build()),
+            false => None,
         };
+        let timeout = errors.required(raw.http.timeout, "http.timeout").map(Duration::from_millis);
+        let body_size = errors.required(raw.http.body_size, "http.body_size");
 
-        let use_compression = raw.http.use_compression
-            .ok_or(ConfigError::MissingField("http.use_compression"))?;
-        let gzip_level = raw.http.gzip_level
-            .ok_or(ConfigError::MissingField("http.gzip_level"))?;
-        match use_compression {
-            true => template_builder.encoding(Encoding::GzipJson(Compression::new(gzip_level))),
-            false => template_builder.encoding(Encoding::Json),
+        let retry = match raw.http.retry {
+            Some(retry) => RetryConfig {
+                dir: retry.dir,
+                max_bytes: retry.max_bytes,
+                max_files: retry.max_files,
+                eviction: retry.eviction.and_then(|eviction| errors.check(
+                    "http.retry.eviction",
+                    eviction.parse().map_err(ConfigError::UnknownEviction),
+                )),
+                dead_letter_dir: retry.dead_letter_dir,
+                base_delay: retry.base_delay.map(Duration::from_millis),
+                max_delay: retry.max_delay.map(Duration::from_millis),
+                max_age: retry.max_age.map(Duration::from_millis),
+                max_attempts: retry.max_attempts,
+            },
+            None => RetryConfig::default(),
         };
 
-        template_builder.host(
-            raw.http.host
-                .ok_or(ConfigError::MissingField("http.host"))?
-        );
-
-        template_builder.endpoint(
-            raw.http.endpoint
-                .ok_or(ConfigError::MissingField("http.endpoint"))?
-        );
-
-        template_builder.params(raw.http.params
-            .ok_or(ConfigError::MissingField("http.params"))?);
-
-        let http = HttpConfig {
-            template: template_builder.build()?,
-            timeout: Duration::from_millis(
-                raw.http.timeout.
-                    ok_or(ConfigError::MissingField("http.timeout"))?
-            ),
-            body_size: raw.http.body_size.
-                ok_or(ConfigError::MissingField("http.body_size"))?,
+        let (poll_dirs, poll_interval) = match raw.log.poll {
+            Some(poll) => (poll.dirs, poll.interval.map(Duration::from_millis)),
+            None => (Vec::new(), None),
         };
 
         let mut log = LogConfig {
-            dirs: raw.log.dirs
-                .into_iter()
-                .map(|s| PathBuf::from(s))
-                .collect(),
+            dirs: raw.log.dirs,
+            poll_dirs,
+            poll_interval,
+            offset_file: raw.log.offset_file.unwrap_or_else(|| raw::DEFAULT_OFFSET_FILE.into()),
             rules: Rules::new(),
+            multiline: MultilineRules::new(),
+            line_filter: LineFilter::new(),
+            redact: Redact::new(),
+            json: JsonParser::new(),
+            level: LevelDetector::new(),
+            timestamp: TimestampParser::new(),
+            k8s: K8sConfig::default(),
         };
 
         if let Some(rules) = raw.log.include {
-            for glob in rules.glob {
-                log.rules.add_inclusion(GlobRule::new(&*glob)?)
+            for (i, glob) in rules.glob.iter().enumerate() {
+                if let Some(rule) = errors.check(format!("log.include.glob[{}]", i), GlobRule::new(&**glob)) {
+                    log.rules.add_inclusion(rule)
+                }
             }
 
-            for regex in rules.regex {
-                log.rules.add_inclusion(RegexRule::new(&*regex)?)
+            for (i, regex) in rules.regex.iter().enumerate() {
+                if let Some(rule) = errors.check(format!("log.include.regex[{}]", i), RegexRule::new(&**regex)) {
+                    log.rules.add_inclusion(rule)
+                }
             }
         }
 
         if let Some(rules) = raw.log.exclude {
-            for glob in rules.glob {
-                log.rules.add_exclusion(GlobRule::new(&*glob)?)
+            for (i, glob) in rules.glob.iter().enumerate() {
+                if let Some(rule) = errors.check(format!("log.exclude.glob[{}]", i), GlobRule::new(&**glob)) {
+                    log.rules.add_exclusion(rule)
+                }
             }
 
-            for regex in rules.regex {
-                log.rules.add_exclusion(RegexRule::new(&*regex)?)
+            for (i, regex) in rules.regex.iter().enumerate() {
+                if let Some(rule) = errors.check(format!("log.exclude.regex[{}]", i), RegexRule::new(&**regex)) {
+                    log.rules.add_exclusion(rule)
+                }
             }
         }
 
+        for (i, rule) in raw.log.multiline.unwrap_or_default().into_iter().enumerate() {
+            let path = format!("log.multiline[{}]", i);
+            // explicit patterns take precedence over a preset
+            let multiline = match (rule.start, rule.continuation, rule.preset) {
+                (None, None, Some(preset)) => errors.check(
+                    format!("{}.preset", path),
+                    preset.parse().map(Multiline::preset).map_err(ConfigError::UnknownPreset),
+                ),
+                (None, None, None) => errors.check(path.clone(), Err(ConfigError::MissingField("preset"))),
+                (start, continuation, _) => errors.check(
+                    path.clone(),
+                    Multiline::new(start.as_deref(), continuation.as_deref()),
+                ),
+            };
+            let glob = errors.check(format!("{}.glob", path), GlobRule::new(&*rule.glob));
+
+            if let (Some(mut multiline), Some(glob)) = (multiline, glob) {
+                if let Some(max_lines) = rule.max_lines {
+                    multiline = multiline.max_lines(max_lines);
+                }
+
+                if let Some(timeout) = rule.timeout {
+                    multiline = multiline.timeout(Duration::from_millis(timeout));
+                }
+
+                log.multiline.add(glob, multiline)
+            }
+        }
+
+        for (i, rule) in raw.log.line_include.unwrap_or_default().into_iter().enumerate() {
+            if let Some(rule) = line_rule(rule, &format!("log.line_include[{}]", i), &mut errors) {
+                log.line_filter.add_inclusion(rule);
+            }
+        }
+
+        for (i, rule) in raw.log.line_exclude.unwrap_or_default().into_iter().enumerate() {
+            if let Some(rule) = line_rule(rule, &format!("log.line_exclude[{}]", i), &mut errors) {
+                log.line_filter.add_exclusion(rule);
+            }
+        }
+
+        if let Some(redact) = raw.log.redact {
+            for (i, detector) in redact.detectors.unwrap_or_default().into_iter().enumerate() {
+                let detector = detector.parse().map_err(ConfigError::UnknownDetector);
+                if let Some(detector) = errors.check(format!("log.redact.detectors[{}]", i), detector) {
+                    log.redact.add_detector(detector);
+                }
+            }
+
+            for (i, rule) in redact.rules.unwrap_or_default().into_iter().enumerate() {
+                let replacement = match (rule.hash, rule.replace) {
+                    (Some(true), _) => Replacement::Hash,
+                    (_, Some(template)) => Replacement::Template(template),
+                    (_, None) => Replacement::Template(format!("[REDACTED:{}]", rule.name)),
+                };
+                let rule = RedactRule::new(rule.name, &rule.regex, replacement);
+                if let Some(rule) = errors.check(format!("log.redact.rules[{}].regex", i), rule) {
+                    log.redact.add_rule(rule);
+                }
+            }
+
+            if let Some(salt) = redact.salt {
+                log.redact.set_salt(salt);
+            }
+        }
+
+        for (i, rule) in raw.log.json.unwrap_or_default().into_iter().enumerate() {
+            let glob = match errors.check(format!("log.json[{}].glob", i), GlobRule::new(&*rule.glob)) {
+                Some(v) => v,
+                None => continue,
+            };
+            let mut json = JsonRule::new(glob);
+            if let Some(meta) = rule.meta {
+                json = json.meta(meta);
+            }
+            if let Some(max_bytes) = rule.max_bytes {
+                json = json.max_bytes(max_bytes);
+            }
+            log.json.add_rule(json);
+        }
+
+        // the built in patterns apply even when the section is left out
+        let level = raw.log.level.unwrap_or_default();
+        // custom patterns are tried before the built in ones
+        for (i, pattern) in level.patterns.unwrap_or_default().into_iter().enumerate() {
+            let path = format!("log.level.patterns[{}]", i);
+            let regex = match errors.check(format!("{}.regex", path), Regex::new(&pattern.regex)) {
+                Some(v) => v,
+                None => continue,
+            };
+            if pattern.level.is_none() && !regex.capture_names().any(|n| n == Some("level")) {
+                errors.check::<(), _>(path, Err(ConfigError::MissingField("level")));
+                continue;
+            }
+            if let Some(pattern) = errors.check(path, LevelPattern::new(&pattern.regex, pattern.level)) {
+                log.level.add_pattern(pattern);
+            }
+        }
+
+        match level.builtins {
+            Some(builtins) => for (i, builtin) in builtins.into_iter().enumerate() {
+                let builtin = builtin.parse::<Builtin>().map_err(ConfigError::UnknownLevelPattern);
+                if let Some(builtin) = errors.check(format!("log.level.builtins[{}]", i), builtin) {
+                    log.level.add_pattern(builtin.pattern());
+                }
+            },
+            None => BUILTINS.iter().for_each(|b| log.level.add_pattern(b.pattern())),
+        }
+
+        // the built in formats apply even when the section is left out
+        let timestamp = raw.log.timestamp.unwrap_or_default();
+        let formats = |errors: &mut Errors, path: &str, formats: Vec<String>| -> Vec<Format> {
+            formats.into_iter()
+                .enumerate()
+                .filter_map(|(i, f)| errors.check(
+                    format!("{}[{}]", path, i),
+                    f.parse().map_err(ConfigError::UnknownTimestampFormat),
+                ))
+                .collect()
+        };
+
+        if let Some(defaults) = timestamp.formats {
+            log.timestamp.set_defaults(formats(&mut errors, "log.timestamp.formats", defaults));
+        }
+        for (i, rule) in timestamp.rules.unwrap_or_default().into_iter().enumerate() {
+            let rule_formats = formats(&mut errors, &format!("log.timestamp.rules[{}].formats", i), rule.formats);
+            let glob = format!("log.timestamp.rules[{}].glob", i);
+            if let Some(glob) = errors.check(glob, GlobRule::new(&*rule.glob)) {
+                log.timestamp.add_rule(TimestampRule::new(glob, rule_formats));
+            }
+        }
+
+        if let Some(k8s) = raw.log.k8s {
+            log.k8s.include_namespaces = k8s.include_namespaces;
+            log.k8s.exclude_namespaces = k8s.exclude_namespaces.unwrap_or_default();
+            if let Some(selector) = k8s.label_selector {
+                if let Some(selector) = errors.check("log.k8s.label_selector", selector.parse::<Selector>()) {
+                    log.k8s.selector = selector;
+                }
+            }
+
+            for (namespace, exclusion) in k8s.namespaces.unwrap_or_default() {
+                let path = format!("log.k8s.namespaces.{}", namespace);
+                let mut regex = |field: &str, regex: Option<String>| regex.and_then(|r| errors.check(
+                    format!("{}.{}", path, field),
+                    Regex::new(&r),
+                ));
+                let exclusion = Exclusion {
+                    exclude: exclusion.exclude,
+                    exclude_containers: exclusion.exclude_containers,
+                    include_containers: exclusion.include_containers,
+                    exclude_lines: regex("exclude_lines", exclusion.exclude_lines),
+                    include_lines: regex("include_lines", exclusion.include_lines),
+                };
+                log.k8s.namespaces.insert(namespace, exclusion);
+            }
+        }
+
+        // metrics are only served once a port is set
+        let metrics = match raw.metrics {
+            Some(RawMetricsConfig { host, port: Some(port) }) => {
+                let host = host.unwrap_or_else(|| "0.0.0.0".to_string());
+                let ip = host.parse::<IpAddr>().map_err(|_| ConfigError::InvalidAddress(host));
+                errors.check("metrics.host", ip).map(|ip| SocketAddr::new(ip, port))
+            }
+            _ => None,
+        };
+
+        let health = match raw.health {
+            Some(health) => HealthConfig {
+                stall_timeout: health.stall_timeout.map(Duration::from_millis),
+                ingest_window: health.ingest_window.map(Duration::from_millis),
+            },
+            None => HealthConfig::default(),
+        };
+
+        // every required field is set when there are no errors
+        let (template, timeout, body_size) = match (template, timeout, body_size) {
+            (Some(template), Some(timeout), Some(body_size)) if errors.is_empty() => (template, timeout, body_size),
+            _ => return Err(ConfigError::Invalid(errors.0)),
+        };
+
         Ok(Config {
-            http,
+            http: HttpConfig {
+                template,
+                timeout,
+                body_size,
+                retry_statuses: raw.http.retry_statuses,
+                permanent_statuses: raw.http.permanent_statuses,
+                retry,
+            },
             log,
+            metrics,
+            health,
         })
     }
 }
 
+// collects the problems with a config, so they can all be reported at once
+#[derive(Default)]
+struct Errors(Vec<ConfigError>);
+
+impl Errors {
+    // returns the value of a result, recording it's error under the path of the field otherwise
+    fn check<T, E: Into<ConfigError>>(&mut self, path: impl Into<String>, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.0.push(ConfigError::At(path.into(), Box::new(e.into())));
+                None
+            }
+        }
+    }
+    // returns the value of a required field, recording that it's missing otherwise
+    fn required<T>(&mut self, value: Option<T>, field: &'static str) -> Option<T> {
+        if value.is_none() {
+            self.0.push(ConfigError::MissingField(field));
+        }
+        value
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// builds a line rule from either it's glob or it's regex, scoped to files if set
+fn line_rule(raw: RawLineRule, path: &str, errors: &mut Errors) -> Option<LineRule> {
+    let rule = match (raw.glob, raw.regex) {
+        (Some(glob), None) => {
+            let rule = errors.check(format!("{}.glob", path), GlobRule::new(&*glob))?;
+            LineRule::new(format!("glob:{}", glob), rule)
+        }
+        (None, Some(regex)) => {
+            let rule = errors.check(format!("{}.regex", path), RegexRule::new(&*regex))?;
+            LineRule::new(format!("regex:{}", regex), rule)
+        }
+        _ => return errors.check(path, Err(ConfigError::MissingField("glob or regex"))),
+    };
+    match raw.files {
+        Some(files) => Some(rule.files(errors.check(format!("{}.files", path), GlobRule::new(&*files))?)),
+        None => Some(rule),
+    }
+}
+
 pub fn get_hostname() -> Option<String> {
     let path = PathBuf::from("/etc/logdna-hostname");
     if path.exists() {
@@ -304,6 +721,13 @@ mod tests {
 
     use super::*;
 
+    // a config that's valid once the fields under test are set
+    fn raw() -> RawConfig {
+        let mut raw = RawConfig::default();
+        raw.http.ingestion_key = Some("emptyingestionkey".to_string());
+        raw
+    }
+
     #[test]
     fn test_hostname() {
         assert!(get_hostname().is_some());
@@ -318,18 +742,293 @@ mod tests {
         assert!(Config::try_from(raw).is_ok());
     }
 
+    #[test]
+    fn test_multiline() {
+        let rule = |preset: &str| raw::MultilineRule {
+            glob: "/var/log/app/*.log".to_string(),
+            preset: Some(preset.to_string()),
+            start: None,
+            continuation: None,
+            max_lines: Some(100),
+            timeout: None,
+        };
+
+        let mut raw = raw();
+        raw.log.multiline = Some(vec![rule("java")]);
+        assert!(!Config::try_from(raw).unwrap().log.multiline.is_empty());
+
+        let mut raw = self::raw();
+        raw.log.multiline = Some(vec![rule("cobol")]);
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    fn k8s(namespaces: Option<HashMap<String, raw::ExclusionConfig>>, selector: Option<&str>) -> raw::K8sConfig {
+        raw::K8sConfig {
+            namespaces,
+            include_namespaces: None,
+            exclude_namespaces: Some(vec!["kube-system".to_string()]),
+            label_selector: selector.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_k8s_namespaces() {
+        let exclusion = |exclude_lines: &str| raw::ExclusionConfig {
+            exclude: Some(true),
+            exclude_containers: None,
+            include_containers: None,
+            exclude_lines: Some(exclude_lines.to_string()),
+            include_lines: None,
+        };
+
+        let mut raw = raw();
+        let mut namespaces = HashMap::new();
+        namespaces.insert("kube-system".to_string(), exclusion("^DEBUG"));
+        raw.log.k8s = Some(k8s(Some(namespaces), None));
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(config.log.k8s.namespaces["kube-system"].exclude, Some(true));
+
+        let mut raw = self::raw();
+        let mut namespaces = HashMap::new();
+        namespaces.insert("kube-system".to_string(), exclusion("["));
+        raw.log.k8s = Some(k8s(Some(namespaces), None));
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn test_k8s_selector() {
+        let mut raw = raw();
+        raw.log.k8s = Some(k8s(None, Some("app in (web,api),tier!=debug")));
+        let config = Config::try_from(raw).unwrap();
+        assert_eq!(config.log.k8s.selector.requirements().len(), 2);
+
+        let mut raw = self::raw();
+        raw.log.k8s = Some(k8s(None, Some("app in (web")));
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn test_line_rules() {
+        let mut raw = raw();
+        raw.log.line_exclude = Some(vec![raw::LineRule {
+            glob: None,
+            regex: Some("GET /healthz".to_string()),
+            files: Some("/var/log/nginx/*".to_string()),
+        }]);
+        raw.log.line_include = Some(vec![raw::LineRule::glob("*".to_string())]);
+        let filter = Config::try_from(raw).unwrap().log.line_filter;
+        assert_eq!(filter.exclusion_list()[0].name(), "regex:GET /healthz");
+        assert!(!filter.passes(Some("/var/log/nginx/access.log"), "GET /healthz 200"));
+        assert!(filter.passes(Some("/var/log/app.log"), "GET /healthz 200"));
+
+        // a rule needs either a glob or a regex
+        let mut raw = self::raw();
+        raw.log.line_exclude = Some(vec![raw::LineRule { glob: None, regex: None, files: None }]);
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let rule = |name: &str, hash| raw::RedactRule {
+            name: name.to_string(),
+            regex: r"user=\w+".to_string(),
+            replace: None,
+            hash: Some(hash),
+        };
+
+        let mut raw = raw();
+        raw.log.redact = Some(raw::RedactConfig {
+            detectors: Some(vec!["email".to_string()]),
+            rules: Some(vec![rule("user", false)]),
+            salt: None,
+        });
+        let redact = Config::try_from(raw).unwrap().log.redact;
+        assert_eq!(redact.redact("user=jane jane@example.com").unwrap(), "[REDACTED:user] [REDACTED:email]");
+
+        let mut raw = self::raw();
+        raw.log.redact = Some(raw::RedactConfig {
+            detectors: Some(vec!["passport".to_string()]),
+            rules: Some(vec![rule("user", true)]),
+            salt: None,
+        });
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let mut raw = raw();
+        raw.log.json = Some(vec![raw::JsonRule {
+            glob: "/var/log/app/*.log".to_string(),
+            meta: Some(true),
+            max_bytes: None,
+        }]);
+        assert!(!Config::try_from(raw).unwrap().log.json.is_empty());
+
+        let mut raw = self::raw();
+        raw.log.json = Some(vec![raw::JsonRule { glob: "[".to_string(), meta: None, max_bytes: None }]);
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn test_level() {
+        let mut raw = raw();
+        raw.log.level = Some(raw::LevelConfig {
+            builtins: Some(vec!["glog".to_string()]),
+            patterns: Some(vec![raw::LevelPattern { regex: "^!!".to_string(), level: Some("alert".to_string()) }]),
+        });
+        let level = Config::try_from(raw).unwrap().log.level;
+        assert_eq!(level.detect("!! E1017 12:00:00.000000").as_deref(), Some("ALERT"));
+        assert_eq!(level.detect("E1017 12:00:00.000000").as_deref(), Some("ERROR"));
+        assert_eq!(level.detect("ERROR"), None);
+
+        // patterns without a level need to capture one
+        let mut raw = self::raw();
+        raw.log.level = Some(raw::LevelConfig {
+            builtins: None,
+            patterns: Some(vec![raw::LevelPattern { regex: "^!!".to_string(), level: None }]),
+        });
+        assert!(Config::try_from(raw).is_err());
+
+        let mut raw = self::raw();
+        raw.log.level = Some(raw::LevelConfig { builtins: Some(vec!["cobol".to_string()]), patterns: None });
+        assert!(Config::try_from(raw).is_err());
+
+        // leaving the section out keeps the built in patterns
+        let mut raw = self::raw();
+        raw.log.level = None;
+        let level = Config::try_from(raw).unwrap().log.level;
+        assert_eq!(level.detect("E1017 12:00:00.000000").as_deref(), Some("ERROR"));
+    }
+
+    #[test]
+    fn test_metrics() {
+        assert_eq!(Config::try_from(raw()).unwrap().metrics, None);
+
+        let mut raw = raw();
+        raw.metrics = Some(raw::MetricsConfig { host: None, port: Some(9090) });
+        assert_eq!(Config::try_from(raw).unwrap().metrics, Some("0.0.0.0:9090".parse().unwrap()));
+
+        let mut raw = self::raw();
+        raw.metrics = Some(raw::MetricsConfig { host: Some("::1".to_string()), port: Some(9090) });
+        assert_eq!(Config::try_from(raw).unwrap().metrics, Some("[::1]:9090".parse().unwrap()));
+
+        let mut raw = self::raw();
+        raw.metrics = Some(raw::MetricsConfig { host: Some("localhost".to_string()), port: Some(9090) });
+        assert!(Config::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn test_offset_file() {
+        let mut raw = raw();
+        raw.log.offset_file = None;
+        assert_eq!(Config::try_from(raw).unwrap().log.offset_file, PathBuf::from(raw::DEFAULT_OFFSET_FILE));
+
+        let mut raw = self::raw();
+        raw.log.offset_file = Some("/tmp/offsets".into());
+        assert_eq!(Config::try_from(raw).unwrap().log.offset_file, PathBuf::from("/tmp/offsets"));
+    }
+
+    #[test]
+    fn test_health() {
+        let mut raw = raw();
+        raw.health = Some(raw::HealthConfig { stall_timeout: Some(10_000), ingest_window: None });
+        let health = Config::try_from(raw).unwrap().health;
+        assert_eq!(health.stall_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(health.ingest_window, None);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let mut raw = raw();
+        raw.log.timestamp = Some(raw::TimestampConfig {
+            formats: Some(vec!["epoch".to_string()]),
+            rules: Some(vec![raw::TimestampRule {
+                glob: "/var/log/app/*".to_string(),
+                formats: vec!["%d/%m/%Y %H:%M:%S".to_string()],
+            }]),
+        });
+        let parser = Config::try_from(raw).unwrap().log.timestamp;
+        let parse = |file, line| parser.parse(file, line).map(|t| t.timestamp());
+        assert_eq!(parse(Some("/var/log/app/app.log"), "17/10/2019 12:00:00 starting"), Some(1_571_313_600));
+        assert_eq!(parse(None, "1571313600 starting"), Some(1_571_313_600));
+        assert_eq!(parse(None, "2019-10-17T12:00:00Z starting"), None);
+
+        let mut raw = self::raw();
+        raw.log.timestamp = Some(raw::TimestampConfig { formats: Some(vec!["iso8601".to_string()]), rules: None });
+        assert!(Config::try_from(raw).is_err());
+
+        // leaving the section out keeps the built in formats
+        let mut raw = self::raw();
+        raw.log.timestamp = None;
+        let parser = Config::try_from(raw).unwrap().log.timestamp;
+        assert_eq!(parser.parse(None, "2019-10-17T12:00:00Z starting").map(|t| t.timestamp()), Some(1_571_313_600));
+    }
+
+    #[test]
+    fn test_collects_errors() {
+        let mut raw = raw();
+        raw.log.exclude.as_mut().unwrap().regex = vec!["ok".to_string(), "(unclosed".to_string()];
+        raw.log.timestamp = Some(raw::TimestampConfig { formats: Some(vec!["iso8601".to_string()]), rules: None });
+        let errors = match Config::try_from(raw) {
+            Err(ConfigError::Invalid(errors)) => errors,
+            other => panic!("expected invalid config, got {:?}", other),
+        };
+        let paths: Vec<_> = errors.iter()
+            .map(|e| match e {
+                ConfigError::At(path, _) => path.as_str(),
+                e => panic!("expected an error with a path, got {:?}", e),
+            })
+            .collect();
+        assert_eq!(paths, vec!["log.exclude.regex[1]", "log.timestamp.formats[0]"]);
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let yaml = "http:\n  host: logs.logdna.com\n  hots: typo\nlog:\n  dirs: [/var/log/]\n  lvl: debug\n";
+        let mut unknown_keys = Vec::new();
+        read(yaml.as_bytes(), &mut unknown_keys).unwrap();
+        assert_eq!(unknown_keys, vec!["http.hots", "log.lvl"]);
+
+        let err = read("http:\n  use_ssl: maybe\n".as_bytes(), &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("line 2 column"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("key");
+        std::fs::write(&key_file, "key").unwrap();
+        let mut args = Args {
+            config_file: Some(dir.path().join("missing.yaml")),
+            ingestion_key_file: Some(key_file),
+            ..Default::default()
+        };
+        let validation = Config::validate(&args);
+        assert!(validation.config.is_ok());
+        assert_eq!(validation.missing_file, args.config_file);
+
+        // a file that exists still has to parse
+        let invalid = dir.path().join("invalid.yaml");
+        std::fs::write(&invalid, "http: [").unwrap();
+        args.config_file = Some(invalid);
+        let validation = Config::validate(&args);
+        assert!(validation.config.is_err());
+        assert_eq!(validation.missing_file, None);
+    }
+
     #[test]
     fn e2e() {
-        remove_file("test.yaml");
+        let _ = remove_file("test.yaml");
 
         let file = OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .read(true)
             .open("test.yaml")
             .unwrap();
 
-        guard(file, |file| {
+        let _ = guard(file, |file| {
             serde_yaml::to_writer(file, &RawConfig::default()).unwrap();
 
             env::set_var(&EnvConfig::config_file_vars()[0], "test.yaml");
@@ -3,12 +3,17 @@ use serde::{Deserialize, Serialize};
 use http::types::params::Params;
 
 use crate::get_hostname;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub const DEFAULT_OFFSET_FILE: &str = "/var/lib/logdna/offsets";
+
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct Config {
     pub http: HttpConfig,
     pub log: LogConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub health: Option<HealthConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
@@ -22,13 +27,57 @@ pub struct HttpConfig {
     pub ingestion_key: Option<String>,
     pub params: Option<Params>,
     pub body_size: Option<usize>,
+    pub retry_statuses: Option<Vec<u16>>,
+    pub permanent_statuses: Option<Vec<u16>>,
+    pub retry: Option<RetryConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct MetricsConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct HealthConfig {
+    pub stall_timeout: Option<u64>,
+    pub ingest_window: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct RetryConfig {
+    pub dir: Option<PathBuf>,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<usize>,
+    pub eviction: Option<String>,
+    pub dead_letter_dir: Option<PathBuf>,
+    pub base_delay: Option<u64>,
+    pub max_delay: Option<u64>,
+    pub max_age: Option<u64>,
+    pub max_attempts: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct LogConfig {
     pub dirs: Vec<PathBuf>,
+    pub poll: Option<PollConfig>,
+    pub offset_file: Option<PathBuf>,
     pub include: Option<Rules>,
     pub exclude: Option<Rules>,
+    pub multiline: Option<Vec<MultilineRule>>,
+    pub line_include: Option<Vec<LineRule>>,
+    pub line_exclude: Option<Vec<LineRule>>,
+    pub redact: Option<RedactConfig>,
+    pub json: Option<Vec<JsonRule>>,
+    pub level: Option<LevelConfig>,
+    pub timestamp: Option<TimestampConfig>,
+    pub k8s: Option<K8sConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct PollConfig {
+    pub dirs: Vec<PathBuf>,
+    pub interval: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
@@ -37,15 +86,96 @@ pub struct Rules {
     pub regex: Vec<String>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            http: HttpConfig::default(),
-            log: LogConfig::default(),
-        }
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct MultilineRule {
+    pub glob: String,
+    pub preset: Option<String>,
+    pub start: Option<String>,
+    pub continuation: Option<String>,
+    pub max_lines: Option<usize>,
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct LineRule {
+    pub glob: Option<String>,
+    pub regex: Option<String>,
+    pub files: Option<String>,
+}
+
+impl LineRule {
+    pub fn glob(pattern: String) -> Self {
+        LineRule { glob: Some(pattern), regex: None, files: None }
+    }
+
+    pub fn regex(pattern: String) -> Self {
+        LineRule { glob: None, regex: Some(pattern), files: None }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct RedactConfig {
+    pub detectors: Option<Vec<String>>,
+    pub rules: Option<Vec<RedactRule>>,
+    pub salt: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct RedactRule {
+    pub name: String,
+    pub regex: String,
+    pub replace: Option<String>,
+    pub hash: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct JsonRule {
+    pub glob: String,
+    pub meta: Option<bool>,
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub struct LevelConfig {
+    pub builtins: Option<Vec<String>>,
+    pub patterns: Option<Vec<LevelPattern>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct LevelPattern {
+    pub regex: String,
+    pub level: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub struct TimestampConfig {
+    pub formats: Option<Vec<String>>,
+    pub rules: Option<Vec<TimestampRule>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TimestampRule {
+    pub glob: String,
+    pub formats: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct K8sConfig {
+    pub namespaces: Option<HashMap<String, ExclusionConfig>>,
+    pub include_namespaces: Option<Vec<String>>,
+    pub exclude_namespaces: Option<Vec<String>>,
+    pub label_selector: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ExclusionConfig {
+    pub exclude: Option<bool>,
+    pub exclude_containers: Option<Vec<String>>,
+    pub include_containers: Option<Vec<String>>,
+    pub exclude_lines: Option<String>,
+    pub include_lines: Option<String>,
+}
+
 impl Default for HttpConfig {
     fn default() -> Self {
         HttpConfig {
@@ -57,10 +187,23 @@ impl Default for HttpConfig {
             gzip_level: Some(2),
             ingestion_key: None,
             params: Params::builder()
-                .hostname(get_hostname().unwrap_or(String::new()))
+                .hostname(get_hostname().unwrap_or_default())
                 .build()
                 .ok(),
             body_size: Some(2 * 1024 * 1024),
+            retry_statuses: Some(vec![408, 429, 500, 502, 503, 504]),
+            permanent_statuses: Some(vec![400, 401, 403]),
+            retry: Some(RetryConfig {
+                dir: Some("/tmp/logdna/".into()),
+                max_bytes: Some(256 * 1024 * 1024),
+                max_files: Some(10_000),
+                eviction: Some("drop_oldest".into()),
+                dead_letter_dir: Some("/tmp/logdna-dead-letter/".into()),
+                base_delay: Some(15_000),
+                max_delay: Some(30 * 60 * 1000),
+                max_age: Some(24 * 60 * 60 * 1000),
+                max_attempts: Some(100),
+            }),
         }
     }
 }
@@ -69,6 +212,8 @@ impl Default for LogConfig {
     fn default() -> Self {
         LogConfig {
             dirs: vec!["/var/log/".into()],
+            poll: None,
+            offset_file: Some(DEFAULT_OFFSET_FILE.into()),
             include: Some(Rules {
                 glob: vec![
                     "*.log".parse().unwrap(),
@@ -92,6 +237,14 @@ impl Default for LogConfig {
                 ],
                 regex: Vec::new(),
             }),
+            multiline: None,
+            line_include: None,
+            line_exclude: None,
+            redact: None,
+            json: None,
+            level: Some(LevelConfig::default()),
+            timestamp: Some(TimestampConfig::default()),
+            k8s: None,
         }
     }
 }
@@ -0,0 +1,215 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+use inotify::{Event as InotifyEvent, EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::error::WatchError;
+
+/// A change to the filesystem detected by a Backend
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// A file or dir was created, or moved in from outside the watched dirs
+    Create(PathBuf),
+    /// A file was written to
+    Modify(PathBuf),
+    /// A file or dir was deleted, or moved out of the watched dirs
+    Delete(PathBuf),
+    /// A file or dir was moved from the first path to the second
+    Move(PathBuf, PathBuf),
+    /// Changes were lost and everything the backend watches needs to be rescanned
+    Overflow,
+}
+
+/// The mechanism a [Watcher](../watch/struct.Watcher.html) uses to detect changes to the files and dirs it watches
+///
+/// Backends only report changes, the Watcher decides what is watched by calling add, remove and rename
+pub trait Backend: Send {
+    /// Starts watching a file or dir, dirs are not watched recursively
+    fn add(&mut self, path: &Path) -> Result<(), WatchError>;
+    /// Stops watching a file or dir
+    fn remove(&mut self, path: &Path);
+    /// Updates the path of a watched file or dir after it was moved
+    fn rename(&mut self, from: &Path, to: &Path);
+    /// Returns the changes since the last call, waiting for at least one change if block is true
+    fn changes(&mut self, block: bool) -> Vec<Change>;
+}
+
+/// A backend that receives changes from the kernel through inotify
+pub struct InotifyBackend {
+    // An instance of inotify
+    inotify: Inotify,
+    // A mapping of watch descriptors to paths
+    // This is required because inotify operates on a watch list which (a list of i64s)
+    // This provides a mapping of those ids to the corresponding paths
+    watch_descriptors: HashMap<WatchDescriptor, PathBuf>,
+    // The reverse of watch_descriptors, used to remove and rename watches by path
+    paths: HashMap<PathBuf, WatchDescriptor>,
+    // A mapping of inotify cookies to the path a file or dir was moved from, and whether a read has passed since
+    // The MOVED_FROM and MOVED_TO events of a rename share a cookie and are paired up using this map
+    // The two events can be split across reads, so entries are held for one more read before being treated
+    // as moved out of the watched dirs
+    pending_moves: HashMap<u32, (PathBuf, bool)>,
+}
+
+impl InotifyBackend {
+    /// Creates a new inotify backend
+    pub fn new() -> Result<Self, io::Error> {
+        Ok(Self {
+            inotify: Inotify::init()?,
+            watch_descriptors: HashMap::new(),
+            paths: HashMap::new(),
+            pending_moves: HashMap::new(),
+        })
+    }
+    // turns an inotify event into change(s)
+    fn process(&mut self, event: InotifyEvent<&OsStr>, changes: &mut Vec<Change>) {
+        if event.mask.contains(EventMask::CREATE) {
+            if let Some(path) = self.event_path(&event) {
+                changes.push(Change::Create(path));
+            }
+        }
+
+        if event.mask.contains(EventMask::MOVED_FROM) {
+            if let Some(path) = self.event_path(&event) {
+                self.pending_moves.insert(event.cookie, (path, false));
+            }
+        }
+
+        if event.mask.contains(EventMask::MOVED_TO) {
+            if let Some(path) = self.event_path(&event) {
+                match self.pending_moves.remove(&event.cookie) {
+                    Some((from, _)) => changes.push(Change::Move(from, path)),
+                    // moved in from outside the watched dirs, so it's the same as a create
+                    None => changes.push(Change::Create(path)),
+                }
+            }
+        }
+
+        if event.mask.contains(EventMask::MODIFY) {
+            if let Some(path) = self.watch_descriptors.get(&event.wd) {
+                changes.push(Change::Modify(path.clone()));
+            }
+        }
+
+        if event.mask.contains(EventMask::DELETE_SELF) {
+            if let Some(path) = self.watch_descriptors.get(&event.wd) {
+                changes.push(Change::Delete(path.clone()));
+            }
+        }
+
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            changes.push(Change::Overflow);
+        }
+    }
+    // resolves the path of the file or dir an event on a watched dir refers to
+    fn event_path(&self, event: &InotifyEvent<&OsStr>) -> Option<PathBuf> {
+        self.watch_descriptors.get(&event.wd)
+            .map(|p| p.join(event.name.unwrap_or(&OsString::new())))
+    }
+}
+
+impl Backend for InotifyBackend {
+    fn add(&mut self, path: &Path) -> Result<(), WatchError> {
+        // add the path to the inotify with the appropriate mask
+        let watch_descriptor = self.inotify.add_watch(path, watch_mask(path))?;
+        // inotify hands back the existing watch descriptor if the inode is already watched
+        if let Some(old) = self.watch_descriptors.insert(watch_descriptor.clone(), path.to_path_buf()) {
+            self.paths.remove(&old);
+        }
+        self.paths.insert(path.to_path_buf(), watch_descriptor);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(watch_descriptor) = self.paths.remove(path) {
+            self.watch_descriptors.remove(&watch_descriptor);
+            // the watch may already be gone if the inode was deleted
+            let _ = self.inotify.rm_watch(watch_descriptor);
+        }
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        // inotify watches follow inodes, so the watch keeps working and only it's path changes
+        if let Some(watch_descriptor) = self.paths.remove(from) {
+            self.watch_descriptors.insert(watch_descriptor.clone(), to.to_path_buf());
+            self.paths.insert(to.to_path_buf(), watch_descriptor);
+        }
+    }
+
+    fn changes(&mut self, block: bool) -> Vec<Change> {
+        // stack allocated buffer for reading inotify events
+        let mut buf = EventBuffer([0u8; 4096]);
+        // a held move is resolved by the next read, so it can't wait for a change that may never come
+        let block = block && self.pending_moves.is_empty();
+        let events = match block {
+            true => self.inotify.read_events_blocking(&mut buf.0),
+            false => self.inotify.read_events(&mut buf.0),
+        };
+        let events = match events {
+            Ok(events) => events,
+            Err(e) => {
+                error!("error reading from inotify fd: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut changes = Vec::new();
+        // process all events we just read
+        for event in events {
+            self.process(event, &mut changes);
+        }
+        // anything still moved without a matching MOVED_TO after another read left the watched dirs
+        self.pending_moves.retain(|_, (from, held)| {
+            if *held {
+                changes.push(Change::Delete(from.clone()));
+            }
+            let keep = !*held;
+            *held = true;
+            keep
+        });
+        changes
+    }
+}
+
+// inotify reads events straight out of the buffer, so it needs the alignment of struct inotify_event
+#[repr(C, align(4))]
+struct EventBuffer([u8; 4096]);
+
+// returns the watch mask depending on if a path is a file or dir
+fn watch_mask(path: &Path) -> WatchMask {
+    if path.is_file() {
+        WatchMask::MODIFY | WatchMask::DELETE_SELF
+    } else {
+        WatchMask::CREATE | WatchMask::DELETE_SELF | WatchMask::MOVED_FROM | WatchMask::MOVED_TO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{canonicalize, create_dir, rename, write};
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn holds_unpaired_move_for_one_read() {
+        let dir = tempdir().unwrap();
+        let dir_path = canonicalize(dir.path()).unwrap();
+        let watched = dir_path.join("watched");
+        let outside = dir_path.join("outside");
+        create_dir(&watched).unwrap();
+        create_dir(&outside).unwrap();
+        write(watched.join("app.log"), "line\n").unwrap();
+
+        let mut backend = InotifyBackend::new().unwrap();
+        backend.add(&watched).unwrap();
+        rename(watched.join("app.log"), outside.join("app.log")).unwrap();
+
+        // the MOVED_TO could still be in the next read
+        assert_eq!(backend.changes(false), Vec::new());
+        assert_eq!(backend.changes(false), vec![Change::Delete(watched.join("app.log"))]);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+
+/// The max number of bytes from the start of a file that make up it's fingerprint
+pub const FINGERPRINT_SIZE: u64 = 256;
+
+/// Identifies a file independent of the path used to reach it
+///
+/// The device and inode pin down the file on disk, while the fingerprint guards against
+/// an inode being reused or a file being truncated and rewritten in place (copytruncate)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileId {
+    /// The id of the device the file lives on
+    pub dev: u64,
+    /// The inode of the file
+    pub inode: u64,
+    /// A fingerprint of the first bytes of the file
+    pub fingerprint: Fingerprint,
+}
+
+impl FileId {
+    /// Creates the id of an open file
+    pub fn new(file: &mut File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            dev: metadata.dev(),
+            inode: metadata.ino(),
+            fingerprint: Fingerprint::read(file, FINGERPRINT_SIZE)?,
+        })
+    }
+    /// Returns true if metadata describes the same inode this id was taken from
+    pub fn is_same_inode(&self, metadata: &Metadata) -> bool {
+        self.dev == metadata.dev() && self.inode == metadata.ino()
+    }
+    /// Returns true if file is the same inode with the same leading content as this id
+    pub fn matches(&self, file: &mut File) -> io::Result<bool> {
+        if !self.is_same_inode(&file.metadata()?) {
+            return Ok(false);
+        }
+        Ok(Fingerprint::read(file, self.fingerprint.len)? == self.fingerprint)
+    }
+}
+
+/// A hash of up to the first FINGERPRINT_SIZE bytes of a file
+///
+/// Files shorter than FINGERPRINT_SIZE have a fingerprint of all their content, which can be
+/// extended with Fingerprint::read once the file has grown
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    /// The number of bytes that were hashed
+    pub len: u64,
+    /// The FNV-1a hash of those bytes, stable across builds so it can be persisted
+    pub hash: u64,
+}
+
+impl Fingerprint {
+    /// Reads the fingerprint of up to the first len bytes of file
+    ///
+    /// The position of the file is reset to the start
+    pub fn read(file: &mut File, len: u64) -> io::Result<Self> {
+        let mut buf = Vec::with_capacity(len as usize);
+        file.seek(SeekFrom::Start(0))?;
+        file.take(len).read_to_end(&mut buf)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self::from_bytes(&buf))
+    }
+    /// Creates the fingerprint of bytes
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in bytes {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        Self {
+            len: bytes.len() as u64,
+            hash,
+        }
+    }
+    /// Returns true if the fingerprint covers less than FINGERPRINT_SIZE bytes
+    pub fn is_partial(&self) -> bool {
+        self.len < FINGERPRINT_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn detects_rewritten_content() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path).unwrap();
+        file.write_all(b"first line\n").unwrap();
+
+        let id = FileId::new(&mut file).unwrap();
+        assert_eq!(id.fingerprint.len, 11);
+        assert!(id.fingerprint.is_partial());
+
+        // appending doesn't change the leading bytes
+        file.write_all(b"second line\n").unwrap();
+        assert!(id.matches(&mut file).unwrap());
+
+        // truncating and rewriting does
+        file.set_len(0).unwrap();
+        file.write_all(b"other line\n").unwrap();
+        assert!(!id.matches(&mut file).unwrap());
+    }
+}
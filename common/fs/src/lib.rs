@@ -2,12 +2,24 @@
 extern crate log;
 #[macro_use]
 extern crate quick_error;
+#[macro_use]
+extern crate crossbeam;
 
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::path::PathBuf;
 
+/// Defines the backends the watcher uses to detect changes
+pub mod backend;
 /// Contains the error type(s) for this crate
 pub mod error;
+/// Identifies files independent of their path
+pub mod identity;
+/// Merges consecutive lines into multiline events, e.g stack traces
+pub mod multiline;
+/// Persists the offsets of tailed files across restarts
+pub mod offset;
+/// Defines the stat polling watcher backend
+pub mod poll;
 /// Traits and types for defining exclusion and inclusion rules
 pub mod rule;
 /// Defines the tailer used to tail directories or single files
@@ -26,6 +38,8 @@ pub enum Event {
     Delete(PathBuf),
     /// A file was written too
     Write(PathBuf),
+    /// A file was renamed or moved from the first path to the second
+    Rename(PathBuf, PathBuf),
 }
 
 impl Display for Event {
@@ -35,6 +49,7 @@ impl Display for Event {
             Event::New(path) => write!(f, "NEW {:?}", path),
             Event::Delete(path) => write!(f, "DELETE {:?}", path),
             Event::Write(path) => write!(f, "WRITE {:?}", path),
+            Event::Rename(from, to) => write!(f, "RENAME {:?} {:?}", from, to),
         }
     }
 }
@@ -0,0 +1,336 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use regex::{Error as RegexError, Regex};
+
+use http::types::body::LineBuilder;
+
+use crate::rule::Rule;
+
+/// Built in multiline settings for common stack trace formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Java stack traces, e.g `\tat com.example.Foo(Foo.java:12)` and `Caused by: ...`
+    Java,
+    /// Python tracebacks, e.g `Traceback (most recent call last):` through to the exception line
+    Python,
+    /// Go panics, e.g `panic: ...` followed by the goroutine dumps
+    Go,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "java" => Ok(Preset::Java),
+            "python" => Ok(Preset::Python),
+            "go" | "golang" => Ok(Preset::Go),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl Preset {
+    // the continuation pattern of the preset
+    fn continuation(self) -> &'static str {
+        match self {
+            Preset::Java => concat!(
+                r#"^(\s+at\s|\s+\.\.\.\s\d+\s(more|common frames omitted)|\s*Caused by:|\s+Suppressed:|"#,
+                r#"([\w$]+\.)+[\w$]*(Exception|Error|Throwable)\b)"#
+            ),
+            Preset::Python => concat!(
+                r#"^(\s|$|Traceback \(most recent call last\):|During handling of the above exception|"#,
+                r#"The above exception was the direct cause|[A-Za-z_][\w.]*(Error|Exception|Warning|Exit|Interrupt)\b)"#
+            ),
+            Preset::Go => concat!(
+                r#"^(\s|$|goroutine \d+ \[|created by |\[signal |exit status |"#,
+                r#"[\w./*()\[\]-]+\(.*\)$)"#
+            ),
+        }
+    }
+}
+
+/// Settings for merging consecutive lines of a file into a single multiline event
+///
+/// A line is a continuation of the current event if it matches the continuation pattern, or if
+/// only a start pattern is set, if it doesn't match the start pattern. Any other line flushes the
+/// current event and begins a new one. Events are also flushed once they reach max_lines or when
+/// no line has been added to them for the duration of the timeout.
+#[derive(Clone, Debug)]
+pub struct Multiline {
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    max_lines: usize,
+    timeout: Duration,
+}
+
+impl Multiline {
+    /// Creates multiline settings from a start and/or continuation pattern
+    pub fn new(start: Option<&str>, continuation: Option<&str>) -> Result<Self, RegexError> {
+        Ok(Self {
+            start: start.map(Regex::new).transpose()?,
+            continuation: continuation.map(Regex::new).transpose()?,
+            max_lines: 500,
+            timeout: Duration::from_secs(1),
+        })
+    }
+    /// Creates multiline settings from one of the built in presets
+    pub fn preset(preset: Preset) -> Self {
+        Self::new(None, Some(preset.continuation())).expect("Multiline::preset()")
+    }
+    /// Sets the max number of lines in an event
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines.max(1);
+        self
+    }
+    /// Sets how long a partial event is held waiting for more lines
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    // returns true if the line belongs to the event before it
+    fn is_continuation(&self, line: &str) -> bool {
+        match (&self.start, &self.continuation) {
+            (_, Some(continuation)) => continuation.is_match(line),
+            (Some(start), None) => !start.is_match(line),
+            (None, None) => false,
+        }
+    }
+}
+
+/// Maps files to the multiline settings that apply to them, the first matching rule wins
+#[derive(Default, Debug)]
+pub struct MultilineRules {
+    rules: Vec<(Box<dyn Rule + Send>, Multiline)>,
+}
+
+impl MultilineRules {
+    /// Constructs an empty instance of MultilineRules
+    pub fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+    /// Applies multiline to the files matching rule
+    pub fn add<T: Rule + Send + 'static>(&mut self, rule: T, multiline: Multiline) {
+        self.rules.push((Box::new(rule), multiline))
+    }
+    /// Returns true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    // returns the index of the first rule matching file
+    fn find(&self, file: &str) -> Option<usize> {
+        self.rules.iter().position(|(rule, _)| rule.matches(file))
+    }
+}
+
+// an event that is still collecting lines
+struct Pending {
+    // the first line of the event, which the rest of the lines are appended to
+    first: LineBuilder,
+    lines: Vec<String>,
+    // the index of the rule the event is being aggregated with
+    rule: usize,
+    last_line: Instant,
+}
+
+/// Merges the lines of each file into multiline events based on MultilineRules
+///
+/// Lines from files that don't match any rule are passed straight through
+#[derive(Default)]
+pub struct Aggregator {
+    rules: MultilineRules,
+    pending: HashMap<String, Pending>,
+}
+
+impl Aggregator {
+    /// Creates an aggregator for the given rules
+    pub fn new(rules: MultilineRules) -> Self {
+        Self {
+            rules,
+            pending: HashMap::new(),
+        }
+    }
+    /// Returns true if there are any rules, if there aren't every line is passed through
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+    /// Adds a line to the event of it's file, returning the event it completed or the line itself if it isn't merged
+    ///
+    /// A line can only complete the event before it or the one it's part of, so there is at most one
+    pub fn push(&mut self, line: LineBuilder, now: Instant) -> Option<LineBuilder> {
+        let file = line.file.clone().unwrap_or_default();
+        let rule = match self.rules.find(&file) {
+            Some(v) => v,
+            None => return Some(line),
+        };
+        let multiline = &self.rules.rules[rule].1;
+        let text = line.line.clone().unwrap_or_default();
+        let is_continuation = multiline.is_continuation(&text);
+        let max_lines = multiline.max_lines;
+
+        let mut complete = None;
+        if let Some(pending) = self.pending.get_mut(&file) {
+            if is_continuation {
+                pending.lines.push(text);
+                pending.last_line = now;
+                if pending.lines.len() >= max_lines {
+                    return self.flush(&file);
+                }
+                return None;
+            }
+            complete = self.flush(&file);
+        }
+
+        if max_lines == 1 {
+            return Some(line);
+        }
+        self.pending.insert(file, Pending {
+            first: line,
+            lines: vec![text],
+            rule,
+            last_line: now,
+        });
+        complete
+    }
+    /// Returns all partial events that haven't received a line within their timeout
+    pub fn flush_expired(&mut self, now: Instant) -> Vec<LineBuilder> {
+        let rules = &self.rules;
+        let expired: Vec<String> = self.pending.iter()
+            .filter(|(_, p)| now.duration_since(p.last_line) >= rules.rules[p.rule].1.timeout)
+            .map(|(file, _)| file.clone())
+            .collect();
+        expired.iter().filter_map(|file| self.flush(file)).collect()
+    }
+    /// Returns all partial events, used when shutting down
+    pub fn flush_all(&mut self) -> Vec<LineBuilder> {
+        self.pending.drain().map(|(_, pending)| join(pending)).collect()
+    }
+    // takes the partial event of a file, if any
+    fn flush(&mut self, file: &str) -> Option<LineBuilder> {
+        self.pending.remove(file).map(join)
+    }
+}
+
+// merges the lines of an event into it's first line
+fn join(pending: Pending) -> LineBuilder {
+    pending.first.line(pending.lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::rule::GlobRule;
+
+    fn aggregator(multiline: Multiline) -> Aggregator {
+        let mut rules = MultilineRules::new();
+        rules.add(GlobRule::new("*.log").unwrap(), multiline);
+        Aggregator::new(rules)
+    }
+
+    // pushes every line of text, returning the lines and events that were completed
+    fn push_all(aggregator: &mut Aggregator, file: &str, text: &str, now: Instant) -> Vec<String> {
+        text.lines()
+            .filter_map(|line| aggregator.push(LineBuilder::new().line(line).file(file), now))
+            .filter_map(|l| l.line)
+            .collect()
+    }
+
+    fn lines(lines: Vec<LineBuilder>) -> Vec<String> {
+        lines.into_iter().filter_map(|l| l.line).collect()
+    }
+
+    #[test]
+    fn merges_on_start_pattern() {
+        let mut aggregator = aggregator(Multiline::new(Some(r"^\d{4}-"), None).unwrap());
+        let now = Instant::now();
+
+        let text = "2019-01-01 one\n  detail\n  detail\n2019-01-01 two";
+        assert_eq!(push_all(&mut aggregator, "/var/log/app.log", text, now), vec!["2019-01-01 one\n  detail\n  detail"]);
+        // the last event is only complete once the next starts, or it times out
+        assert_eq!(lines(aggregator.flush_expired(now + Duration::from_secs(1))), vec!["2019-01-01 two"]);
+    }
+
+    #[test]
+    fn flushes_partial_event_on_timeout() {
+        let multiline = Multiline::preset(Preset::Java).timeout(Duration::from_millis(500));
+        let mut aggregator = aggregator(multiline);
+        let now = Instant::now();
+
+        let text = "java.lang.IllegalStateException: boom\n\tat Foo.bar(Foo.java:1)";
+        assert!(push_all(&mut aggregator, "/var/log/app.log", text, now).is_empty());
+        assert!(aggregator.flush_expired(now + Duration::from_millis(499)).is_empty());
+
+        // a line arriving resets the timeout
+        let later = now + Duration::from_millis(400);
+        assert!(push_all(&mut aggregator, "/var/log/app.log", "\tat Foo.main(Foo.java:2)", later).is_empty());
+        assert!(aggregator.flush_expired(now + Duration::from_millis(500)).is_empty());
+
+        assert_eq!(
+            lines(aggregator.flush_expired(later + Duration::from_millis(500))),
+            vec!["java.lang.IllegalStateException: boom\n\tat Foo.bar(Foo.java:1)\n\tat Foo.main(Foo.java:2)"]
+        );
+    }
+
+    #[test]
+    fn flushes_at_max_lines() {
+        let mut aggregator = aggregator(Multiline::preset(Preset::Java).max_lines(2));
+        let now = Instant::now();
+
+        let text = "Exception\n\tat a(a.java:1)\n\tat b(b.java:1)";
+        assert_eq!(push_all(&mut aggregator, "/var/log/app.log", text, now), vec!["Exception\n\tat a(a.java:1)"]);
+        // and everything left is sent at shutdown
+        assert_eq!(lines(aggregator.flush_all()), vec!["\tat b(b.java:1)"]);
+    }
+
+    #[test]
+    fn passes_through_unmatched_files() {
+        let mut aggregator = aggregator(Multiline::preset(Preset::Java));
+
+        let text = "Exception\n\tat a(a.java:1)";
+        assert_eq!(push_all(&mut aggregator, "/var/log/app.txt", text, Instant::now()), vec!["Exception", "\tat a(a.java:1)"]);
+    }
+
+    #[test]
+    fn presets() {
+        let java = "\
+2019-08-20 12:00:00 ERROR request failed
+java.lang.RuntimeException: outer
+\tat com.example.App.handle(App.java:10)
+\tat com.example.App.main(App.java:5)
+Caused by: java.io.IOException: inner
+\tat com.example.Io.read(Io.java:3)
+\t... 2 more
+2019-08-20 12:00:01 INFO next";
+        let python = "\
+ERROR:root:request failed
+Traceback (most recent call last):
+  File \"app.py\", line 10, in <module>
+    main()
+  File \"app.py\", line 6, in main
+    raise ValueError(\"bad\")
+ValueError: bad
+INFO:root:next";
+        let go = "\
+panic: runtime error: index out of range [5] with length 3
+
+goroutine 1 [running]:
+main.main()
+\t/app/main.go:8 +0x1d
+exit status 2
+2019/08/20 12:00:01 next";
+
+        for &(preset, text) in &[(Preset::Java, java), (Preset::Python, python), (Preset::Go, go)] {
+            let mut aggregator = aggregator(Multiline::preset(preset));
+            let now = Instant::now();
+            let mut events = push_all(&mut aggregator, "/var/log/app.log", text, now);
+            events.extend(lines(aggregator.flush_expired(now + Duration::from_secs(1))));
+
+            let mut expected: Vec<&str> = text.rsplitn(2, '\n').collect();
+            expected.reverse();
+            assert_eq!(events, expected, "{:?}", preset);
+        }
+    }
+}
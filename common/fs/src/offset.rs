@@ -0,0 +1,168 @@
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use hashbrown::HashMap;
+
+use crate::identity::{FileId, Fingerprint};
+
+/// The position of the tailer within a file, along with the identity of that file
+///
+/// The identity is recorded so that an offset taken against one file is never applied
+/// to a different file that later appeared at the same path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Offset {
+    /// The identity of the file
+    pub id: FileId,
+    /// Bytes from the beginning of the file that have been read
+    pub offset: u64,
+}
+
+/// Persists the tailer's offset table to disk so tailing can resume after a restart
+///
+/// The offsets are of the bytes read from each file, see [Tailer::set_offset_store](../tail/struct.Tailer.html#method.set_offset_store)
+///
+/// The store is a plain text file with one entry per line, in the form of
+/// `<dev> <inode> <fingerprint len> <fingerprint hash> <offset> <path>`.
+/// Saves are crash safe, the table is written to a temporary file which is synced and then
+/// renamed over the previous checkpoint, so a reader only ever sees a complete table.
+#[derive(Debug)]
+pub struct OffsetStore {
+    path: PathBuf,
+}
+
+impl OffsetStore {
+    /// Creates a store backed by the file at path, the file is created on the first save
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+    /// Returns the path of the backing file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Loads the last saved offset table, a missing file is treated as an empty table
+    pub fn load(&self) -> io::Result<HashMap<PathBuf, Offset>> {
+        let mut offsets = HashMap::new();
+
+        let file = match File::open(&self.path) {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(offsets),
+            Err(e) => return Err(e),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match parse_entry(&line) {
+                Some((path, offset)) => {
+                    offsets.insert(path, offset);
+                }
+                None => warn!("skipping invalid offset entry {:?} in {:?}", line, self.path),
+            }
+        }
+
+        Ok(offsets)
+    }
+    /// Atomically replaces the saved offset table with offsets
+    pub fn save(&self, offsets: &HashMap<PathBuf, Offset>) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        create_dir_all(dir)?;
+
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        for (path, offset) in offsets {
+            // paths that can't be written on a single line can't be read back, so they are skipped
+            match path.to_str() {
+                Some(s) if !s.contains('\n') => writeln!(
+                    writer,
+                    "{} {} {} {} {} {}",
+                    offset.id.dev,
+                    offset.id.inode,
+                    offset.id.fingerprint.len,
+                    offset.id.fingerprint.hash,
+                    offset.offset,
+                    s
+                )?,
+                _ => warn!("unable to checkpoint offset for {:?}", path),
+            }
+        }
+        // make sure the data is on disk before it replaces the old checkpoint
+        writer.into_inner()?.sync_all()?;
+        rename(&tmp_path, &self.path)?;
+        // sync the dir so the rename itself survives a crash
+        File::open(dir)?.sync_all()
+    }
+}
+
+// parses a single `<dev> <inode> <fingerprint len> <fingerprint hash> <offset> <path>` entry
+fn parse_entry(line: &str) -> Option<(PathBuf, Offset)> {
+    let mut parts = line.splitn(6, ' ');
+    let dev = parts.next()?.parse().ok()?;
+    let inode = parts.next()?.parse().ok()?;
+    let len = parts.next()?.parse().ok()?;
+    let hash = parts.next()?.parse().ok()?;
+    let offset = parts.next()?.parse().ok()?;
+    let path = parts.next().filter(|p| !p.is_empty())?;
+    let id = FileId {
+        dev,
+        inode,
+        fingerprint: Fingerprint { len, hash },
+    };
+    Some((PathBuf::from(path), Offset { id, offset }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn offset(inode: u64, offset: u64) -> Offset {
+        Offset {
+            id: FileId {
+                dev: 1,
+                inode,
+                fingerprint: Fingerprint::from_bytes(b"test"),
+            },
+            offset,
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempdir().unwrap();
+        let store = OffsetStore::new(dir.path().join("state").join("offsets"));
+        assert!(store.load().unwrap().is_empty());
+
+        let mut offsets = HashMap::new();
+        offsets.insert(PathBuf::from("/var/log/a.log"), offset(2, 3));
+        offsets.insert(PathBuf::from("/var/log/with space.log"), offset(5, 6));
+        store.save(&offsets).unwrap();
+        assert_eq!(store.load().unwrap(), offsets);
+
+        offsets.remove(&PathBuf::from("/var/log/a.log"));
+        store.save(&offsets).unwrap();
+        assert_eq!(store.load().unwrap(), offsets);
+    }
+
+    #[test]
+    fn skips_invalid_entries() {
+        let dir = tempdir().unwrap();
+        let store = OffsetStore::new(dir.path().join("offsets"));
+        std::fs::write(store.path(), "1 2 4 12 3 /var/log/a.log\ngarbage\n1 2 3 /var/log/b.log\n").unwrap();
+
+        let offsets = store.load().unwrap();
+        assert_eq!(offsets.len(), 1);
+        assert_eq!(offsets.get(&PathBuf::from("/var/log/a.log")), Some(&Offset {
+            id: FileId { dev: 1, inode: 2, fingerprint: Fingerprint { len: 4, hash: 12 } },
+            offset: 3,
+        }));
+    }
+}
@@ -0,0 +1,217 @@
+use std::ffi::OsString;
+use std::fs::{read_dir, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::backend::{Backend, Change};
+use crate::error::WatchError;
+
+/// A backend that detects changes by periodically stat'ing every watched file and dir
+///
+/// Unlike inotify this works on filesystems that don't deliver kernel events, e.g NFS, some FUSE mounts
+/// or overlays where the writer lives in another mount namespace. Files are diffed on size, mtime and inode,
+/// dirs are diffed on their listing. A file that disappears and an entry that appears with the same inode
+/// during the same poll are reported as a move.
+pub struct PollBackend {
+    // The time between polls
+    interval: Duration,
+    // The earliest time the next poll can happen
+    next_poll: Instant,
+    // The stat of every watched file and dir as of the last poll
+    watched: HashMap<PathBuf, Stat>,
+    // The names of the entries of every watched dir as of the last poll
+    // This includes entries that aren't watched, e.g excluded files, so they aren't reported as created every poll
+    entries: HashMap<PathBuf, HashSet<OsString>>,
+}
+
+// The parts of a file's metadata that are diffed between polls
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Stat {
+    dev: u64,
+    inode: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+    is_dir: bool,
+}
+
+impl Stat {
+    fn new(metadata: &Metadata) -> Self {
+        Self {
+            dev: metadata.dev(),
+            inode: metadata.ino(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            is_dir: metadata.is_dir(),
+        }
+    }
+
+    fn is_same_inode(&self, other: &Stat) -> bool {
+        self.dev == other.dev && self.inode == other.inode
+    }
+}
+
+impl PollBackend {
+    /// Creates a new polling backend that polls every interval
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_poll: Instant::now() + interval,
+            watched: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+    // stats everything that is watched and diffs it against the last poll
+    fn poll(&mut self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        // paths that are gone or now point to a different inode, along with their last stat
+        let mut deleted: Vec<(PathBuf, Stat)> = Vec::new();
+        // paths that are new or now point to a different inode
+        let mut created: Vec<PathBuf> = Vec::new();
+
+        for (path, stat) in self.watched.iter_mut() {
+            let new = match path.metadata() {
+                Ok(v) => Stat::new(&v),
+                Err(_) => {
+                    deleted.push((path.clone(), *stat));
+                    continue;
+                }
+            };
+
+            if !new.is_same_inode(stat) {
+                deleted.push((path.clone(), *stat));
+                created.push(path.clone());
+            } else if !new.is_dir && (new.len != stat.len || new.modified != stat.modified) {
+                *stat = new;
+                changes.push(Change::Modify(path.clone()));
+            }
+        }
+
+        for (dir, names) in self.entries.iter_mut() {
+            let new_names: HashSet<OsString> = match read_dir(dir) {
+                Ok(v) => v.filter_map(|e| e.ok()).map(|e| e.file_name()).collect(),
+                Err(_) => continue,
+            };
+            created.extend(new_names.iter()
+                .filter(|n| !names.contains(*n))
+                .map(|n| dir.join(n)));
+            *names = new_names;
+        }
+
+        // pair up paths that disappeared with paths that appeared on the same inode
+        let mut moves: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut creates: Vec<PathBuf> = Vec::new();
+        for path in created {
+            let stat = match path.metadata() {
+                Ok(v) => Stat::new(&v),
+                Err(_) => continue,
+            };
+            match deleted.iter().position(|(_, s)| s.is_same_inode(&stat)) {
+                Some(i) => moves.push((deleted.swap_remove(i).0, path)),
+                None => creates.push(path),
+            }
+        }
+        // anything under a moved dir moved with it, the watcher renames those paths as part of the move
+        deleted.retain(|(p, _)| !moves.iter().any(|(from, _)| p.starts_with(from)));
+
+        // deletes go first so a file replaced by a move is dropped before the move takes it's path
+        changes.extend(deleted.into_iter().map(|(p, _)| Change::Delete(p)));
+        changes.extend(moves.into_iter().map(|(from, to)| Change::Move(from, to)));
+        changes.extend(creates.into_iter().map(Change::Create));
+        changes
+    }
+}
+
+impl Backend for PollBackend {
+    fn add(&mut self, path: &Path) -> Result<(), WatchError> {
+        let stat = Stat::new(&path.metadata()?);
+        if stat.is_dir {
+            let names = read_dir(path)?
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name())
+                .collect();
+            self.entries.insert(path.to_path_buf(), names);
+        }
+        self.watched.insert(path.to_path_buf(), stat);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) {
+        self.watched.remove(path);
+        self.entries.remove(path);
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        if let Some(stat) = self.watched.remove(from) {
+            self.watched.insert(to.to_path_buf(), stat);
+        }
+        if let Some(names) = self.entries.remove(from) {
+            self.entries.insert(to.to_path_buf(), names);
+        }
+    }
+
+    fn changes(&mut self, block: bool) -> Vec<Change> {
+        loop {
+            let now = Instant::now();
+            if now >= self.next_poll {
+                self.next_poll = now + self.interval;
+                let changes = self.poll();
+                if !block || !changes.is_empty() {
+                    return changes;
+                }
+            } else if !block {
+                return Vec::new();
+            }
+            sleep(self.next_poll.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{canonicalize, create_dir, remove_file, rename, write, OpenOptions};
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn detects_changes() {
+        let dir = tempdir().unwrap();
+        let dir_path = canonicalize(dir.path()).unwrap();
+        let log = dir_path.join("app.log");
+        let rotated = dir_path.join("app.log.1");
+        let deleted = dir_path.join("deleted.log");
+        write(&log, "line\n").unwrap();
+        write(&deleted, "line\n").unwrap();
+
+        let mut backend = PollBackend::new(Duration::from_millis(10));
+        for path in &[&dir_path, &log, &deleted] {
+            backend.add(path).unwrap();
+        }
+        assert!(backend.changes(false).is_empty());
+
+        OpenOptions::new().append(true).open(&log).unwrap().write_all(b"another line\n").unwrap();
+        assert_eq!(backend.changes(true), vec![Change::Modify(log.clone())]);
+
+        // rotate and create, along with a new dir and a delete
+        // deleting last means the freed inode can't be reused and mistaken for a move
+        rename(&log, &rotated).unwrap();
+        write(&log, "new line\n").unwrap();
+        create_dir(dir_path.join("sub")).unwrap();
+        remove_file(&deleted).unwrap();
+
+        let mut changes = backend.changes(true);
+        changes.sort_by_key(|c| format!("{:?}", c));
+        assert_eq!(changes, vec![
+            Change::Create(log.clone()),
+            Change::Create(dir_path.join("sub")),
+            Change::Delete(deleted),
+            Change::Move(log, rotated),
+        ]);
+    }
+}
@@ -5,7 +5,7 @@ use globber::{Error as PatternError, Pattern};
 use regex::{Error as RegexError, Regex};
 
 /// A list of rules
-pub type RuleList = Vec<Box<Rule + Send>>;
+pub type RuleList = Vec<Box<dyn Rule + Send>>;
 
 /// A trait for implementing a rule, see GlobRule/RegexRule for an example
 pub trait Rule: Debug {
@@ -27,10 +27,7 @@ pub enum Status {
 impl Status {
     /// Converts a status into a bool, returning true if the status is ok and false otherwise
     pub fn is_ok(&self) -> bool {
-        match self {
-            Status::Ok => true,
-            _ => false,
-        }
+        matches!(self, Status::Ok)
     }
 }
 
@@ -1,13 +1,18 @@
-use std::fs::{File, metadata};
+use std::fs::File;
 use std::io::{BufRead, BufReader, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crossbeam::{bounded, Receiver, Sender};
-use hashbrown::HashMap;
+use crossbeam::{bounded, never, tick, Receiver, Sender};
+use hashbrown::{HashMap, HashSet};
 
 use http::types::body::LineBuilder;
+use metrics::health::{HEALTH, HEARTBEAT_INTERVAL};
+use metrics::IntGauge;
 
 use crate::Event;
+use crate::identity::{FileId, Fingerprint, FINGERPRINT_SIZE};
+use crate::offset::{Offset, OffsetStore};
 
 /// Tails files on a filesystem by inheriting events from a Watcher
 pub struct Tailer {
@@ -16,50 +21,182 @@ pub struct Tailer {
     // used to pops items out of the sender
     event_receiver: Receiver<Event>,
     // tracks the offset (bytes from the beginning of the file we have read) of file(s)
-    offsets: HashMap<PathBuf, u64>,
+    offsets: HashMap<PathBuf, Offset>,
+    // offsets loaded from the store on startup, these are consumed by Initiate events
+    checkpoints: HashMap<PathBuf, Offset>,
+    // the paths that had a checkpoint on startup, a file at one of them that matches no checkpoint replaced
+    // the file we were tailing there
+    checkpointed: HashSet<PathBuf>,
+    // where the offset table is periodically persisted, if set
+    store: Option<OffsetStore>,
+    // how often the offset table is persisted to the store
+    checkpoint_interval: Duration,
+    // the number of events waiting to be handled
+    queued: IntGauge,
+}
+
+impl Default for Tailer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tailer {
     /// Creates new instance of Tailer
     pub fn new() -> Self {
-        let (s, r) = bounded(32_000);
+        let capacity = 32_000;
+        let (s, r) = bounded(capacity);
         Self {
             event_sender: s,
             event_receiver: r,
             offsets: HashMap::new(),
+            checkpoints: HashMap::new(),
+            checkpointed: HashSet::new(),
+            store: None,
+            checkpoint_interval: Duration::from_secs(5),
+            queued: metrics::channel("tailer", capacity),
         }
     }
     /// Returns the sender the tailer is "listening" on
     pub fn sender(&self) -> Sender<Event> {
         self.event_sender.clone()
     }
+    /// Sets the store used to persist offsets, loading any offsets it already holds
+    ///
+    /// Loaded offsets are used by Initiate events so that tailing resumes where it left off
+    /// rather than at the end of the file, a file that replaced one we were tailing is read from the start.
+    ///
+    /// Offsets record the bytes read, not the bytes delivered. A graceful shutdown delivers or spools
+    /// every line before the final checkpoint, but lines still held by the middlewares, e.g partial
+    /// multiline events, or the client's buffer when the agent crashes are lost.
+    pub fn set_offset_store(&mut self, store: OffsetStore) {
+        match store.load() {
+            Ok(v) => {
+                info!("loaded {} offset(s) from {:?}", v.len(), store.path());
+                self.checkpointed = v.keys().cloned().collect();
+                self.checkpoints = v;
+            }
+            Err(e) => error!("unable to load offsets from {:?}: {:?}", store.path(), e),
+        }
+        self.store = Some(store);
+    }
+    /// Sets how often offsets are persisted to the offset store
+    pub fn set_checkpoint_interval(&mut self, interval: Duration) {
+        self.checkpoint_interval = interval;
+    }
     /// Runs the main logic of the tailer, this can only be run once so Tailer is consumed
+    ///
+    /// Returns once every sender of events has been dropped, e.g the watcher stopped, after handling
+    /// the remaining events and taking a final checkpoint
     pub fn run(mut self, sender: Sender<LineBuilder>) {
+        // drop our own sender so the channel disconnects once everyone else's is gone
+        self.event_sender = bounded(0).0;
+
+        // only wake up to checkpoint if there is somewhere to persist offsets to
+        let checkpoint = match self.store {
+            Some(_) => tick(self.checkpoint_interval),
+            None => never(),
+        };
+
+        let heartbeat = HEALTH.register("tailer");
+        let heartbeat_tick = tick(HEARTBEAT_INTERVAL);
+
         loop {
-            // safe to unwrap
-            let event = self.event_receiver.recv().unwrap();
-
-            match event {
-                Event::Initiate(path) => {
-                    // will initiate a file to it's current length
-                    let len = path.metadata().map(|m| m.len()).unwrap_or(0);
-                    info!("initiated {:?} to offset table with offset {}", path, len);
-                    self.offsets.insert(path, len);
-                }
-                Event::New(path) => {
-                    // similar to initiate but sets the offset to 0
-                    info!("added {:?} to offset table", path);
-                    self.offsets.insert(path.clone(), 0);
-                    self.tail(path, &sender);
+            select! {
+                recv(self.event_receiver) -> event => match event {
+                    Ok(event) => {
+                        self.queued.set(self.event_receiver.len() as i64);
+                        self.handle(event, &sender);
+                    }
+                    // all events have been handled and no more can arrive
+                    Err(_) => break,
+                },
+                recv(checkpoint) -> _ => self.checkpoint(),
+                recv(heartbeat_tick) -> _ => heartbeat.beat(),
+            }
+        }
+
+        self.checkpoint();
+        info!("tailer stopped");
+    }
+
+    // handles a single event from the watcher
+    fn handle(&mut self, event: Event, sender: &Sender<LineBuilder>) {
+        match event {
+            Event::Initiate(path) => {
+                let (mut file, id, len) = match open(&path) {
+                    Some(v) => v,
+                    None => return,
+                };
+                // resume from the last checkpoint taken against this exact file
+                // a file that took the place of one we were tailing, e.g it was rotated while
+                // we weren't running, is entirely new, anything else is initiated to it's current length
+                let offset = match self.take_checkpoint(&path, &mut file) {
+                    Some(v) if v <= len => v,
+                    None if self.checkpointed.contains(&path) => 0,
+                    _ => len,
+                };
+                info!("initiated {:?} to offset table with offset {}", path, offset);
+                self.offsets.insert(path.clone(), Offset { id, offset });
+                // ship anything that was written while we weren't running
+                if offset < len {
+                    self.tail(path, sender);
                 }
-                Event::Delete(ref path) => {
-                    // just remove the file from the offset table on delete
-                    // this acts almost like a garbage collection mechanism
-                    // ensuring the offset table doesn't "leak" by holding deleted files
+            }
+            Event::New(path) => {
+                // similar to initiate but sets the offset to 0
+                let id = match open(&path) {
+                    Some((_, id, _)) => id,
+                    None => return,
+                };
+                info!("added {:?} to offset table", path);
+                self.offsets.insert(path.clone(), Offset { id, offset: 0 });
+                self.tail(path, sender);
+            }
+            Event::Delete(ref path) => {
+                // just remove the file from the offset table on delete
+                // this acts almost like a garbage collection mechanism
+                // ensuring the offset table doesn't "leak" by holding deleted files
+                if self.offsets.remove(path).is_some() {
                     info!("removed {:?} from offset table", path);
-                    self.offsets.remove(path);
                 }
-                Event::Write(path) => self.tail(path, &sender),
+                remove_metrics(path);
+            }
+            Event::Write(path) => self.tail(path, sender),
+            Event::Rename(from, to) => match self.offsets.remove(&from) {
+                Some(offset) => {
+                    info!("moved {:?} to {:?} in offset table", from, to);
+                    remove_metrics(&from);
+                    self.offsets.insert(to.clone(), offset);
+                    // drain whatever is left in the old file under it's new name
+                    // the watcher sends a New event if a file is created in it's place
+                    self.tail(to, sender);
+                }
+                None => warn!("{:?} was not found in offset table!", from),
+            },
+        }
+        metrics::FS_FILES.set(self.offsets.len() as i64);
+    }
+
+    // removes and returns the checkpointed offset for a file
+    // checkpoints are looked up by path first and then by identity
+    // in case the file was renamed while we weren't running
+    fn take_checkpoint(&mut self, path: &PathBuf, file: &mut File) -> Option<u64> {
+        let key = match self.checkpoints.get(path) {
+            Some(c) if c.id.matches(file).unwrap_or(false) => Some(path.clone()),
+            _ => self.checkpoints
+                .iter()
+                .find(|(_, c)| c.id.matches(file).unwrap_or(false))
+                .map(|(p, _)| p.clone()),
+        };
+        self.checkpoints.remove(&key?).map(|c| c.offset)
+    }
+
+    // persists the offset table to the store
+    fn checkpoint(&self) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.save(&self.offsets) {
+                error!("unable to save offsets to {:?}: {:?}", store.path(), e);
             }
         }
     }
@@ -67,44 +204,74 @@ impl Tailer {
     // tail a file for new line(s)
     fn tail(&mut self, path: PathBuf, sender: &Sender<LineBuilder>) {
         // get the offset from the map, return if not found
-        let offset = match self.offsets.get_mut(&path) {
+        let state = match self.offsets.get_mut(&path) {
             Some(v) => v,
             None => {
                 warn!("{:?} was not found in offset table!", path);
                 return;
             }
         };
-        // get the file len
-        let len = match metadata(&path).map(|m| m.len()) {
+        // open the file, all checks are done against the open file
+        // so it can't be swapped out from under us part way through
+        let mut file = match File::open(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("unable to access {:?}: {:?}", path, e);
+                return;
+            }
+        };
+        let metadata = match file.metadata() {
             Ok(v) => v,
             Err(e) => {
                 error!("unable to stat {:?}: {:?}", path, e);
                 return;
             }
         };
-        // if we are at the end of the file there's no work to do
-        if *offset == len {
+        // the path now points to a different file, this happens when a file is rotated
+        // before we have processed the Rename event the watcher sent for it
+        // so leave the offset alone and let the Rename event carry it to the new path
+        if !state.id.is_same_inode(&metadata) {
+            info!("{:?} no longer refers to the file being tailed", path);
             return;
         }
-        // get the name of the file set to "" if the file is invalid utf8
-        let file_name = path.to_str().unwrap_or("").to_string();
-        // open the file, create a reader
-        //todo when match postfix lands on stable replace prefix match for readability
-        let mut reader = match File::open(&path)
-            .map(|f| BufReader::new(f)) {
-            Ok(v) => v,
+        // get the file len
+        let len = metadata.len();
+        // if the leading bytes changed the file was truncated and rewritten in place
+        // if the offset is greater than the file's len it's very likely a truncation occurred
+        // in either case the file now holds new data, so start from the beginning
+        match state.id.matches(&mut file) {
+            Ok(true) if state.offset <= len => {}
+            Ok(_) => {
+                info!("{:?} was truncated from {} to {}", path, state.offset, len);
+                state.offset = 0;
+            }
             Err(e) => {
-                error!("unable to access {:?}: {:?}", path, e);
+                error!("unable to fingerprint {:?}: {:?}", path, e);
                 return;
             }
-        };
-        // if the offset is greater than the file's len
-        // it's very likely a truncation occurred
-        if *offset > len {
-            info!("{:?} was truncated from {} to {}", path, *offset, len);
-            *offset = len;
+        }
+        // if we are at the end of the file there's no work to do
+        if state.offset == len {
             return;
         }
+        // a fingerprint taken while the file was small is extended as the file grows
+        // and it's retaken whenever we start reading the file from the beginning
+        if state.offset == 0 || (state.id.fingerprint.is_partial() && state.id.fingerprint.len < len) {
+            match Fingerprint::read(&mut file, FINGERPRINT_SIZE) {
+                Ok(v) => state.id.fingerprint = v,
+                Err(e) => {
+                    error!("unable to fingerprint {:?}: {:?}", path, e);
+                    return;
+                }
+            }
+        }
+        let offset = &mut state.offset;
+        // get the name of the file set to "" if the file is invalid utf8
+        let file_name = path.to_str().unwrap_or("").to_string();
+        let lines_read = metrics::FS_LINES.with_label_values(&[&file_name]);
+        let bytes_read = metrics::FS_BYTES.with_label_values(&[&file_name]);
+        // create a reader over the already open file
+        let mut reader = BufReader::new(file);
         // seek to the offset, this creates the "tailing" effect
         if let Err(e) = reader.seek(SeekFrom::Start(*offset)) {
             error!("error seeking {:?}", e);
@@ -137,7 +304,9 @@ impl Tailer {
             line.pop();
             // increment the offset
             *offset += line_len;
-            // send the line upstream, safe to unwrap
+            lines_read.inc();
+            bytes_read.inc_by(line_len);
+            // send the line upstream
             sender.send(
                 LineBuilder::new()
                     .line(line)
@@ -145,4 +314,149 @@ impl Tailer {
             ).unwrap()
         }
     }
-}
\ No newline at end of file
+}
+
+// opens a file returning it along with it's identity and length
+fn open(path: &PathBuf) -> Option<(File, FileId, u64)> {
+    let result = File::open(path).and_then(|mut file| {
+        let len = file.metadata()?.len();
+        let id = FileId::new(&mut file)?;
+        Ok((file, id, len))
+    });
+    match result {
+        Ok(v) => Some(v),
+        Err(e) => {
+            error!("unable to access {:?}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+// removes the per file metrics of a file that is no longer tailed under path
+fn remove_metrics(path: &Path) {
+    let file = path.to_str().unwrap_or("");
+    // the metrics don't exist if nothing was read from the file
+    let _ = metrics::FS_LINES.remove_label_values(&[file]);
+    let _ = metrics::FS_BYTES.remove_label_values(&[file]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use crossbeam::unbounded;
+    use tempfile::tempdir;
+
+    fn append(path: &PathBuf, data: &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    fn lines(receiver: &Receiver<LineBuilder>) -> Vec<String> {
+        receiver.try_iter().filter_map(|l| l.line).collect()
+    }
+
+    #[test]
+    fn resumes_from_checkpoint_after_restart() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let store = dir.path().join("offsets");
+        let (sender, receiver) = unbounded();
+
+        append(&log, "before start\n");
+
+        let mut tailer = Tailer::new();
+        tailer.set_offset_store(OffsetStore::new(&store));
+        // without a checkpoint the file is initiated to its current length
+        tailer.handle(Event::Initiate(log.clone()), &sender);
+        assert!(lines(&receiver).is_empty());
+        append(&log, "first run\n");
+        tailer.handle(Event::Write(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["first run"]);
+        tailer.checkpoint();
+        drop(tailer);
+
+        // the file grows while no tailer is running
+        append(&log, "while stopped 1\nwhile stopped 2\n");
+
+        let mut tailer = Tailer::new();
+        tailer.set_offset_store(OffsetStore::new(&store));
+        tailer.handle(Event::Initiate(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["while stopped 1", "while stopped 2"]);
+    }
+
+    #[test]
+    fn reads_replaced_file_from_start() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let store = dir.path().join("offsets");
+        let (sender, receiver) = unbounded();
+
+        append(&log, "old file\n");
+
+        let mut tailer = Tailer::new();
+        tailer.set_offset_store(OffsetStore::new(&store));
+        tailer.handle(Event::Initiate(log.clone()), &sender);
+        tailer.checkpoint();
+        drop(tailer);
+
+        // create the new file before removing the old one so the inode can't be reused
+        let rotated = dir.path().join("test.log.new");
+        append(&rotated, "new file, longer than the old one\n");
+        std::fs::rename(&rotated, &log).unwrap();
+
+        let mut tailer = Tailer::new();
+        tailer.set_offset_store(OffsetStore::new(&store));
+        tailer.handle(Event::Initiate(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["new file, longer than the old one"]);
+    }
+
+    #[test]
+    fn drains_renamed_file() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let rotated = dir.path().join("test.log.1");
+        let (sender, receiver) = unbounded();
+
+        append(&log, "first\n");
+        let mut tailer = Tailer::new();
+        tailer.handle(Event::New(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["first"]);
+
+        // rotate the file, then write to both the old and new file before the tailer catches up
+        append(&log, "second\n");
+        std::fs::rename(&log, &rotated).unwrap();
+        append(&rotated, "third\n");
+        append(&log, "new file\n");
+
+        // the write was for the old file, which no longer lives at this path
+        tailer.handle(Event::Write(log.clone()), &sender);
+        assert!(lines(&receiver).is_empty());
+
+        tailer.handle(Event::Rename(log.clone(), rotated.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["second", "third"]);
+        tailer.handle(Event::New(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["new file"]);
+    }
+
+    #[test]
+    fn rereads_truncated_file() {
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("test.log");
+        let (sender, receiver) = unbounded();
+
+        append(&log, "a line that will be truncated\n");
+        let mut tailer = Tailer::new();
+        tailer.handle(Event::New(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["a line that will be truncated"]);
+
+        // copytruncate, then the writer carries on past our old offset before we notice
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(0).unwrap();
+        append(&log, "the file was rewritten after the truncation\n");
+        tailer.handle(Event::Write(log.clone()), &sender);
+        assert_eq!(lines(&receiver), vec!["the file was rewritten after the truncation"]);
+    }
+}
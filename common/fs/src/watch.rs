@@ -1,41 +1,78 @@
-use hashbrown::HashMap;
-use std::ffi::{OsStr, OsString};
+use hashbrown::{HashMap, HashSet};
 use std::fs::{read_dir, canonicalize};
 use std::io;
-use std::mem::replace;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crossbeam::Sender;
-use inotify::{Event as InotifyEvent, EventMask, Inotify, WatchDescriptor, WatchMask};
+use crossbeam::{Receiver, Sender, TryRecvError};
+use metrics::health::HEALTH;
 
+use crate::backend::{Backend, Change, InotifyBackend};
 use crate::error::WatchError;
 use crate::Event;
+use crate::poll::PollBackend;
 use crate::rule::{Rule, Rules, Status};
 
 //todo provide examples and some extra tid bits around operational behavior
 /// Used to watch the filesystem for [Events](../enum.Event.html)
 ///
 /// Also has support for exclusion and inclusion rules to narrow the scope of watched files/directories
+///
+/// Dirs are watched with inotify by default, dirs on filesystems that don't deliver inotify events
+/// (e.g NFS) can be polled instead, see WatchBuilder::poll
 pub struct Watcher {
-    // An instance of inotify
-    inotify: Inotify,
-    // A mapping of watch descriptors to paths
-    // This is required because inotify operates on a watch list which (a list of i64s)
-    // This provides a mapping of those ids to the corresponding paths
+    // The backends used to detect changes
+    // The inotify backend is always first, followed by the polling backend if any dirs are polled
+    backends: Vec<Box<dyn Backend>>,
+    // A mapping of watched paths to the backend watching them
     // The invariant that is relied on here is that is mapping is always correct
-    // The main mechanism for breaking this invariant is overflowing the kernel queue (Q_OVERFLOW)
-    watch_descriptors: HashMap<WatchDescriptor, PathBuf>,
+    // The main mechanism for breaking this invariant is a backend losing changes, e.g overflowing the kernel queue
+    // When that happens the mapping is rebuilt from scratch, see Watcher::recover
+    watched: HashMap<PathBuf, Watched>,
     // A list of inclusion and exclusion rules
     rules: Rules,
-    // The list of dirs to watch on startup, e.g /var/log/
+    // The number of times a backend lost changes and the watcher had to recover
+    overflows: u64,
+    // The list of dirs to watch on startup, e.g /var/log/, along with the index of the backend that watches them
+    // These dirs are rescanned to rebuild the mapping of watched paths after a kernel queue overflow
     // These dirs will be watched recursively
     // So if /var/log/ is in this list, /var/log/httpd/ is redundant
-    initial_dirs: Vec<PathBuf>,
+    initial_dirs: Vec<(PathBuf, usize)>,
     // A duration that the event loop will wait before polling again
     // Effectively a dumb rate limit, in the case the sender is unbounded
     loop_interval: Duration,
+    // Stops the watcher when it's sender is dropped, if set
+    shutdown: Option<Receiver<()>>,
+    // Receives new dirs and rules to apply while running, if set
+    reload: Option<Receiver<Reload>>,
+    // The interval of the polling backend, kept in case a reload adds the first polled dir
+    poll_interval: Duration,
+    // Renamed files whose new name doesn't pass the rules, along with when they were last written to
+    // The writer may not have reopened it's log yet, so these are kept until they go idle
+    draining: HashMap<PathBuf, Instant>,
+    // How long a draining file has to go without writes before it's dropped
+    drain_timeout: Duration,
+}
+
+/// New dirs and rules for a running [Watcher](struct.Watcher.html), see WatchBuilder::reload
+///
+/// These replace the watcher's initial dirs and rules, anything that is no longer covered is unwatched
+pub struct Reload {
+    pub dirs: Vec<PathBuf>,
+    pub poll_dirs: Vec<PathBuf>,
+    pub rules: Rules,
+}
+
+// A watched file or dir
+#[derive(Clone, Copy, Debug)]
+struct Watched {
+    // The index of the backend watching the path
+    backend: usize,
+    // The device and inode the path pointed to when it was watched
+    dev: u64,
+    inode: u64,
 }
 
 impl Watcher {
@@ -43,8 +80,13 @@ impl Watcher {
     pub fn builder() -> WatchBuilder {
         WatchBuilder {
             initial_dirs: Vec::new(),
+            poll_dirs: Vec::new(),
+            poll_interval: Duration::from_secs(1),
             loop_interval: Duration::from_millis(50),
             rules: Rules::new(),
+            shutdown: None,
+            reload: None,
+            drain_timeout: Duration::from_secs(5),
         }
     }
     /// Runs the main logic loop of the watcher, consuming itself because run can only be called once
@@ -53,35 +95,46 @@ impl Watcher {
     /// to prevent kernel over flow. However, being unbounded isn't a hard requirement.
     pub fn run(mut self, sender: Sender<Event>) {
         // iterate over all initial dirs and add them to the watcher
-        // replace is need because it takes owner ship of the initial_dirs field without consuming self
-        for dir in replace(&mut self.initial_dirs, Vec::new()) {
-            // if the watch was successful a list of watched paths will be returned
-            // we only create Initiate events for files
-            // the events get sent upstream through sender
-            match self.watch(&dir) {
-                Ok(paths) => paths.into_iter()
-                    .filter(|p| p.is_file())
-                    .for_each(|p| sender.send(Event::Initiate(p)).unwrap()),
-                Err(e) => error!("error initializing root path {:?}: {:?}", dir, e),
+        // we only create Initiate events for files
+        // the events get sent upstream through sender
+        for path in self.watch_initial_dirs() {
+            if path.is_file() {
+                sender.send(Event::Initiate(path)).unwrap();
             }
         }
 
-        // stack allocated buffer for reading inotify events
-        let mut buf = [0u8; 4096];
-        // infinite loop that constantly reads the inotify file descriptor when it has data
+        // with a single backend we can wait on it for changes, otherwise each backend is checked in turn
+        // we also can't wait if we need to check for shutdown or reloads
+        let block = self.backends.len() == 1 && self.shutdown.is_none() && self.reload.is_none();
+        // a watcher that waits for changes can't heartbeat while idle, so only one that loops is checked
+        let heartbeat = match block {
+            true => None,
+            false => Some(HEALTH.register("watcher")),
+        };
+        // loop that constantly reads changes from the backends until shutdown
         // if the sender passed in to run() is bounded this loop can be blocked if that sender hits capacity
         loop {
-            let events = match self.inotify.read_events_blocking(&mut buf) {
-                Ok(events) => events,
-                Err(e) => {
-                    error!("error reading from inotify fd: {}", e);
-                    continue;
+            if let Some(ref heartbeat) = heartbeat {
+                heartbeat.beat();
+            }
+            if let Some(ref shutdown) = self.shutdown {
+                if let Err(TryRecvError::Disconnected) = shutdown.try_recv() {
+                    info!("watcher stopped");
+                    return;
                 }
-            };
-            // process all events we just read
-            for event in events {
-                self.process(event, &sender);
             }
+            let reload = self.reload.as_ref().and_then(|r| r.try_recv().ok());
+            if let Some(reload) = reload {
+                self.reload(reload, &sender);
+            }
+            // draining files are only dropped by this loop, so don't wait on the backend while there are any
+            let wait = block && self.draining.is_empty();
+            for backend in 0..self.backends.len() {
+                for change in self.backends[backend].changes(wait) {
+                    self.process(backend, change, &sender);
+                }
+            }
+            self.drop_drained(&sender);
             //sleep for loop_interval duration
             sleep(self.loop_interval)
         }
@@ -90,8 +143,12 @@ impl Watcher {
     ///
     /// This scan has an unlimited depth, so watching /var/log/ will capture all the root and all children
     pub fn watch<P: Into<PathBuf>>(&mut self, path: P) -> Result<Vec<PathBuf>, WatchError> {
+        self.watch_with(0, path.into())
+    }
+    // watches a file or directory using a specific backend
+    fn watch_with(&mut self, backend: usize, path: PathBuf) -> Result<Vec<PathBuf>, WatchError> {
         let mut paths = Vec::new();
-        let path = canonicalize(path.into())?;
+        let path = canonicalize(path)?;
         // paths needs to be valid utf8
         let path_str = path.to_str().ok_or_else(|| WatchError::PathNonUtf8(path.clone()))?;
         // if the path is a dir we need to scan it recursively
@@ -103,9 +160,9 @@ impl Watcher {
                 .for_each(|(p, s)| {
                     // we only apply exclusion/inclusion rules to files
                     if p.is_dir() || self.path_is_ok(&s) {
-                        // if the path is added to inotify successfully
+                        // if the path is added to the backend successfully
                         // we push it onto the paths vec to be return upstream
-                        match self.add(&p) {
+                        match self.add(backend, &p) {
                             Ok(_) => paths.push(p),
                             Err(e) => error!("error adding {:?} to watcher: {:?}", p, e)
                         }
@@ -115,17 +172,29 @@ impl Watcher {
             // in this case we are watching a file
             // check that is passes our inclusion/exclusion rules and push it
             if self.path_is_ok(path_str) {
-                self.add(&path)?;
+                self.add(backend, &path)?;
                 paths.push(path);
             }
         }
 
         Ok(paths)
     }
-    // adds path to inotify and watch descriptor map
-    fn add(&mut self, path: &PathBuf) -> Result<(), WatchError> {
+    // watches all the initial dirs, returning every path that is now watched
+    fn watch_initial_dirs(&mut self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for (dir, backend) in self.initial_dirs.clone() {
+            // if the watch was successful a list of watched paths will be returned
+            match self.watch_with(backend, dir.clone()) {
+                Ok(mut v) => paths.append(&mut v),
+                Err(e) => error!("error initializing root path {:?}: {:?}", dir, e),
+            }
+        }
+        paths
+    }
+    // adds path to a backend and the mapping of watched paths
+    fn add(&mut self, backend: usize, path: &Path) -> Result<(), WatchError> {
         // make sure that the path passed in is not a symlink
-        let path = canonicalize(path.clone())?;
+        let path = canonicalize(path)?;
 
         // runtime check to make sure that we are following symlinks correctly
         // no symlink should ever get passed to this function
@@ -133,11 +202,15 @@ impl Watcher {
         // we check the file exists and that an error is returned meaning its not a symlink
         assert!(path.exists() && path.read_link().is_err());
 
-        // add the path to the inotify with the appropriate mask
-        let watch_descriptor = self.inotify.add_watch(&path, watch_mask(&path))?;
-        // add the watch descriptor to the map so we can resolve the path later
+        let metadata = path.metadata()?;
+        self.backends[backend].add(&path)?;
+        // add the path to the map so we know which backend is watching it
         info!("added {:?} to watcher", path);
-        self.watch_descriptors.insert(watch_descriptor, path);
+        self.watched.insert(path, Watched {
+            backend,
+            dev: metadata.dev(),
+            inode: metadata.ino(),
+        });
         Ok(())
     }
     // a helper for checking if a path passes exclusion/inclusion rules
@@ -154,56 +227,227 @@ impl Watcher {
             }
         }
     }
-    // handles inotify events and may produce Event(s) that are return upstream through sender
-    fn process(&mut self, event: InotifyEvent<&OsStr>, sender: &Sender<Event>) {
-        if event.mask.contains(EventMask::CREATE) {
-            let path = match self.watch_descriptors.get(&event.wd)
-                .map(|p| p.join(event.name.unwrap_or(&OsString::new()))) {
-                Some(v) => v,
-                None => { return; }
+    // handles backend changes and may produce Event(s) that are return upstream through sender
+    fn process(&mut self, backend: usize, change: Change, sender: &Sender<Event>) {
+        match change {
+            Change::Create(path) => self.create(backend, path, sender),
+            Change::Modify(path) => {
+                if self.watched.contains_key(&path) {
+                    if let Some(last_write) = self.draining.get_mut(&path) {
+                        *last_write = Instant::now();
+                    }
+                    sender.send(Event::Write(path)).unwrap();
+                }
+            }
+            // stop watching anything that was deleted or moved out of the watched dirs
+            Change::Delete(path) => {
+                let removed: Vec<PathBuf> = self.watched.keys()
+                    .filter(|p| p.starts_with(&path))
+                    .cloned()
+                    .collect();
+                for path in removed {
+                    self.unwatch(&path);
+                    sender.send(Event::Delete(path)).unwrap();
+                }
+            }
+            Change::Move(from, to) => self.rename(backend, from, to, sender),
+            Change::Overflow => self.recover(backend, sender),
+        }
+    }
+    // rebuilds the mapping of paths a backend watches after it lost changes
+    //
+    // changes were dropped so the mapping can no longer be trusted, instead the backend's initial dirs are rescanned
+    // the new mapping is diffed against the old one by inode rather than by path to pick up renames
+    // every file still in place gets a Write event in case we missed writes to it
+    fn recover(&mut self, backend: usize, sender: &Sender<Event>) {
+        self.overflows += 1;
+        metrics::FS_WATCH_OVERFLOWS.inc();
+        warn!("lost filesystem changes, rescanning watched dirs (overflow count: {})", self.overflows);
+
+        let old: HashMap<(u64, u64), PathBuf> = self.watched.iter()
+            .filter(|(_, w)| w.backend == backend)
+            .map(|(p, w)| ((w.dev, w.inode), p.clone()))
+            .collect();
+        self.watched.retain(|_, w| w.backend != backend);
+        // draining files don't pass the rules, so the rescan drops them along with anything else that's gone
+        for path in old.values() {
+            self.draining.remove(path);
+        }
+        for (dir, _) in self.initial_dirs.clone().into_iter().filter(|(_, b)| *b == backend) {
+            if let Err(e) = self.watch_with(backend, dir.clone()) {
+                error!("error initializing root path {:?}: {:?}", dir, e);
+            }
+        }
+
+        let mut kept = HashSet::new();
+        for (path, w) in self.watched.iter().filter(|(_, w)| w.backend == backend) {
+            kept.insert((w.dev, w.inode));
+            if !path.is_file() {
+                continue;
+            }
+            let event = match old.get(&(w.dev, w.inode)) {
+                Some(old_path) if old_path == path => Event::Write(path.clone()),
+                Some(old_path) => Event::Rename(old_path.clone(), path.clone()),
+                None => Event::New(path.clone()),
             };
+            sender.send(event).unwrap();
+        }
 
-            match self.watch(&path) {
-                Ok(paths) => paths.into_iter()
-                    .filter(|p| p.is_file())
-                    .for_each(|p| sender.send(Event::New(p)).unwrap()),
-                Err(e) => error!("error adding root path {:?}: {:?}", path, e),
+        for (id, path) in old {
+            if self.watched.contains_key(&path) {
+                continue;
+            }
+            self.backends[backend].remove(&path);
+            // a file that was renamed has already been reported
+            if !kept.contains(&id) {
+                info!("removed {:?} from watcher", path);
+                sender.send(Event::Delete(path)).unwrap();
             }
         }
+    }
+    // replaces the initial dirs and rules, then diffs what should be watched against what is watched
+    //
+    // files that are no longer covered get a Delete event, files that now are get an Initiate event
+    // the same as on startup, paths that moved to a different backend are silently rewatched
+    fn reload(&mut self, reload: Reload, sender: &Sender<Event>) {
+        info!("reloading watcher with dirs {:?} and polled dirs {:?}", reload.dirs, reload.poll_dirs);
+        self.rules = reload.rules;
+        self.initial_dirs = reload.dirs.into_iter().map(|p| (p, 0)).collect();
+        if !reload.poll_dirs.is_empty() {
+            if self.backends.len() == 1 {
+                self.backends.push(Box::new(PollBackend::new(self.poll_interval)));
+            }
+            self.initial_dirs.extend(reload.poll_dirs.into_iter().map(|p| (p, 1)));
+        }
 
-        if event.mask.contains(EventMask::MODIFY) {
-            self.watch_descriptors.get(&event.wd)
-                .and_then(|path|
-                    sender.send(Event::Write(path.clone())).ok()
-                );
+        // every path that should be watched, along with the backend that should watch it
+        // a path under several initial dirs goes to the first one's backend
+        let mut wanted: HashMap<PathBuf, usize> = HashMap::new();
+        for (dir, backend) in &self.initial_dirs {
+            for path in recursive_scan(dir) {
+                let passes = path.to_str().map(|s| self.rules.passes(s).is_ok()).unwrap_or(false);
+                if path.is_dir() || passes {
+                    wanted.entry(path).or_insert(*backend);
+                }
+            }
         }
 
-        if event.mask.contains(EventMask::DELETE_SELF) {
-            self.watch_descriptors.remove(&event.wd)
-                .and_then(|path| {
-                    Some(info!("removed {:?} from watcher", path));
-                    sender.send(Event::Delete(path)).ok()
-                });
+        let removed: Vec<PathBuf> = self.watched.iter()
+            .filter(|(p, w)| wanted.get(*p) != Some(&w.backend))
+            .map(|(p, _)| p.clone())
+            .collect();
+        // paths that are only moving to a different backend, these have already been reported
+        let mut switched = HashSet::new();
+        for path in removed {
+            self.unwatch(&path);
+            if wanted.contains_key(&path) {
+                switched.insert(path);
+            } else if path.is_file() {
+                sender.send(Event::Delete(path)).unwrap();
+            }
         }
 
-        if event.mask.contains(EventMask::Q_OVERFLOW) {
-            panic!("overflowed kernel queue!")
+        for (path, backend) in wanted {
+            if self.watched.contains_key(&path) {
+                continue;
+            }
+            if let Err(e) = self.add(backend, &path) {
+                error!("error adding {:?} to watcher: {:?}", path, e);
+                continue;
+            }
+            if path.is_file() && !switched.contains(&path) {
+                sender.send(Event::Initiate(path)).unwrap();
+            }
         }
     }
-}
+    /// Returns the number of times a backend lost changes, e.g the kernel queue overflowed
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+    // watches a newly created path, sending a New event for every file found
+    fn create(&mut self, backend: usize, path: PathBuf, sender: &Sender<Event>) {
+        match self.watch_with(backend, path.clone()) {
+            Ok(paths) => paths.into_iter()
+                .filter(|p| p.is_file())
+                .for_each(|p| sender.send(Event::New(p)).unwrap()),
+            Err(e) => error!("error adding root path {:?}: {:?}", path, e),
+        }
+    }
+    // moves the watches of a renamed file or dir (and everything under it) to the new path
+    //
+    // the backend keeps watching the same files, only their paths change
+    // a Rename event is sent for every file so the Tailer can finish reading it under the new name
+    // files whose new name doesn't pass the rules keep being watched until they go idle, see Watcher::drop_drained
+    fn rename(&mut self, backend: usize, from: PathBuf, to: PathBuf, sender: &Sender<Event>) {
+        let moved: Vec<PathBuf> = self.watched.keys()
+            .filter(|p| p.starts_with(&from))
+            .cloned()
+            .collect();
+        // we weren't watching the old path, e.g it was excluded, so treat it like a new file
+        if moved.is_empty() {
+            self.create(backend, to, sender);
+            return;
+        }
+        // anything we were watching at the new path was just replaced by the rename
+        let replaced: Vec<PathBuf> = self.watched.keys()
+            .filter(|p| p.starts_with(&to) && !p.starts_with(&from))
+            .cloned()
+            .collect();
+        for path in replaced {
+            self.unwatch(&path);
+        }
+
+        for old_path in moved {
+            let new_path = match old_path.strip_prefix(&from) {
+                Ok(v) if v.as_os_str().is_empty() => to.clone(),
+                Ok(v) => to.join(v),
+                Err(_) => continue,
+            };
+            let watched = match self.watched.remove(&old_path) {
+                Some(v) => v,
+                None => continue,
+            };
+            self.backends[watched.backend].rename(&old_path, &new_path);
+            self.watched.insert(new_path.clone(), watched);
+            let last_write = self.draining.remove(&old_path);
+
+            if !new_path.is_file() {
+                continue;
+            }
 
-// returns the watch mask depending on if a path is a file or dir
-fn watch_mask(path: &PathBuf) -> WatchMask {
-    if path.is_file() {
-        WatchMask::MODIFY | WatchMask::DELETE_SELF
-    } else {
-        WatchMask::CREATE | WatchMask::DELETE_SELF
+            info!("renamed {:?} to {:?} in watcher", old_path, new_path);
+            sender.send(Event::Rename(old_path, new_path.clone())).unwrap();
+            let passes = new_path.to_str().map(|s| self.path_is_ok(s)).unwrap_or(false);
+            if !passes {
+                self.draining.insert(new_path, last_write.unwrap_or_else(Instant::now));
+            }
+        }
+    }
+    // drops draining files that haven't been written to for the drain timeout with a Delete event
+    fn drop_drained(&mut self, sender: &Sender<Event>) {
+        let timeout = self.drain_timeout;
+        let drained: Vec<PathBuf> = self.draining.iter()
+            .filter(|(_, last_write)| last_write.elapsed() >= timeout)
+            .map(|(p, _)| p.clone())
+            .collect();
+        for path in drained {
+            self.unwatch(&path);
+            sender.send(Event::Delete(path)).unwrap();
+        }
+    }
+    // removes a path from it's backend and the mapping of watched paths
+    fn unwatch(&mut self, path: &Path) {
+        self.draining.remove(path);
+        if let Some(watched) = self.watched.remove(path) {
+            info!("removed {:?} from watcher", path);
+            self.backends[watched.backend].remove(path);
+        }
     }
 }
 
 // recursively scans a directory for unlimited depth
-fn recursive_scan(path: &PathBuf) -> Vec<PathBuf> {
-    let path = match canonicalize(path.clone()) {
+fn recursive_scan(path: &Path) -> Vec<PathBuf> {
+    let path = match canonicalize(path) {
         Ok(v) => v,
         Err(_) => {
             return Vec::new();
@@ -244,12 +488,18 @@ fn recursive_scan(path: &PathBuf) -> Vec<PathBuf> {
 /// Creates an instance of a Watcher
 pub struct WatchBuilder {
     initial_dirs: Vec<PathBuf>,
+    poll_dirs: Vec<PathBuf>,
+    poll_interval: Duration,
     loop_interval: Duration,
     rules: Rules,
+    shutdown: Option<Receiver<()>>,
+    reload: Option<Receiver<Reload>>,
+    drain_timeout: Duration,
 }
 
 impl WatchBuilder {
     /// Add a dir to the list of initial dirs
+    #[allow(clippy::should_implement_trait)]
     pub fn add<T: Into<PathBuf>>(mut self, path: T) -> Self {
         self.initial_dirs.push(path.into());
         self
@@ -259,11 +509,34 @@ impl WatchBuilder {
         self.initial_dirs.extend_from_slice(path.as_ref());
         self
     }
+    /// Add a dir to the list of initial dirs that are polled instead of watched with inotify
+    pub fn poll<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.poll_dirs.push(path.into());
+        self
+    }
+    /// Add a multiple dirs to the list of initial dirs that are polled instead of watched with inotify
+    pub fn poll_all<T: AsRef<[PathBuf]>>(mut self, path: T) -> Self {
+        self.poll_dirs.extend_from_slice(path.as_ref());
+        self
+    }
+    /// Sets the interval polled dirs are checked for changes at
+    pub fn poll_interval<T: Into<Duration>>(mut self, duration: T) -> Self {
+        self.poll_interval = duration.into();
+        self
+    }
     /// Sets the loop interval
     pub fn loop_interval<T: Into<Duration>>(mut self, duration: T) -> Self {
         self.loop_interval = duration.into();
         self
     }
+    /// Sets how long a file renamed to a path that doesn't pass the rules, e.g a rotated log, is still
+    /// watched without being written to before it's dropped
+    ///
+    /// This gives the writer time to reopen it's log, defaults to 5 seconds
+    pub fn drain_timeout<T: Into<Duration>>(mut self, duration: T) -> Self {
+        self.drain_timeout = duration.into();
+        self
+    }
     /// Adds an inclusion rule
     pub fn include<T: Rule + Send + 'static>(mut self, rule: T) -> Self {
         self.rules.add_inclusion(rule);
@@ -274,6 +547,18 @@ impl WatchBuilder {
         self.rules.add_exclusion(rule);
         self
     }
+    /// Sets a receiver that stops the watcher once it's sender is dropped
+    ///
+    /// When the watcher stops it drops the sender passed to run, letting the consumer of events shut down in turn
+    pub fn shutdown(mut self, shutdown: Receiver<()>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+    /// Sets a receiver of new dirs and rules to apply while the watcher is running
+    pub fn reload(mut self, reload: Receiver<Reload>) -> Self {
+        self.reload = Some(reload);
+        self
+    }
     /// Appends all rules from another instance of rules
     pub fn append_all<T: Into<Rules>>(mut self, rules: T) -> Self {
         self.rules.add_all(rules);
@@ -281,12 +566,195 @@ impl WatchBuilder {
     }
     /// Consumes the builder and produces an instance of the watcher
     pub fn build(self) -> Result<Watcher, io::Error> {
+        let mut backends: Vec<Box<dyn Backend>> = vec![Box::new(InotifyBackend::new()?)];
+        let mut initial_dirs: Vec<(PathBuf, usize)> = self.initial_dirs.into_iter()
+            .map(|p| (p, 0))
+            .collect();
+        if !self.poll_dirs.is_empty() {
+            backends.push(Box::new(PollBackend::new(self.poll_interval)));
+            initial_dirs.extend(self.poll_dirs.into_iter().map(|p| (p, 1)));
+        }
+
         Ok(Watcher {
-            inotify: Inotify::init()?,
-            watch_descriptors: HashMap::new(),
+            backends,
+            watched: HashMap::new(),
             rules: self.rules,
-            initial_dirs: self.initial_dirs,
+            overflows: 0,
+            initial_dirs,
             loop_interval: self.loop_interval,
+            shutdown: self.shutdown,
+            reload: self.reload,
+            poll_interval: self.poll_interval,
+            draining: HashMap::new(),
+            drain_timeout: self.drain_timeout,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{rename, write, OpenOptions};
+    use std::io::Write;
+    use std::thread::spawn;
+
+    use crossbeam::{unbounded, Receiver};
+    use tempfile::tempdir;
+
+    use crate::rule::GlobRule;
+
+    fn next(receiver: &Receiver<Event>) -> String {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap().to_string()
+    }
+
+    fn reports_rotation(builder: fn(&Path) -> WatchBuilder) {
+        let dir = tempdir().unwrap();
+        let dir_path = canonicalize(dir.path()).unwrap();
+        let log = dir_path.join("app.log");
+        let rotated = dir_path.join("app.log.1");
+        write(&log, "line\n").unwrap();
+
+        let watcher = builder(&dir_path)
+            .include(GlobRule::new("*.log").unwrap())
+            .drain_timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let (sender, receiver) = unbounded();
+        spawn(move || watcher.run(sender));
+        assert_eq!(next(&receiver), Event::Initiate(log.clone()).to_string());
+
+        // logrotate's default rename then create
+        rename(&log, &rotated).unwrap();
+        write(&log, "new line\n").unwrap();
+
+        assert_eq!(next(&receiver), Event::Rename(log.clone(), rotated.clone()).to_string());
+        assert_eq!(next(&receiver), Event::New(log.clone()).to_string());
+        // the writer hasn't reopened it's log yet, so it still writes to app.log.1
+        OpenOptions::new().append(true).open(&rotated).unwrap().write_all(b"late line\n").unwrap();
+        assert_eq!(next(&receiver), Event::Write(rotated.clone()).to_string());
+        // app.log.1 isn't included so it's dropped once it goes idle
+        assert_eq!(next(&receiver), Event::Delete(rotated).to_string());
+    }
+
+    #[test]
+    fn reports_rotation_with_inotify() {
+        reports_rotation(|dir| Watcher::builder().add(dir))
+    }
+
+    #[test]
+    fn reports_rotation_with_polling() {
+        reports_rotation(|dir| Watcher::builder().poll(dir).poll_interval(Duration::from_millis(10)))
+    }
+
+    // a backend that only reports the changes a test sends it
+    struct MockBackend(Receiver<Change>);
+
+    impl Backend for MockBackend {
+        fn add(&mut self, _: &Path) -> Result<(), WatchError> {
+            Ok(())
+        }
+
+        fn remove(&mut self, _: &Path) {}
+
+        fn rename(&mut self, _: &Path, _: &Path) {}
+
+        fn changes(&mut self, _: bool) -> Vec<Change> {
+            self.0.try_iter().collect()
+        }
+    }
+
+    #[test]
+    fn recovers_from_overflow() {
+        let dir = tempdir().unwrap();
+        let dir_path = canonicalize(dir.path()).unwrap();
+        for name in &["renamed.log", "deleted.log", "written.log"] {
+            write(dir_path.join(name), "line\n").unwrap();
+        }
+
+        let (shutdown_sender, shutdown) = unbounded();
+        let mut watcher = Watcher::builder()
+            .add(&dir_path)
+            .include(GlobRule::new("*.log").unwrap())
+            .shutdown(shutdown)
+            .build()
+            .unwrap();
+        let (change_sender, changes) = unbounded();
+        watcher.backends[0] = Box::new(MockBackend(changes));
+        let (sender, receiver) = unbounded();
+        let handle = spawn(move || watcher.run(sender));
+        for _ in 0..3 {
+            next(&receiver);
+        }
+
+        // make changes the backend never reports, as if the kernel dropped them
+        // created.log is written first so it can't reuse the inode of deleted.log
+        rename(dir_path.join("renamed.log"), dir_path.join("renamed.2.log")).unwrap();
+        write(dir_path.join("created.log"), "line\n").unwrap();
+        std::fs::remove_file(dir_path.join("deleted.log")).unwrap();
+        write(dir_path.join("written.log"), "line\nline\n").unwrap();
+        change_sender.send(Change::Overflow).unwrap();
+
+        let mut events: Vec<String> = (0..4).map(|_| next(&receiver)).collect();
+        events.sort();
+        let mut expected: Vec<String> = vec![
+            Event::Delete(dir_path.join("deleted.log")),
+            Event::New(dir_path.join("created.log")),
+            Event::Rename(dir_path.join("renamed.log"), dir_path.join("renamed.2.log")),
+            Event::Write(dir_path.join("written.log")),
+        ].into_iter().map(|e| e.to_string()).collect();
+        expected.sort();
+        assert_eq!(events, expected);
+
+        drop(shutdown_sender);
+        handle.join().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn applies_reloads() {
+        let dir = tempdir().unwrap();
+        let dir_path = canonicalize(dir.path()).unwrap();
+        let old_dir = dir_path.join("old");
+        let new_dir = dir_path.join("new");
+        for (dir, name) in &[(&old_dir, "app.log"), (&new_dir, "app.log"), (&new_dir, "app.txt")] {
+            std::fs::create_dir_all(dir).unwrap();
+            write(dir.join(name), "line\n").unwrap();
+        }
+
+        let mut watcher = Watcher::builder()
+            .add(&old_dir)
+            .include(GlobRule::new("*.log").unwrap())
+            .build()
+            .unwrap();
+        let (sender, receiver) = unbounded();
+        watcher.watch_initial_dirs();
+
+        let mut rules = Rules::new();
+        rules.add_inclusion(GlobRule::new("*.log").unwrap());
+        watcher.reload(Reload { dirs: vec![new_dir.clone()], poll_dirs: vec![old_dir.clone()], rules }, &sender);
+        drop(sender);
+
+        // old/app.log moved to the polling backend, so it's neither deleted nor initiated
+        let events: Vec<String> = receiver.iter().map(|e| e.to_string()).collect();
+        assert_eq!(events, vec![Event::Initiate(new_dir.join("app.log")).to_string()]);
+        assert_eq!(watcher.watched[&old_dir.join("app.log")].backend, 1);
+        assert!(!watcher.watched.contains_key(&new_dir.join("app.txt")));
+
+        let mut rules = Rules::new();
+        rules.add_inclusion(GlobRule::new("*.txt").unwrap());
+        let (sender, receiver) = unbounded();
+        watcher.reload(Reload { dirs: vec![new_dir.clone()], poll_dirs: Vec::new(), rules }, &sender);
+        drop(sender);
+
+        let mut events: Vec<String> = receiver.iter().map(|e| e.to_string()).collect();
+        events.sort();
+        let mut expected: Vec<String> = vec![
+            Event::Delete(old_dir.join("app.log")),
+            Event::Delete(new_dir.join("app.log")),
+            Event::Initiate(new_dir.join("app.txt")),
+        ].into_iter().map(|e| e.to_string()).collect();
+        expected.sort();
+        assert_eq!(events, expected);
+    }
+}
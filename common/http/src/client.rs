@@ -1,32 +1,50 @@
-use std::mem::replace;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+use hashbrown::HashMap;
+
 use crossbeam::{after, bounded, Receiver, Sender};
 use either::Either;
+use metrics::health::HEALTH;
+use metrics::IntGauge;
 use tokio::prelude::Future;
 use tokio::runtime::Runtime;
 
+use crate::ingest::{IngestClient, Response};
+use crate::retry::{Attempts, Retryable};
+use crate::timestamp;
 use crate::types::body::{IngestBody, Line, LineBuilder};
-use crate::types::client::Client as HttpClient;
 use crate::types::error::HttpError;
 use crate::types::request::RequestTemplate;
-use crate::types::response::Response;
 
 /// Http(s) client used to send logs to the Ingest API
 pub struct Client {
-    inner: HttpClient,
+    inner: IngestClient,
     runtime: Runtime,
     line_sender: Sender<LineBuilder>,
     line_receiver: Receiver<LineBuilder>,
-    retry_in_sender: Sender<IngestBody>,
-    retry_in_receiver: Receiver<IngestBody>,
-    retry_out_sender: Sender<Arc<IngestBody>>,
+    retry_in_sender: Sender<Retryable>,
+    retry_in_receiver: Receiver<Retryable>,
+    retry_out_sender: Sender<Retryable>,
+    template_sender: Sender<RequestTemplate>,
+    template_receiver: Receiver<RequestTemplate>,
+    // statuses that are retried, e.g throttling or an ingest outage
+    retry_statuses: Arc<Vec<u16>>,
+    // statuses that will never succeed on retry, e.g a bad ingestion key
+    permanent_statuses: Arc<Vec<u16>>,
+    // bodies that have been sent but haven't had a response yet, spooled for retry if still unsent at shutdown
+    in_flight: Arc<Mutex<HashMap<u64, Retryable>>>,
+    next_id: u64,
+    // how long to wait for in flight bodies at shutdown
+    shutdown_timeout: Duration,
 
     buffer: Vec<Line>,
     buffer_max_size: usize,
     buffer_bytes: usize,
     buffer_timeout: Receiver<Instant>,
+    // the number of lines waiting to be buffered
+    queued: IntGauge,
 }
 
 impl Client {
@@ -34,67 +52,107 @@ impl Client {
     /// and a request template for building ingest requests
     pub fn new(template: RequestTemplate) -> Self {
         let mut runtime = Runtime::new().expect("Runtime::new()");
-        let (s, r) = bounded(256);
+        let capacity = 256;
+        let (s, r) = bounded(capacity);
         let (temp, _) = bounded(0);
-        let (retry_in_sender, retry_in_receiver) = bounded(256);
+        // a rendezvous channel, so a body is never left in the channel when the client shuts down
+        let (retry_in_sender, retry_in_receiver) = bounded(0);
+        let (template_sender, template_receiver) = bounded(1);
         Self {
-            inner: HttpClient::new(template, &mut runtime),
+            inner: IngestClient::new(template, &mut runtime),
             runtime,
             line_sender: s,
             line_receiver: r,
             retry_in_sender,
             retry_in_receiver,
             retry_out_sender: temp,
+            template_sender,
+            template_receiver,
+            retry_statuses: Arc::new(vec![408, 429, 500, 502, 503, 504]),
+            permanent_statuses: Arc::new(vec![400, 401, 403]),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            next_id: 0,
+            shutdown_timeout: Duration::from_secs(10),
 
             buffer: Vec::new(),
             buffer_max_size: 2 * 1024 * 1024,
             buffer_bytes: 0,
             buffer_timeout: new_timeout(),
+            queued: metrics::channel("client", capacity),
         }
     }
     /// Returns the channel senders used to send data from other threads
-    pub fn sender(&self) -> (Sender<LineBuilder>, Sender<IngestBody>) {
+    pub fn sender(&self) -> (Sender<LineBuilder>, Sender<Retryable>) {
         (self.line_sender.clone(), self.retry_in_sender.clone())
     }
 
     pub fn retry_sender(&self) -> Sender<LineBuilder> {
         self.line_sender.clone()
     }
+    /// Returns a sender of request templates, each one replaces the template used for new requests
+    pub fn template_sender(&self) -> Sender<RequestTemplate> {
+        self.template_sender.clone()
+    }
 
     /// The main logic loop, consumes self because it should only be called once
-    pub fn run(mut self, retry_sender: Sender<Arc<IngestBody>>) {
+    ///
+    /// Returns once every sender of lines has been dropped, after sending the buffered lines
+    /// and waiting for in flight requests, anything still unsent is handed to the retry queue
+    pub fn run(mut self, retry_sender: Sender<Retryable>) {
         self.retry_out_sender = retry_sender;
+        // drop our own sender so the channel disconnects once everyone else's is gone
+        self.line_sender = bounded(0).0;
 
+        // the buffer timeout wakes the loop up often enough to heartbeat while idle
+        let heartbeat = HEALTH.register("client");
         loop {
+            heartbeat.beat();
             if self.buffer_bytes < self.buffer_max_size {
                 let msg = select! {
-                    recv(self.line_receiver) -> msg => msg.map(|m| Either::Left(m)),
-                    recv(self.retry_in_receiver) -> msg => msg.map(|m| Either::Right(m)),
+                    recv(self.line_receiver) -> msg => msg.map(Either::Left),
+                    recv(self.retry_in_receiver) -> msg => msg.map(Either::Right),
                     recv(self.buffer_timeout) -> _ => {
                         self.flush();
                         continue;
                     },
+                    recv(self.template_receiver) -> msg => {
+                        if let Ok(template) = msg {
+                            info!("using new request template");
+                            self.inner.set_template(template);
+                        }
+                        continue;
+                    },
                 };
                 // The left hand side of the either is new lines the come from the Tailer
                 // The right hand side of the either is ingest bodies that are ready for retry
                 match msg {
                     Ok(Either::Left(line)) => {
-                        if let Ok(line) = line.build() {
+                        self.queued.set(self.line_receiver.len() as i64);
+                        if let Ok(line) = timestamp::build(line) {
+                            metrics::HTTP_BUFFERED_LINES.inc();
+                            metrics::HTTP_BUFFERED_BYTES.inc_by(line.line.len() as u64);
                             self.buffer_bytes += line.line.len();
                             self.buffer.push(line);
                         }
                         continue;
                     }
-                    Ok(Either::Right(body)) => {
-                        self.send(body);
+                    Ok(Either::Right(retryable)) => {
+                        let body = match Arc::try_unwrap(retryable.body) {
+                            Ok(v) => v,
+                            Err(v) => v.as_ref().clone(),
+                        };
+                        self.send(body, Some(retryable.attempts));
                         continue;
                     }
-                    Err(_) => {}
+                    // no more lines can arrive, the rest of the agent has shut down
+                    Err(_) => break,
                 };
             } else {
                 self.flush()
             }
         }
+
+        self.shutdown();
     }
 
     pub fn set_max_buffer_size(&mut self, size: usize) {
@@ -104,9 +162,23 @@ impl Client {
     pub fn set_timeout(&mut self, timeout: Duration) {
         self.inner.set_timeout(timeout)
     }
+    /// Sets the response statuses that cause a body to be retried
+    pub fn set_retry_statuses(&mut self, statuses: Vec<u16>) {
+        self.retry_statuses = Arc::new(statuses);
+    }
+    /// Sets the response statuses that are known to never succeed, bodies that get them are dropped
+    ///
+    /// Bodies that get a status in neither list are also dropped, this list only changes how loudly that is logged
+    pub fn set_permanent_statuses(&mut self, statuses: Vec<u16>) {
+        self.permanent_statuses = Arc::new(statuses);
+    }
+    /// Sets how long to wait for in flight requests when shutting down
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
 
     fn flush(&mut self) {
-        let buffer = replace(&mut self.buffer, Vec::new());
+        let buffer = std::mem::take(&mut self.buffer);
         self.buffer_bytes = 0;
         self.buffer_timeout = new_timeout();
 
@@ -114,22 +186,66 @@ impl Client {
             return;
         }
 
-        self.send(IngestBody::new(buffer));
+        self.send(IngestBody::new(buffer), None);
     }
 
-    fn send(&mut self, body: IngestBody) {
+    // sends a body, attempts is only set if the body has already failed to send before
+    fn send(&mut self, body: IngestBody, attempts: Option<Attempts>) {
+        let attempts = attempts.map(Attempts::next).unwrap_or_else(Attempts::first);
+        let body = Arc::new(body);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.in_flight.lock().expect("in flight lock poisoned")
+            .insert(id, Retryable { body: body.clone(), delay: None, attempts });
+
+        let in_flight = self.in_flight.clone();
         let sender = self.retry_out_sender.clone();
+        let retry_statuses = self.retry_statuses.clone();
+        let permanent_statuses = self.permanent_statuses.clone();
+        let start = Instant::now();
         let fut = self.inner.send(body)
             .then(move |r| {
+                metrics::HTTP_REQUEST_DURATION.observe(start.elapsed().as_secs_f64());
+                let result = match r {
+                    Ok(Response::Sent) => "ok".to_string(),
+                    Ok(Response::Failed(_, s, _, _)) => s.as_u16().to_string(),
+                    Err(HttpError::Timeout(_)) => "timeout".to_string(),
+                    Err(_) => "error".to_string(),
+                };
+                metrics::HTTP_REQUESTS.with_label_values(&[&result]).inc();
+                match result.as_str() {
+                    "ok" => HEALTH.ingest_succeeded(),
+                    _ => HEALTH.ingest_failed(),
+                }
+                // the body was already handed to the retry queue by shutdown
+                if in_flight.lock().expect("in flight lock poisoned").remove(&id).is_none() {
+                    return Ok(());
+                }
+                // the retry queue may have already stopped, in which case the body is lost
+                let retry = |retryable| if sender.send(retryable).is_err() {
+                    error!("retry queue stopped, dropping unsent body");
+                };
                 match r {
-                    Ok(Response::Failed(_, s, r)) => warn!("bad response {}: {}", s, r),
+                    Ok(Response::Failed(body, s, r, retry_after)) => {
+                        if retry_statuses.contains(&s.as_u16()) {
+                            warn!("bad response {}, retrying: {}", s, r);
+                            retry(Retryable { body, delay: retry_after, attempts });
+                        } else {
+                            metrics::HTTP_BODIES_REJECTED.with_label_values(&[s.as_str()]).inc();
+                            if permanent_statuses.contains(&s.as_u16()) {
+                                error!("request rejected {}, dropping body: {}", s, r);
+                            } else {
+                                warn!("bad response {}, dropping body: {}", s, r);
+                            }
+                        }
+                    }
                     Err(HttpError::Send(body, e)) => {
                         warn!("failed sending http request, retrying: {}", e);
-                        sender.send(body).unwrap();
+                        retry(Retryable { body, delay: None, attempts });
                     }
                     Err(HttpError::Timeout(body)) => {
                         warn!("failed sending http request, retrying: request timed out!");
-                        sender.send(body).unwrap();
+                        retry(Retryable { body, delay: None, attempts });
                     }
                     Err(e) => {
                         warn!("failed sending http request: {}", e);
@@ -140,8 +256,137 @@ impl Client {
             });
         self.runtime.spawn(fut);
     }
+
+    // sends the buffer, waits for in flight requests and hands anything still unsent to the retry queue
+    fn shutdown(mut self) {
+        self.flush();
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while Instant::now() < deadline && !self.in_flight.lock().expect("in flight lock poisoned").is_empty() {
+            sleep(Duration::from_millis(10));
+        }
+
+        // bodies the retry queue handed back that were never sent
+        let mut unsent: Vec<Retryable> = self.retry_in_receiver.try_iter().collect();
+        unsent.extend(self.in_flight.lock().expect("in flight lock poisoned").drain().map(|(_, r)| r));
+        if !unsent.is_empty() {
+            warn!("spooling {} unsent bodies for retry", unsent.len());
+        }
+        for retryable in unsent {
+            if self.retry_out_sender.send(retryable).is_err() {
+                error!("retry queue stopped, dropping unsent body");
+            }
+        }
+
+        // stop any requests that are still running, their bodies have already been spooled
+        self.runtime.shutdown_now().wait().expect("Runtime::shutdown_now()");
+        info!("client stopped");
+    }
 }
 
 fn new_timeout() -> Receiver<Instant> {
     after(Duration::from_millis(250))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{sleep, spawn};
+
+    use crossbeam::unbounded;
+
+    use crate::types::params::Params;
+    use crate::types::request::Schema;
+
+    // starts a http server on localhost that answers each request with the next scripted response
+    fn mock_server(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                read_request(&mut stream);
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 6\r\nConnection: close\r\n\r\nreason", response).unwrap();
+            }
+        });
+        addr
+    }
+
+    // reads a request, including it's chunked or sized body, so closing the connection doesn't reset it
+    fn read_request<T: Read>(stream: &mut T) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_lowercase();
+            let head_end = match text.find("\r\n\r\n") {
+                Some(v) => v + 4,
+                None => continue,
+            };
+            let content_length = text.lines()
+                .find(|l| l.starts_with("content-length:"))
+                .and_then(|l| l["content-length:".len()..].trim().parse::<usize>().ok());
+            let done = match content_length {
+                Some(len) => request.len() >= head_end + len,
+                None => request.ends_with(b"0\r\n\r\n"),
+            };
+            if n == 0 || done {
+                return;
+            }
+        }
+    }
+
+    fn client(addr: String) -> (Client, crossbeam::Receiver<Retryable>) {
+        let template = RequestTemplate::builder()
+            .schema(Schema::Http)
+            .host(addr)
+            .api_key("key")
+            .params(Params::builder().hostname("test").build().unwrap())
+            .build()
+            .unwrap();
+        let mut client = Client::new(template);
+        let (sender, receiver) = unbounded();
+        client.retry_out_sender = sender;
+        (client, receiver)
+    }
+
+    fn body() -> IngestBody {
+        IngestBody::new(vec![Line::builder().line("test").build().unwrap()])
+    }
+
+    #[test]
+    fn classifies_responses() {
+        let addr = mock_server(vec![
+            "503 Service Unavailable\r\nRetry-After: 7",
+            "401 Unauthorized",
+            "429 Too Many Requests",
+            "200 OK",
+        ]);
+        let (mut client, receiver) = client(addr);
+        let timeout = Duration::from_secs(5);
+        let rejected = metrics::HTTP_BODIES_REJECTED.with_label_values(&["401"]);
+
+        client.send(body(), None);
+        let retryable = receiver.recv_timeout(timeout).unwrap();
+        assert_eq!(retryable.delay, Some(Duration::from_secs(7)));
+
+        client.send(body(), None);
+        let start = Instant::now();
+        while rejected.get() == 0 && start.elapsed() < timeout {
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(rejected.get(), 1);
+
+        client.send(body(), None);
+        let retryable = receiver.recv_timeout(timeout).unwrap();
+        assert_eq!(retryable.delay, None);
+
+        client.send(body(), None);
+        assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+        assert_eq!(rejected.get(), 1);
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::{future, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::header::RETRY_AFTER;
+use hyper::{Client as HyperClient, HeaderMap, StatusCode};
+use hyper_rustls::HttpsConnector;
+use rustls::ClientConfig as TlsConfig;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
+
+use crate::types::body::IngestBody;
+use crate::types::error::HttpError;
+use crate::types::request::RequestTemplate;
+
+/// A response from the Ingest API
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    Sent,
+    // contains the failed body, a status code, a reason the request failed(String)
+    // and how long the server asked us to wait before retrying (Retry-After)
+    Failed(Arc<IngestBody>, StatusCode, String, Option<Duration>),
+}
+
+/// Type alias for a response from `IngestClient::send`
+pub type IngestResponse = Box<dyn Future<Item=Response, Error=HttpError> + Send + 'static>;
+
+/// Sends ingest bodies built from a RequestTemplate
+///
+/// This does the same job as the client in logdna-client, but keeps the response headers
+/// the agent needs to decide if and when a failed body should be retried
+pub struct IngestClient {
+    hyper: Arc<HyperClient<HttpsConnector<HttpConnector>>>,
+    template: RequestTemplate,
+    timeout: Duration,
+}
+
+impl IngestClient {
+    /// Creates a new client that runs it's connections on runtime
+    pub fn new(template: RequestTemplate, runtime: &mut Runtime) -> Self {
+        let http_connector = {
+            // the connector runs on the reactor of whichever runtime thread polls it
+            let mut connector = HttpConnector::new_with_executor(runtime.executor(), None);
+            connector.enforce_http(false); // this is needed or https:// urls will error
+            connector
+        };
+
+        let tls_config = {
+            let mut cfg = TlsConfig::new();
+            cfg.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            cfg
+        };
+
+        Self {
+            hyper: Arc::new(
+                HyperClient::builder()
+                    .max_idle_per_host(20)
+                    .build(HttpsConnector::from((http_connector, tls_config)))
+            ),
+            template,
+            timeout: Duration::from_secs(5),
+        }
+    }
+    /// Sets the request timeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+    /// Replaces the template requests are built from, e.g after a config reload
+    pub fn set_template(&mut self, template: RequestTemplate) {
+        self.template = template
+    }
+    /// Sends a body, the returned future must be spawned on the runtime the client was created with
+    pub fn send(&self, body: Arc<IngestBody>) -> IngestResponse {
+        let hyper = self.hyper.clone();
+        let timeout = self.timeout;
+        let send_body = body.clone();
+        let timeout_body = body.clone();
+        Box::new(
+            self.template.new_request(body.clone())
+                .map_err(HttpError::from)
+                .and_then(move |req|
+                    Timeout::new(
+                        hyper.request(req)
+                            .map_err(move |e| HttpError::Send(send_body, e)),
+                        timeout,
+                    ).map_err(move |e| {
+                        match e.into_inner() {
+                            Some(e) => e,
+                            None => HttpError::Timeout(timeout_body),
+                        }
+                    })
+                )
+                .and_then(|res| {
+                    let status = res.status();
+                    let retry_after = retry_after(res.headers());
+                    res.into_body()
+                        .map_err(Into::into)
+                        .fold(Vec::new(), |mut vec, chunk| {
+                            vec.extend_from_slice(&chunk);
+                            future::ok::<_, HttpError>(vec)
+                        })
+                        .and_then(|reason| String::from_utf8(reason).map_err(Into::into))
+                        .map(move |reason| (status, reason, retry_after))
+                })
+                .map(move |(status, reason, retry_after)| {
+                    if status.is_success() {
+                        Response::Sent
+                    } else {
+                        Response::Failed(body, status, reason, retry_after)
+                    }
+                })
+        )
+    }
+}
+
+// parses the Retry-After header, which is either a number of seconds or a http date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means we can retry right away
+    Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), None);
+    }
+}
@@ -6,7 +6,10 @@ extern crate quick_error;
 extern crate crossbeam;
 
 pub mod client;
+pub mod ingest;
 pub mod retry;
+/// Carries the time a line was logged at through the middlewares
+pub mod timestamp;
 
 pub mod types {
     pub use logdna_client::*;
@@ -1,11 +1,16 @@
-use std::fs::{create_dir_all, File, OpenOptions, read_dir, remove_file};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File, OpenOptions, read_dir, remove_file, rename};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
 use chrono::prelude::Utc;
 use crossbeam::{bounded, Receiver, scope, Sender};
+use metrics::health::HEALTH;
 use uuid::Uuid;
 
 use crate::types::body::IngestBody;
@@ -15,15 +20,19 @@ quick_error! {
     pub enum Error {
         Io(e: std::io::Error) {
             from()
+            display("{}", e)
         }
         Serde(e: serde_json::Error){
             from()
+            display("{}", e)
         }
         Recv(e: crossbeam::RecvError){
             from()
+            display("{}", e)
         }
-        Send(e: crossbeam::SendError<IngestBody>){
+        Send(e: crossbeam::SendError<Retryable>){
             from()
+            display("{}", e)
         }
         NonUTF8(path: std::path::PathBuf){
             display("{:?} is not valid utf8", path)
@@ -34,10 +43,128 @@ quick_error! {
     }
 }
 
+// How often the spool is checked for bodies that are ready to be retried
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An ingest body that failed to send and is queued for retry
+#[derive(Debug)]
+pub struct Retryable {
+    /// The body that failed to send
+    pub body: Arc<IngestBody>,
+    /// How long the server asked us to wait before retrying, e.g from a Retry-After header
+    pub delay: Option<Duration>,
+    /// The attempts made to send the body so far
+    pub attempts: Attempts,
+}
+
+/// Tracks the attempts made to send a body
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Attempts {
+    /// The number of times sending the body has failed
+    pub count: u32,
+    /// The unix timestamp sending the body first failed at
+    pub first_failed: i64,
+}
+
+impl Attempts {
+    /// The attempts of a body that just failed for the first time
+    pub fn first() -> Self {
+        Self { count: 1, first_failed: Utc::now().timestamp() }
+    }
+    /// The attempts of a body after it failed again
+    pub fn next(self) -> Self {
+        Self { count: self.count + 1, ..self }
+    }
+}
+
+/// What to discard when the spool is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    /// Delete the oldest bodies in the spool to make room for the new one
+    DropOldest,
+    /// Discard the new body, keeping what is already in the spool
+    RejectNewest,
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop_oldest" => Ok(Eviction::DropOldest),
+            "reject_newest" => Ok(Eviction::RejectNewest),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+// The in memory accounting of the files in the spool, rebuilt from the dir on startup
+#[derive(Debug, Default)]
+struct Spool {
+    // the size of every file in the spool, ordered from oldest to newest by when their body first failed
+    files: BTreeMap<(i64, PathBuf), u64>,
+    // the total size of all files in the spool
+    bytes: u64,
+}
+
+impl Spool {
+    fn insert(&mut self, first_failed: i64, path: PathBuf, size: u64) {
+        if let Some(old) = self.files.insert((first_failed, path), size) {
+            self.bytes -= old;
+        }
+        self.bytes += size;
+        self.update_metrics();
+    }
+
+    // returns the size of the file, or None if it isn't in the spool, e.g it was evicted
+    fn remove(&mut self, first_failed: i64, path: PathBuf) -> Option<u64> {
+        let size = self.files.remove(&(first_failed, path))?;
+        self.bytes -= size;
+        self.update_metrics();
+        Some(size)
+    }
+
+    fn pop_oldest(&mut self) -> Option<PathBuf> {
+        let key = self.files.keys().next()?.clone();
+        self.bytes -= self.files.remove(&key).unwrap_or_default();
+        self.update_metrics();
+        Some(key.1)
+    }
+
+    fn update_metrics(&self) {
+        metrics::RETRY_SPOOL_BODIES.set(self.files.len() as i64);
+        metrics::RETRY_SPOOL_BYTES.set(self.bytes as i64);
+    }
+}
+
+/// Spools bodies that failed to send to disk and resends them with exponential backoff
+///
+/// Each body is stored in it's own file named `<retry at>_<attempts>_<first failed>_<uuid>.retry`,
+/// bodies that fail too many times or for too long are moved to the dead letter dir.
+/// The spool is capped in bytes and files, once full the eviction policy decides what is discarded
 pub struct Retry {
-    retry_sender: Sender<Arc<IngestBody>>,
-    retry_receiver: Receiver<Arc<IngestBody>>,
-    body_sender: Sender<IngestBody>,
+    retry_sender: Sender<Retryable>,
+    retry_receiver: Receiver<Retryable>,
+    body_sender: Sender<Retryable>,
+
+    dir: PathBuf,
+    dead_letter_dir: PathBuf,
+    base_delay: Duration,
+    max_delay: Duration,
+    max_age: Duration,
+    max_attempts: u32,
+    max_bytes: u64,
+    max_files: usize,
+    eviction: Eviction,
+    spool: Mutex<Spool>,
+    // set once every sender of retries is gone, stops resending
+    stopped: AtomicBool,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Retry {
@@ -48,17 +175,71 @@ impl Retry {
             retry_sender: s,
             retry_receiver: r,
             body_sender: temp,
+
+            dir: PathBuf::from("/tmp/logdna/"),
+            dead_letter_dir: PathBuf::from("/tmp/logdna-dead-letter/"),
+            base_delay: Duration::from_secs(15),
+            max_delay: Duration::from_secs(30 * 60),
+            max_age: Duration::from_secs(24 * 60 * 60),
+            max_attempts: 100,
+            max_bytes: 256 * 1024 * 1024,
+            max_files: 10_000,
+            eviction: Eviction::DropOldest,
+            spool: Mutex::new(Spool::default()),
+            stopped: AtomicBool::new(false),
         }
     }
 
-    pub fn sender(&self) -> Sender<Arc<IngestBody>> {
+    pub fn sender(&self) -> Sender<Retryable> {
         self.retry_sender.clone()
     }
+    /// Sets the dir bodies are spooled in until they are retried
+    pub fn set_dir<T: Into<PathBuf>>(&mut self, dir: T) {
+        self.dir = dir.into();
+    }
+    /// Sets the dir bodies are moved to once they can no longer be retried
+    pub fn set_dead_letter_dir<T: Into<PathBuf>>(&mut self, dir: T) {
+        self.dead_letter_dir = dir.into();
+    }
+    /// Sets the delay before the first retry, which doubles with every attempt
+    pub fn set_base_delay(&mut self, delay: Duration) {
+        self.base_delay = delay;
+    }
+    /// Sets the longest delay between retries
+    pub fn set_max_delay(&mut self, delay: Duration) {
+        self.max_delay = delay;
+    }
+    /// Sets how long after it first failed a body is given up on
+    pub fn set_max_age(&mut self, age: Duration) {
+        self.max_age = age;
+    }
+    /// Sets how many times a body can fail before it is given up on
+    pub fn set_max_attempts(&mut self, attempts: u32) {
+        self.max_attempts = attempts;
+    }
+    /// Sets the max total size of the spooled bodies in bytes
+    pub fn set_max_bytes(&mut self, bytes: u64) {
+        self.max_bytes = bytes;
+    }
+    /// Sets the max number of spooled bodies
+    pub fn set_max_files(&mut self, files: usize) {
+        self.max_files = files;
+    }
+    /// Sets what is discarded when the spool is full
+    pub fn set_eviction(&mut self, eviction: Eviction) {
+        self.eviction = eviction;
+    }
 
-    pub fn run(mut self, body_sender: Sender<IngestBody>) {
+    /// Spools and resends bodies until every sender of retries has been dropped
+    pub fn run(mut self, body_sender: Sender<Retryable>) {
         self.body_sender = body_sender;
+        // drop our own sender so the channel disconnects once everyone else's is gone
+        self.retry_sender = bounded(0).0;
 
-        create_dir_all("/tmp/logdna/").expect("can't create /tmp/logdna");
+        create_dir_all(&self.dir).unwrap_or_else(|_| panic!("can't create {:?}", self.dir));
+        if let Err(e) = self.load_spool() {
+            error!("failed to read spool {:?}: {}", self.dir, e)
+        }
         scope(|s| {
             s.spawn(|_| self.handle_incoming());
             s.spawn(|_| self.handle_outgoing());
@@ -67,72 +248,393 @@ impl Retry {
 
     fn handle_incoming(&self) {
         loop {
-            if let Err(e) = self.poll_incoming() {
-                error!("failed to write retry: {}", e)
+            match self.poll_incoming() {
+                Ok(_) => {}
+                Err(Error::Recv(_)) => break,
+                Err(e) => error!("failed to write retry: {}", e),
             }
         }
+        self.stopped.store(true, Ordering::Relaxed);
+        info!("retry stopped");
     }
 
     fn poll_incoming(&self) -> Result<(), Error> {
-        let body = self.retry_receiver.recv()?;
+        let retryable = self.retry_receiver.recv()?;
+        let attempts = retryable.attempts;
+        let now = Utc::now().timestamp();
 
-        let body = match Arc::try_unwrap(body) {
+        let body = match Arc::try_unwrap(retryable.body) {
             Ok(v) => v,
             Err(v) => v.as_ref().clone(),
         };
 
+        if self.is_expired(attempts, now) {
+            warn!("body first failed {}s ago, moving it to {:?}", now - attempts.first_failed, self.dead_letter_dir);
+            metrics::RETRY_BODIES.with_label_values(&["expired"]).inc();
+            return self.dead_letter(&body, attempts, now);
+        }
+        if attempts.count >= self.max_attempts {
+            warn!("body failed {} times, moving it to {:?}", attempts.count, self.dead_letter_dir);
+            metrics::RETRY_BODIES.with_label_values(&["exhausted"]).inc();
+            return self.dead_letter(&body, attempts, now);
+        }
+
+        // the server knows best when it will be ready, but never retry sooner than our own backoff
+        let delay = self.backoff(attempts.count).max(retryable.delay.unwrap_or_default());
+        let data = serde_json::to_vec(&body)?;
+        let size = data.len() as u64;
+
+        let mut spool = self.spool.lock().expect("spool lock poisoned");
+        while spool.bytes + size > self.max_bytes || spool.files.len() + 1 > self.max_files {
+            let oldest = match self.eviction {
+                Eviction::DropOldest => spool.pop_oldest(),
+                Eviction::RejectNewest => None,
+            };
+            match oldest {
+                Some(path) => {
+                    warn!("retry spool is full, discarding oldest body {:?}", path);
+                    metrics::RETRY_BODIES.with_label_values(&["evicted"]).inc();
+                    if let Err(e) = remove_file(&path) {
+                        error!("failed to remove {:?}: {}", path, e);
+                    }
+                }
+                // either the policy keeps old bodies or there is nothing left to evict, e.g the body is over max_bytes
+                None => {
+                    warn!("retry spool is full, discarding new body of {} bytes", size);
+                    metrics::RETRY_BODIES.with_label_values(&["discarded"]).inc();
+                    return Ok(());
+                }
+            }
+        }
+
+        let path = self.dir.join(file_name(now + delay.as_secs() as i64, attempts));
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)?
+            .write_all(&data)?;
+        spool.insert(attempts.first_failed, path, size);
+        metrics::RETRY_BODIES.with_label_values(&["queued"]).inc();
+        Ok(())
+    }
+    // writes a body that is no longer retried to the dead letter dir
+    fn dead_letter(&self, body: &IngestBody, attempts: Attempts, now: i64) -> Result<(), Error> {
+        create_dir_all(&self.dead_letter_dir)?;
         let file = OpenOptions::new()
-            .create(true)
+            .create_new(true)
             .write(true)
-            .open(format!(
-                "/tmp/logdna/{}_{}.retry",
-                Utc::now().timestamp(),
-                Uuid::new_v4().to_string()
-            ))?;
+            .open(self.dead_letter_dir.join(file_name(now, attempts)))?;
 
-        Ok(serde_json::to_writer(file, &body)?)
+        Ok(serde_json::to_writer(file, body)?)
+    }
+    // rebuilds the accounting of the spool from the files in it
+    fn load_spool(&self) -> Result<(), Error> {
+        let mut spool = self.spool.lock().expect("spool lock poisoned");
+        for file in read_dir(&self.dir)? {
+            let file = file?;
+            let path = file.path();
+            if path.is_dir() {
+                continue;
+            }
+            match parse_file_name(&path) {
+                Ok((_, attempts)) => spool.insert(attempts.first_failed, path, file.metadata()?.len()),
+                Err(e) => warn!("ignoring {:?} in retry spool: {}", path, e),
+            }
+        }
+        info!("retry spool has {} bodies, {} bytes", spool.files.len(), spool.bytes);
+        Ok(())
     }
 
     fn handle_outgoing(&self) {
-        loop {
+        let heartbeat = HEALTH.register("retry");
+        while !self.stopped.load(Ordering::Relaxed) {
+            heartbeat.beat();
             if let Err(e) = self.poll_outgoing() {
                 error!("failed to read retry: {}", e)
             }
-            sleep(Duration::from_secs(15));
+            sleep(POLL_INTERVAL);
         }
     }
 
+    // resends the bodies that are due, a file that can't be handled is skipped rather than failing the whole pass
     fn poll_outgoing(&self) -> Result<(), Error> {
-        let files = read_dir("/tmp/logdna/")?;
+        let files = read_dir(&self.dir)?;
 
         for file in files {
-            let path = file?.path();
+            let path = match file {
+                Ok(v) => v.path(),
+                Err(e) => {
+                    error!("failed to read retry spool {:?}: {}", self.dir, e);
+                    continue;
+                }
+            };
             if path.is_dir() {
                 continue;
             }
 
-            let file_name = path.file_name()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_string())
-                .ok_or(Error::NonUTF8(path.clone()))?;
-            let timestamp: i64 = file_name
-                .split("_")
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>()
-                .get(0)
-                .and_then(|s| FromStr::from_str(s).ok())
-                .ok_or(Error::InvalidFileName(file_name.clone()))?;
-
-            if Utc::now().timestamp() - timestamp < 15 {
+            // load_spool already warned about anything that isn't a retry
+            let (retry_at, attempts) = match parse_file_name(&path) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let now = Utc::now().timestamp();
+            if now < retry_at {
+                continue;
+            }
+
+            // take the file out of the spool first so it can't be evicted while it's being handled
+            let size = match self.spool.lock().expect("spool lock poisoned").remove(attempts.first_failed, path.clone()) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            if self.is_expired(attempts, now) {
+                warn!("body first failed {}s ago, moving it to {:?}", now - attempts.first_failed, self.dead_letter_dir);
+                metrics::RETRY_BODIES.with_label_values(&["expired"]).inc();
+                self.move_to_dead_letter(path, attempts, now, size);
                 continue;
             }
 
-            let file = File::open(&path)?;
-            let body = serde_json::from_reader(file)?;
-            self.body_sender.send(body)?;
-            remove_file(&path)?;
+            let body = match read_body(&path) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed to read retry {:?}, moving it to {:?}: {}", path, self.dead_letter_dir, e);
+                    metrics::RETRY_BODIES.with_label_values(&["unreadable"]).inc();
+                    self.move_to_dead_letter(path, attempts, now, size);
+                    continue;
+                }
+            };
+            if let Err(e) = self.body_sender.send(Retryable { body: Arc::new(body), delay: None, attempts }) {
+                // the client is gone, leave the file in the spool for the next run
+                self.spool.lock().expect("spool lock poisoned").insert(attempts.first_failed, path, size);
+                return Err(e.into());
+            }
+            metrics::RETRY_BODIES.with_label_values(&["resent"]).inc();
+            if let Err(e) = remove_file(&path) {
+                error!("failed to remove {:?}: {}", path, e);
+            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+    // moves a spooled file that is no longer retried to the dead letter dir
+    //
+    // if that fails the file is put back in the spool so the move is tried again on the next pass
+    fn move_to_dead_letter(&self, path: PathBuf, attempts: Attempts, now: i64, size: u64) {
+        let moved = create_dir_all(&self.dead_letter_dir)
+            .and_then(|_| rename(&path, self.dead_letter_dir.join(file_name(now, attempts))));
+        if let Err(e) = moved {
+            error!("failed to move {:?} to {:?}: {}", path, self.dead_letter_dir, e);
+            self.spool.lock().expect("spool lock poisoned").insert(attempts.first_failed, path, size);
+        }
+    }
+    // returns true if the body has been failing for longer than the max age
+    fn is_expired(&self, attempts: Attempts, now: i64) -> bool {
+        now - attempts.first_failed >= self.max_age.as_secs() as i64
+    }
+    // returns the delay before the next attempt of a body that has failed count times
+    //
+    // the delay doubles with every attempt up to max_delay, then a random amount of up to half of it is taken off
+    // so bodies that failed together, e.g during an outage, don't all come back at the same time
+    fn backoff(&self, count: u32) -> Duration {
+        let delay = self.base_delay
+            .checked_mul(1 << count.saturating_sub(1).min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        delay - (delay / 2).mul_f64(rand::random())
+    }
+}
+
+// reads a spooled body
+fn read_body(path: &Path) -> Result<IngestBody, Error> {
+    Ok(serde_json::from_reader(File::open(path)?)?)
+}
+
+// returns the name of the file a body is stored in
+fn file_name(retry_at: i64, attempts: Attempts) -> String {
+    format!("{}_{}_{}_{}.retry", retry_at, attempts.count, attempts.first_failed, Uuid::new_v4())
+}
+
+// parses the time a body can be retried at and it's attempts from the name of it's file
+//
+// files written by older versions are named `<retry at>_<uuid>.retry` and are treated as a first attempt
+fn parse_file_name(path: &Path) -> Result<(i64, Attempts), Error> {
+    let file_name = path.file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| Error::NonUTF8(path.to_path_buf()))?;
+    let parts: Vec<&str> = file_name.split('_').collect();
+    let invalid = || Error::InvalidFileName(file_name.to_string());
+
+    let retry_at: i64 = parts.first()
+        .and_then(|s| FromStr::from_str(s).ok())
+        .ok_or_else(invalid)?;
+    let attempts = match parts.len() {
+        2 => Attempts { count: 1, first_failed: retry_at },
+        4 => Attempts {
+            count: FromStr::from_str(parts[1]).map_err(|_| invalid())?,
+            first_failed: FromStr::from_str(parts[2]).map_err(|_| invalid())?,
+        },
+        _ => return Err(invalid()),
+    };
+
+    Ok((retry_at, attempts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::write;
+
+    use crossbeam::unbounded;
+    use tempfile::tempdir;
+
+    use crate::types::body::Line;
+
+    fn retryable(attempts: Attempts) -> Retryable {
+        Retryable {
+            body: Arc::new(IngestBody::new(vec![Line::builder().line("test").build().unwrap()])),
+            delay: None,
+            attempts,
+        }
+    }
+
+    // the outcome counters are shared by every test, so each test only checks the outcomes no other test has
+    fn outcome(outcome: &str) -> u64 {
+        metrics::RETRY_BODIES.with_label_values(&[outcome]).get()
+    }
+
+    // counts the bodies in a spool dir
+    fn spooled(dir: &Path) -> usize {
+        read_dir(dir).unwrap().filter(|f| f.as_ref().unwrap().path().is_file()).count()
+    }
+
+    #[test]
+    fn backs_off_with_jitter() {
+        let mut retry = Retry::new();
+        retry.set_base_delay(Duration::from_secs(10));
+        retry.set_max_delay(Duration::from_secs(60));
+
+        for (count, max) in &[(1, 10), (2, 20), (3, 40), (4, 60), (50, 60)] {
+            let delay = retry.backoff(*count);
+            assert!(delay >= Duration::from_secs(max / 2) && delay <= Duration::from_secs(*max), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn dead_letters_bodies() {
+        let (exhausted, expired) = (outcome("exhausted"), outcome("expired"));
+        let dir = tempdir().unwrap();
+        let mut retry = Retry::new();
+        retry.set_dir(dir.path());
+        retry.set_dead_letter_dir(dir.path().join("dead"));
+        retry.set_base_delay(Duration::from_secs(0));
+        retry.set_max_attempts(3);
+        retry.set_max_age(Duration::from_secs(60));
+        let (sender, receiver) = unbounded();
+        retry.body_sender = sender;
+
+        let now = Utc::now().timestamp();
+        let attempts = [
+            Attempts { count: 2, first_failed: now },
+            Attempts { count: 3, first_failed: now },
+            Attempts { count: 1, first_failed: now - 60 },
+        ];
+        for attempts in &attempts {
+            retry.retry_sender.send(retryable(*attempts)).unwrap();
+            retry.poll_incoming().unwrap();
+        }
+        assert_eq!(spooled(dir.path()), 1);
+        assert_eq!(read_dir(dir.path().join("dead")).unwrap().count(), 2);
+
+        retry.poll_outgoing().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().attempts, attempts[0]);
+        assert_eq!(spooled(dir.path()), 0);
+
+        assert_eq!((outcome("exhausted"), outcome("expired")), (exhausted + 1, expired + 1));
+    }
+
+    #[test]
+    fn enforces_spool_quota() {
+        let (evicted, discarded) = (outcome("evicted"), outcome("discarded"));
+        let dir = tempdir().unwrap();
+        let now = Utc::now().timestamp();
+        let spool = |eviction| {
+            let mut retry = Retry::new();
+            retry.set_dir(dir.path());
+            retry.set_max_files(2);
+            retry.set_eviction(eviction);
+            retry.load_spool().unwrap();
+            retry
+        };
+
+        let retry = spool(Eviction::DropOldest);
+        for first_failed in &[now - 3, now - 2, now - 1] {
+            retry.retry_sender.send(retryable(Attempts { count: 1, first_failed: *first_failed })).unwrap();
+            retry.poll_incoming().unwrap();
+        }
+        assert_eq!(spooled(dir.path()), 2);
+        assert_eq!(outcome("evicted"), evicted + 1);
+        let oldest = retry.spool.lock().unwrap().files.keys().next().unwrap().0;
+        assert_eq!(oldest, now - 2);
+
+        // the accounting is rebuilt from the dir, so the spool is still full after a restart
+        let retry = spool(Eviction::RejectNewest);
+        assert_eq!(retry.spool.lock().unwrap().files.len(), 2);
+        retry.retry_sender.send(retryable(Attempts::first())).unwrap();
+        retry.poll_incoming().unwrap();
+        assert_eq!(spooled(dir.path()), 2);
+        assert_eq!(outcome("discarded"), discarded + 1);
+    }
+
+    #[test]
+    fn dead_letters_unreadable_bodies() {
+        let unreadable = outcome("unreadable");
+        let dir = tempdir().unwrap();
+        let mut retry = Retry::new();
+        retry.set_dir(dir.path());
+        retry.set_dead_letter_dir(dir.path().join("dead"));
+        retry.set_base_delay(Duration::from_secs(0));
+        let (sender, receiver) = unbounded();
+        retry.body_sender = sender;
+
+        let now = Utc::now().timestamp();
+        let attempts = Attempts { count: 1, first_failed: now };
+        write(dir.path().join(file_name(now, attempts)), "{\"lines\": [").unwrap();
+        retry.retry_sender.send(retryable(attempts)).unwrap();
+        retry.poll_incoming().unwrap();
+        // not a retry at all, so it's left alone
+        write(dir.path().join("notes.txt"), "").unwrap();
+        retry.load_spool().unwrap();
+
+        // the corrupt body doesn't stop the valid one from being resent
+        retry.poll_outgoing().unwrap();
+        assert_eq!(receiver.try_iter().count(), 1);
+        assert_eq!(read_dir(dir.path().join("dead")).unwrap().count(), 1);
+        assert_eq!(spooled(dir.path()), 1);
+        assert!(retry.spool.lock().unwrap().files.is_empty());
+        assert_eq!(outcome("unreadable"), unreadable + 1);
+    }
+
+    #[test]
+    fn keeps_bodies_when_client_is_gone() {
+        let dir = tempdir().unwrap();
+        let mut retry = Retry::new();
+        retry.set_dir(dir.path());
+        retry.set_base_delay(Duration::from_secs(0));
+
+        retry.retry_sender.send(retryable(Attempts::first())).unwrap();
+        retry.poll_incoming().unwrap();
+        // the body sender of a new Retry is already disconnected
+        assert!(retry.poll_outgoing().is_err());
+        assert_eq!(spooled(dir.path()), 1);
+        assert_eq!(retry.spool.lock().unwrap().files.len(), 1);
+    }
+
+    #[test]
+    fn parses_legacy_file_names() {
+        let path = Path::new("/tmp/logdna/1500_5b5a3ab8-0d28-4a4e-8b61-6e2f0a2e1d7c.retry");
+        assert_eq!(parse_file_name(path).unwrap(), (1500, Attempts { count: 1, first_failed: 1500 }));
+        assert!(parse_file_name(Path::new("/tmp/logdna/garbage.retry")).is_err());
+    }
+}
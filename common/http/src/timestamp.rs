@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+use crate::types::body::{Line, LineBuilder};
+use crate::types::error::LineError;
+
+/// The meta key a line's timestamp is carried under until the line is built
+///
+/// LineBuilder has no timestamp of it's own, so middlewares that know when a line was logged set it here
+/// and the client moves it into the built line
+pub const META_KEY: &str = "__timestamp";
+
+/// Sets the time a line was logged at
+///
+/// Lines with meta that isn't a json object are left as is
+pub fn set(mut line: LineBuilder, timestamp: DateTime<Utc>) -> LineBuilder {
+    let timestamp = Value::from(timestamp.timestamp());
+    match line.meta {
+        Some(Value::Object(ref mut meta)) => {
+            meta.insert(META_KEY.to_string(), timestamp);
+        }
+        None => {
+            let mut meta = Map::new();
+            meta.insert(META_KEY.to_string(), timestamp);
+            line.meta = Some(Value::Object(meta));
+        }
+        Some(_) => {}
+    }
+    line
+}
+
+/// Returns the time a line was logged at, if a middleware has set it
+pub fn get(line: &LineBuilder) -> Option<i64> {
+    line.meta.as_ref()?.get(META_KEY)?.as_i64()
+}
+
+/// Builds a line, using the time it was logged at if set instead of the current time
+pub fn build(mut line: LineBuilder) -> Result<Line, LineError> {
+    let mut timestamp = None;
+    if let Some(Value::Object(ref mut meta)) = line.meta {
+        timestamp = meta.remove(META_KEY).and_then(|v| v.as_i64());
+        if meta.is_empty() {
+            line.meta = None;
+        }
+    }
+
+    let mut line = line.build()?;
+    if let Some(timestamp) = timestamp {
+        line.timestamp = timestamp;
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn carries_timestamp_to_line() {
+        let logged_at = Utc.timestamp(1_500_000_000, 0);
+
+        let line = set(LineBuilder::new().line("test"), logged_at);
+        assert_eq!(get(&line), Some(1_500_000_000));
+        let line = build(line).unwrap();
+        assert_eq!(line.timestamp, 1_500_000_000);
+        assert_eq!(line.meta, None);
+
+        let line = set(LineBuilder::new().line("test").meta(serde_json::json!({"stream": "stdout"})), logged_at);
+        let line = build(line).unwrap();
+        assert_eq!(line.timestamp, 1_500_000_000);
+        assert_eq!(line.meta, Some(serde_json::json!({"stream": "stdout"})));
+
+        // lines without a timestamp get the current time
+        assert!(build(LineBuilder::new().line("test")).unwrap().timestamp > 1_500_000_000);
+    }
+}
@@ -0,0 +1,335 @@
+use std::env;
+use std::fs::{read_to_string, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Chunk, Client as HyperClient, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use rustls::ClientConfig as TlsConfig;
+use serde::Deserialize;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
+
+use crate::Pod;
+
+/// The dir kubernetes mounts the service account token and CA into every pod at
+pub const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(e: std::io::Error) {
+            from()
+            display("{}", e)
+        }
+        Http(e: hyper::Error) {
+            from()
+            display("{}", e)
+        }
+        Request(e: hyper::http::Error) {
+            from()
+            display("{}", e)
+        }
+        Serde(e: serde_json::Error) {
+            from()
+            display("{}", e)
+        }
+        Utf(e: std::string::FromUtf8Error) {
+            from()
+            display("{}", e)
+        }
+        Status(status: StatusCode, reason: String) {
+            display("api server responded {}: {}", status, reason)
+        }
+        Timeout {
+            display("request to api server timed out")
+        }
+        Ca {
+            display("failed to parse ca certificate")
+        }
+        MissingEnvVar(name: &'static str) {
+            display("{} is not set, the agent doesn't seem to be running in a cluster", name)
+        }
+    }
+}
+
+/// A client for the parts of the Kubernetes API the agent uses
+pub struct ApiClient {
+    hyper: HyperClient<HttpsConnector<HttpConnector>>,
+    // the requests are run on a runtime owned by the client, callers block until they complete
+    runtime: Runtime,
+    // the scheme, host and port of the api server, e.g https://10.0.0.1:443
+    base_url: String,
+    token: Option<String>,
+    timeout: Duration,
+}
+
+impl ApiClient {
+    /// Creates a client for the api server at base_url, trusting the CA in ca if set
+    pub fn new<T: Into<String>>(base_url: T, token: Option<String>, ca: Option<&Path>) -> Result<Self, Error> {
+        let runtime = Runtime::new()?;
+
+        let mut http_connector = HttpConnector::new_with_executor(runtime.executor(), None);
+        http_connector.enforce_http(false);
+
+        let mut tls_config = TlsConfig::new();
+        tls_config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        if let Some(ca) = ca {
+            tls_config.root_store
+                .add_pem_file(&mut BufReader::new(File::open(ca)?))
+                .map_err(|_| Error::Ca)?;
+        }
+
+        Ok(Self {
+            hyper: HyperClient::builder().build(HttpsConnector::from((http_connector, tls_config))),
+            runtime,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            token,
+            timeout: Duration::from_secs(10),
+        })
+    }
+    /// Creates a client for the cluster the agent runs in
+    ///
+    /// The api server is found through the KUBERNETES_SERVICE_HOST and KUBERNETES_SERVICE_PORT env vars
+    /// and authenticated with the service account token and CA mounted in SERVICE_ACCOUNT_DIR
+    pub fn in_cluster() -> Result<Self, Error> {
+        let host = env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| Error::MissingEnvVar("KUBERNETES_SERVICE_HOST"))?;
+        let port = env::var("KUBERNETES_SERVICE_PORT")
+            .map_err(|_| Error::MissingEnvVar("KUBERNETES_SERVICE_PORT"))?;
+        // ipv6 addresses need brackets in a url
+        let host = match host.contains(':') {
+            true => format!("[{}]", host),
+            false => host,
+        };
+
+        let dir = Path::new(SERVICE_ACCOUNT_DIR);
+        let token = read_to_string(dir.join("token"))?.trim().to_string();
+        Self::new(format!("https://{}:{}", host, port), Some(token), Some(&dir.join("ca.crt")))
+    }
+    /// Sets the request timeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    /// Returns a pod by it's namespace and name
+    pub fn pod(&self, namespace: &str, name: &str) -> Result<Pod, Error> {
+        let body = self.get(&format!("/api/v1/namespaces/{}/pods/{}", namespace, name))?;
+        Ok(serde_json::from_str(&body)?)
+    }
+    /// Lists pods, optionally only those matching a field selector, e.g spec.nodeName=node-1
+    pub fn list_pods(&self, field_selector: Option<&str>) -> Result<PodList, Error> {
+        let mut path = "/api/v1/pods".to_string();
+        if let Some(selector) = field_selector {
+            path.push_str(&format!("?fieldSelector={}", encode(selector)));
+        }
+        Ok(serde_json::from_str(&self.get(&path)?)?)
+    }
+    /// Watches pods for changes after resource_version, optionally only those matching a field selector
+    ///
+    /// The api server ends the watch after timeout, the returned iterator ends along with it
+    pub fn watch_pods(
+        &self,
+        field_selector: Option<&str>,
+        resource_version: &str,
+        timeout: Duration,
+    ) -> Result<WatchEvents<'_>, Error> {
+        let mut path = format!(
+            "/api/v1/pods?watch=1&resourceVersion={}&timeoutSeconds={}",
+            encode(resource_version),
+            timeout.as_secs()
+        );
+        if let Some(selector) = field_selector {
+            path.push_str(&format!("&fieldSelector={}", encode(selector)));
+        }
+
+        let res = self.request(&path)?;
+        let status = res.status();
+        let body = res.into_body();
+        if !status.is_success() {
+            let body = self.wait(body.concat2(), self.timeout)?;
+            return Err(Error::Status(status, String::from_utf8(body.to_vec())?));
+        }
+        Ok(WatchEvents {
+            api: self,
+            body: Some(body),
+            buf: Vec::new(),
+            // the api server should end the watch well before this
+            idle_timeout: timeout + self.timeout,
+        })
+    }
+    // sends a GET request for path, returning the body of a successful response
+    fn get(&self, path: &str) -> Result<String, Error> {
+        let res = self.request(path)?;
+        let status = res.status();
+        let body = self.wait(res.into_body().concat2(), self.timeout)?;
+        let body = String::from_utf8(body.to_vec())?;
+        if !status.is_success() {
+            return Err(Error::Status(status, body));
+        }
+        Ok(body)
+    }
+    // sends a GET request for path, returning the response once it's headers have arrived
+    fn request(&self, path: &str) -> Result<Response<Body>, Error> {
+        let mut request = Request::get(format!("{}{}", self.base_url, path));
+        if let Some(ref token) = self.token {
+            request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Body::empty())?;
+        self.wait(self.hyper.request(request), self.timeout)
+    }
+    // runs a future on the runtime, blocking until it completes or times out
+    fn wait<F>(&self, fut: F, timeout: Duration) -> Result<F::Item, Error>
+        where F: Future<Error=hyper::Error> + Send + 'static,
+              F::Item: Send + 'static {
+        let (sender, receiver) = oneshot::channel();
+        self.runtime.executor().spawn(Timeout::new(fut, timeout).then(|r| {
+            let _ = sender.send(r);
+            Ok(())
+        }));
+        receiver.wait()
+            .expect("runtime dropped request")
+            .map_err(|e| match e.into_inner() {
+                Some(e) => Error::Http(e),
+                None => Error::Timeout,
+            })
+    }
+}
+
+/// A page of pods along with the resource version to watch from
+#[derive(Deserialize, Debug)]
+pub struct PodList {
+    pub metadata: ListMetadata,
+    pub items: Vec<Pod>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListMetadata {
+    #[serde(rename = "resourceVersion")]
+    pub resource_version: String,
+}
+
+/// An event from a watch of pods
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "object")]
+pub enum WatchEvent {
+    #[serde(rename = "ADDED")]
+    Added(Pod),
+    #[serde(rename = "MODIFIED")]
+    Modified(Pod),
+    #[serde(rename = "DELETED")]
+    Deleted(Pod),
+    /// The watch failed, e.g with 410 Gone once the resource version is too old to watch from
+    #[serde(rename = "ERROR")]
+    Error(ApiStatus),
+}
+
+/// The status the api server sends back with a failure
+#[derive(Deserialize, Debug)]
+pub struct ApiStatus {
+    pub code: u16,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// The events of a watch, read from the response body as they arrive
+pub struct WatchEvents<'a> {
+    api: &'a ApiClient,
+    // None once the body has ended
+    body: Option<Body>,
+    // the part of the body that hasn't been parsed yet, events are separated by newlines
+    buf: Vec<u8>,
+    idle_timeout: Duration,
+}
+
+impl<'a> Iterator for WatchEvents<'a> {
+    type Item = Result<WatchEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Some(serde_json::from_slice(&line).map_err(Into::into));
+            }
+
+            let body = self.body.take()?;
+            let next = body.into_future().map_err(|(e, _)| e);
+            let (chunk, body): (Option<Chunk>, Body) = match self.api.wait(next, self.idle_timeout) {
+                Ok(v) => v,
+                Err(e) => return Some(Err(e)),
+            };
+            match chunk {
+                Some(chunk) => {
+                    self.buf.extend_from_slice(&chunk);
+                    self.body = Some(body);
+                }
+                // an event without a trailing newline
+                None if !self.buf.is_empty() => {
+                    let line = std::mem::take(&mut self.buf);
+                    return Some(serde_json::from_slice(&line).map_err(Into::into));
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+// percent encodes a query parameter value
+fn encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::{mock_api, Reply};
+
+    const POD: &str = r#"{
+        "kind": "Pod",
+        "apiVersion": "v1",
+        "metadata": {
+            "name": "app-5d8f7c",
+            "namespace": "default",
+            "labels": {"app": "web"},
+            "annotations": {"team": "core"}
+        },
+        "spec": {"nodeName": "node-1"}
+    }"#;
+
+    #[test]
+    fn fetches_pods() {
+        let (url, requests) = mock_api(vec![
+            Reply::Body("200 OK", POD.to_string()),
+            Reply::Body("404 Not Found", r#"{"kind": "Status", "reason": "NotFound"}"#.to_string()),
+        ]);
+        let client = ApiClient::new(url, Some("token".to_string()), None).unwrap();
+
+        let pod = client.pod("default", "app-5d8f7c").unwrap();
+        assert_eq!(pod.metadata.name, "app-5d8f7c");
+        assert_eq!(pod.metadata.labels.get("app").map(String::as_str), Some("web"));
+        assert_eq!(requests.try_recv().unwrap(), (
+            "GET /api/v1/namespaces/default/pods/app-5d8f7c HTTP/1.1".to_string(),
+            Some("Bearer token".to_string()),
+        ));
+
+        match client.pod("default", "gone") {
+            Err(Error::Status(status, _)) => assert_eq!(status, StatusCode::NOT_FOUND),
+            other => panic!("expected a 404, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use http::timestamp;
+use http::types::body::LineBuilder;
+use middleware::{Middleware, Status};
+
+lazy_static! {
+    static ref CRI_REG: Regex = Regex::new(r"^(\S+) (stdout|stderr) ([PF])(?: (.*))?$").expect("Regex::new()");
+}
+
+/// The dirs container runtimes write logs to, lines from other files are never unwrapped
+pub const CONTAINER_LOG_DIRS: &[&str] = &["/var/log/containers/", "/var/log/pods/", "/var/lib/docker/containers/"];
+
+/// Unwraps lines written by a container runtime, leaving just the message the container logged
+///
+/// Both Docker's json-file format, `{"log":"message\n","stream":"stdout","time":"..."}`, and the CRI format
+/// used by containerd and CRI-O, `<time> <stream> <P|F> <message>`, are detected per line. The stream is added
+/// to the line's meta and the time is used as the line's timestamp.
+///
+/// Runtimes split long messages into partial lines, these are held back and reassembled into the full message.
+/// A message that's never completed, e.g because the container exited, is sent as is once it times out
+/// or it's file is removed.
+pub struct ContainerLog {
+    // partial messages waiting for the rest of their line, by file and stream
+    partials: Mutex<HashMap<(String, String), Partial>>,
+    // the most bytes held back for a single message before it's sent as is
+    max_partial_bytes: usize,
+    // how long a message is held back without receiving another part
+    partial_timeout: Duration,
+}
+
+// the start of a message that was split into partial lines
+struct Partial {
+    // the first partial line, the message is sent with it's file and other fields
+    first: LineBuilder,
+    message: String,
+    time: Option<DateTime<Utc>>,
+    last_line: Instant,
+}
+
+// a line with the runtime's wrapper removed
+#[derive(Debug, PartialEq)]
+struct Parsed {
+    message: String,
+    stream: String,
+    time: Option<DateTime<Utc>>,
+    // the message continues on the next line
+    partial: bool,
+}
+
+#[derive(Deserialize)]
+struct DockerLine {
+    log: String,
+    stream: String,
+    time: Option<String>,
+}
+
+impl Default for ContainerLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContainerLog {
+    pub fn new() -> Self {
+        Self {
+            partials: Mutex::new(HashMap::new()),
+            max_partial_bytes: 1024 * 1024,
+            partial_timeout: Duration::from_secs(1),
+        }
+    }
+    /// Sets the most bytes held back for a single message, anything longer is split
+    pub fn set_max_partial_bytes(&mut self, bytes: usize) {
+        self.max_partial_bytes = bytes;
+    }
+    /// Sets how long a message is held back without receiving another part before it's sent as is
+    pub fn set_partial_timeout(&mut self, timeout: Duration) {
+        self.partial_timeout = timeout;
+    }
+    // takes the partial messages that timed out or whose file no longer exists, or all of them
+    fn take_expired<F: Fn(&str) -> bool>(&self, now: Instant, exists: F, all: bool) -> Vec<LineBuilder> {
+        let mut partials = self.partials.lock().expect("partials lock poisoned");
+        let expired: Vec<_> = partials.iter()
+            .filter(|((file, _), p)| {
+                all || now.duration_since(p.last_line) >= self.partial_timeout || !exists(file)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired.into_iter()
+            .filter_map(|key| {
+                let partial = partials.remove(&key)?;
+                Some(unwrap(partial.first, partial.message, key.1, partial.time))
+            })
+            .collect()
+    }
+}
+
+impl Middleware for ContainerLog {
+    fn run(&self) {}
+
+    fn process(&self, line: LineBuilder) -> Status {
+        let file = match line.file {
+            Some(ref file) if CONTAINER_LOG_DIRS.iter().any(|d| file.starts_with(d)) => file.clone(),
+            _ => return Status::Ok(line),
+        };
+        let parsed = match line.line.as_deref().and_then(parse) {
+            Some(v) => v,
+            None => return Status::Ok(line),
+        };
+
+        let key = (file, parsed.stream.clone());
+        let mut partials = self.partials.lock().expect("partials lock poisoned");
+        let (first, message, time) = match partials.remove(&key) {
+            Some(mut partial) => {
+                partial.message.push_str(&parsed.message);
+                (Some(partial.first), partial.message, partial.time.or(parsed.time))
+            }
+            None => (None, parsed.message, parsed.time),
+        };
+        if parsed.partial && message.len() < self.max_partial_bytes {
+            let first = first.unwrap_or_else(|| line.clone());
+            partials.insert(key, Partial { first, message, time, last_line: Instant::now() });
+            return Status::Skip(line);
+        }
+        drop(partials);
+
+        Status::Ok(unwrap(line, message, parsed.stream, time))
+    }
+
+    fn flush(&self, all: bool) -> Vec<LineBuilder> {
+        self.take_expired(Instant::now(), |file| Path::new(file).exists(), all)
+    }
+}
+
+// replaces a line with the message it wrapped, adding the stream to it's meta
+fn unwrap(mut line: LineBuilder, message: String, stream: String, time: Option<DateTime<Utc>>) -> LineBuilder {
+    line.line = Some(message);
+    line = match line.meta.take() {
+        Some(Value::Object(mut meta)) => {
+            meta.insert("stream".to_string(), stream.into());
+            line.meta(meta)
+        }
+        None => {
+            let mut meta = Map::new();
+            meta.insert("stream".to_string(), stream.into());
+            line.meta(meta)
+        }
+        Some(meta) => line.meta(meta),
+    };
+    if let Some(time) = time {
+        line = timestamp::set(line, time);
+    }
+    line
+}
+
+// removes the wrapper from a docker or cri line, returning None if the line is in neither format
+fn parse(line: &str) -> Option<Parsed> {
+    if line.starts_with('{') {
+        let docker: DockerLine = serde_json::from_str(line).ok()?;
+        // docker ends every complete message with a newline
+        let partial = !docker.log.ends_with('\n');
+        return Some(Parsed {
+            message: docker.log.trim_end_matches(['\n', '\r']).to_string(),
+            stream: docker.stream,
+            time: docker.time.as_deref().and_then(parse_time),
+            partial,
+        });
+    }
+
+    let captures = CRI_REG.captures(line)?;
+    Some(Parsed {
+        message: captures.get(4).map(|m| m.as_str()).unwrap_or("").to_string(),
+        stream: captures[2].to_string(),
+        time: Some(parse_time(&captures[1])?),
+        partial: &captures[3] == "P",
+    })
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time).ok().map(|t| t.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fs::multiline::{Multiline, MultilineRules, Preset};
+    use fs::rule::GlobRule;
+    use middleware::multiline::MultilineMerger;
+
+    const FILE: &str = "/var/log/containers/web_default_app-abc.log";
+
+    fn process(middleware: &ContainerLog, file: &str, line: &str) -> Option<LineBuilder> {
+        match middleware.process(LineBuilder::new().file(file).line(line)) {
+            Status::Ok(line) => Some(line),
+            Status::Skip(_) => None,
+        }
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!(parse(r#"{"log":"hello\n","stream":"stderr","time":"2019-10-01T12:00:00.123456789Z"}"#), Some(Parsed {
+            message: "hello".to_string(),
+            stream: "stderr".to_string(),
+            time: parse_time("2019-10-01T12:00:00.123456789Z"),
+            partial: false,
+        }));
+        assert_eq!(parse("2019-10-01T12:00:00.123456789+02:00 stdout P part of a"), Some(Parsed {
+            message: "part of a".to_string(),
+            stream: "stdout".to_string(),
+            time: parse_time("2019-10-01T10:00:00.123456789Z"),
+            partial: true,
+        }));
+        // an empty cri message has no trailing space
+        assert_eq!(parse("2019-10-01T12:00:00Z stdout F").unwrap().message, "");
+        assert_eq!(parse("plain line"), None);
+        assert_eq!(parse(r#"{"msg": "json without a docker wrapper"}"#), None);
+        assert_eq!(parse("not-a-time stdout F message"), None);
+    }
+
+    #[test]
+    fn unwraps_lines() {
+        let middleware = ContainerLog::new();
+
+        let line = process(&middleware, FILE, r#"{"log":"hello\n","stream":"stdout","time":"2019-10-01T12:00:00Z"}"#)
+            .unwrap();
+        assert_eq!(line.line.as_deref(), Some("hello"));
+        assert_eq!(line.meta.as_ref().unwrap()["stream"], "stdout");
+        assert_eq!(timestamp::get(&line), Some(1_569_931_200));
+
+        // lines outside the container log dirs are left alone
+        let raw = "2019-10-01T12:00:00Z stdout F message";
+        assert_eq!(process(&middleware, "/var/log/app.log", raw).unwrap().line.as_deref(), Some(raw));
+    }
+
+    #[test]
+    fn reassembles_partial_lines() {
+        let middleware = ContainerLog::new();
+
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:00Z stdout P first ").is_none());
+        // partials are tracked per stream
+        let stderr = process(&middleware, FILE, "2019-10-01T12:00:01Z stderr F error").unwrap();
+        assert_eq!(stderr.line.as_deref(), Some("error"));
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:02Z stdout P second ").is_none());
+
+        let line = process(&middleware, FILE, "2019-10-01T12:00:03Z stdout F third").unwrap();
+        assert_eq!(line.line.as_deref(), Some("first second third"));
+        // the message was logged when it's first part was
+        assert_eq!(timestamp::get(&line), Some(1_569_931_200));
+
+        assert!(process(&middleware, FILE, r#"{"log":"docker ","stream":"stdout"}"#).is_none());
+        let line = process(&middleware, FILE, r#"{"log":"partial\n","stream":"stdout"}"#).unwrap();
+        assert_eq!(line.line.as_deref(), Some("docker partial"));
+    }
+
+    #[test]
+    fn limits_partial_lines() {
+        let mut middleware = ContainerLog::new();
+        middleware.set_max_partial_bytes(10);
+
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:00Z stdout P 12345").is_none());
+        let line = process(&middleware, FILE, "2019-10-01T12:00:00Z stdout P 67890").unwrap();
+        assert_eq!(line.line.as_deref(), Some("1234567890"));
+    }
+
+    #[test]
+    fn unwraps_lines_before_merging_them() {
+        let container_log = ContainerLog::new();
+        let mut rules = MultilineRules::new();
+        rules.add(GlobRule::new("/var/log/containers/*").unwrap(), Multiline::preset(Preset::Java));
+        let merger = MultilineMerger::new(rules);
+        let process = |line: &str| match process(&container_log, FILE, line) {
+            Some(line) => match merger.process(line) {
+                Status::Ok(line) => line.line,
+                Status::Skip(_) => None,
+            },
+            None => None,
+        };
+
+        let trace = [
+            r#"{"log":"java.lang.IllegalStateException: boom\n","stream":"stderr","time":"2019-10-01T12:00:00Z"}"#,
+            r#"{"log":"\tat com.example.App.handle(App.java:10)\n","stream":"stderr","time":"2019-10-01T12:00:00Z"}"#,
+            r#"{"log":"\tat com.example.App.main(App.java:5)\n","stream":"stderr","time":"2019-10-01T12:00:00Z"}"#,
+        ];
+        for line in &trace {
+            assert_eq!(process(line), None);
+        }
+        let next = r#"{"log":"next\n","stream":"stdout","time":"2019-10-01T12:00:01Z"}"#;
+        assert_eq!(process(next).as_deref(), Some(concat!(
+            "java.lang.IllegalStateException: boom\n",
+            "\tat com.example.App.handle(App.java:10)\n",
+            "\tat com.example.App.main(App.java:5)",
+        )));
+    }
+
+    #[test]
+    fn flushes_unfinished_partial_lines() {
+        let mut middleware = ContainerLog::new();
+        middleware.set_partial_timeout(Duration::from_secs(10));
+        let exists = |_: &str| true;
+
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:00Z stdout P never ").is_none());
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:01Z stdout P finished").is_none());
+        let now = Instant::now();
+        assert!(middleware.take_expired(now, exists, false).is_empty());
+
+        let lines = middleware.take_expired(now + Duration::from_secs(10), exists, false);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].file.as_deref(), Some(FILE));
+        assert_eq!(lines[0].line.as_deref(), Some("never finished"));
+        assert_eq!(lines[0].meta.as_ref().unwrap()["stream"], "stdout");
+        assert_eq!(timestamp::get(&lines[0]), Some(1_569_931_200));
+
+        // partials of removed files aren't kept around
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:02Z stderr P removed").is_none());
+        assert_eq!(middleware.take_expired(now, |_| false, false).len(), 1);
+
+        // and everything is sent at shutdown
+        assert!(process(&middleware, FILE, "2019-10-01T12:00:03Z stdout P shutdown").is_none());
+        assert_eq!(middleware.flush(true)[0].line.as_deref(), Some("shutdown"));
+        assert!(middleware.partials.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use regex::Regex;
+
+use http::types::body::KeyValueMap;
+
+/// Excludes the whole pod when set to "true", "false" ships a pod whose namespace is excluded
+pub const EXCLUDE: &str = "logdna.com/exclude";
+/// A comma separated list of containers whose lines are excluded
+pub const EXCLUDE_CONTAINERS: &str = "logdna.com/exclude-containers";
+/// A comma separated list of containers, lines from any other container are excluded
+pub const INCLUDE_CONTAINERS: &str = "logdna.com/include-containers";
+/// A regex, matching lines are excluded
+pub const EXCLUDE_LINES: &str = "logdna.com/exclude-lines";
+/// A regex, lines that don't match are excluded
+pub const INCLUDE_LINES: &str = "logdna.com/include-lines";
+
+/// Which lines of a pod are excluded, set by the pod's annotations or as a default for a namespace
+///
+/// Unset fields fall back to the namespace's default, so an annotation only overrides what it sets
+#[derive(Debug, Clone, Default)]
+pub struct Exclusion {
+    pub exclude: Option<bool>,
+    pub exclude_containers: Option<Vec<String>>,
+    pub include_containers: Option<Vec<String>>,
+    pub exclude_lines: Option<Regex>,
+    pub include_lines: Option<Regex>,
+}
+
+impl Exclusion {
+    /// Parses the exclusion annotations of a pod, invalid values are logged and ignored
+    pub fn from_annotations(pod: &str, annotations: &KeyValueMap) -> Self {
+        let regex = |key| {
+            annotations.get(key).and_then(|v: &String| match Regex::new(v) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    warn!("ignoring {} annotation of pod {}: {}", key, pod, e);
+                    None
+                }
+            })
+        };
+
+        Self {
+            exclude: annotations.get(EXCLUDE).and_then(|v| match v.parse() {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    warn!("ignoring {} annotation of pod {}: {:?} is not true or false", EXCLUDE, pod, v);
+                    None
+                }
+            }),
+            exclude_containers: annotations.get(EXCLUDE_CONTAINERS).map(|v| split(v)),
+            include_containers: annotations.get(INCLUDE_CONTAINERS).map(|v| split(v)),
+            exclude_lines: regex(EXCLUDE_LINES),
+            include_lines: regex(INCLUDE_LINES),
+        }
+    }
+    /// Fills the fields that aren't set from defaults
+    pub fn or(self, defaults: &Exclusion) -> Self {
+        Self {
+            exclude: self.exclude.or(defaults.exclude),
+            exclude_containers: self.exclude_containers.or_else(|| defaults.exclude_containers.clone()),
+            include_containers: self.include_containers.or_else(|| defaults.include_containers.clone()),
+            exclude_lines: self.exclude_lines.or_else(|| defaults.exclude_lines.clone()),
+            include_lines: self.include_lines.or_else(|| defaults.include_lines.clone()),
+        }
+    }
+    /// Returns true if a line logged by container is excluded
+    pub fn excludes(&self, container: &str, line: &str) -> bool {
+        if self.exclude == Some(true) {
+            return true;
+        }
+        if let Some(ref containers) = self.exclude_containers {
+            if containers.iter().any(|c| c == container) {
+                return true;
+            }
+        }
+        if let Some(ref containers) = self.include_containers {
+            if !containers.iter().any(|c| c == container) {
+                return true;
+            }
+        }
+        if let Some(ref regex) = self.exclude_lines {
+            if regex.is_match(line) {
+                return true;
+            }
+        }
+        if let Some(ref regex) = self.include_lines {
+            if !regex.is_match(line) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// splits a comma separated list, ignoring whitespace and empty items
+fn split(list: &str) -> Vec<String> {
+    list.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_namespace_defaults() {
+        let defaults = Exclusion {
+            exclude: Some(true),
+            exclude_lines: Some(Regex::new("^GET /healthz").unwrap()),
+            ..Default::default()
+        };
+        assert!(Exclusion::default().or(&defaults).excludes("app", "hello"));
+
+        // the pod opts back in but keeps the namespace's line exclusion
+        let annotations = KeyValueMap::new()
+            .add(EXCLUDE, "false")
+            .add(EXCLUDE_CONTAINERS, "istio-proxy, linkerd-proxy")
+            .add(INCLUDE_LINES, "[");
+        let exclusion = Exclusion::from_annotations("web", &annotations).or(&defaults);
+        assert!(!exclusion.excludes("app", "hello"));
+        assert!(exclusion.excludes("app", "GET /healthz 200"));
+        assert!(exclusion.excludes("linkerd-proxy", "hello"));
+        // the invalid regex was ignored
+        assert!(exclusion.include_lines.is_none());
+
+        let annotations = KeyValueMap::new()
+            .add(INCLUDE_CONTAINERS, "app")
+            .add(INCLUDE_LINES, "ERROR");
+        let exclusion = Exclusion::from_annotations("web", &annotations);
+        assert!(exclusion.excludes("sidecar", "ERROR"));
+        assert!(exclusion.excludes("app", "INFO"));
+        assert!(!exclusion.excludes("app", "ERROR"));
+    }
+}
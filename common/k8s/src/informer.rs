@@ -0,0 +1,192 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+use crate::api::{ApiClient, Error, WatchEvent};
+use crate::Pod;
+
+// how long to wait before trying again after the api server fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// the api server's status code for a resource version that is too old to watch from
+const GONE: u16 = 410;
+
+/// A change to a pod seen by an [Informer](struct.Informer.html)
+#[derive(Debug)]
+pub enum PodChange {
+    /// A pod was added or it's metadata changed
+    Applied(Pod),
+    /// A pod was deleted, identified by it's namespace and name
+    Deleted(String, String),
+}
+
+/// Keeps up with the pods on a node by listing them once and then following a watch
+///
+/// If the watch falls too far behind, e.g after the api server was unreachable, the pods are listed again
+/// and any pod missing from the new list is reported as deleted
+pub struct Informer {
+    api: Arc<ApiClient>,
+    // limits the pods to those scheduled on a node, e.g spec.nodeName=node-1
+    field_selector: Option<String>,
+    // the resource version to watch from, None when the pods need to be listed
+    resource_version: Option<String>,
+    // the namespace and name of every pod seen
+    known: HashSet<(String, String)>,
+    // how long the api server keeps a watch open
+    watch_timeout: Duration,
+}
+
+impl Informer {
+    /// Creates an informer for the pods on node, or every pod if node isn't set
+    pub fn new(api: Arc<ApiClient>, node: Option<String>) -> Self {
+        Self {
+            api,
+            field_selector: node.map(|n| format!("spec.nodeName={}", n)),
+            resource_version: None,
+            known: HashSet::new(),
+            watch_timeout: Duration::from_secs(300),
+        }
+    }
+    /// Sets how long the api server keeps a watch open before it is restarted
+    pub fn set_watch_timeout(&mut self, timeout: Duration) {
+        self.watch_timeout = timeout;
+    }
+    /// Follows the pods forever, calling handler with every change
+    pub fn run<F: FnMut(PodChange)>(mut self, mut handler: F) {
+        loop {
+            if let Err(e) = self.poll(&mut handler) {
+                warn!("failed following pods, retrying in {:?}: {}", RETRY_INTERVAL, e);
+                sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+    // lists the pods if needed, otherwise follows a single watch until it ends
+    fn poll<F: FnMut(PodChange)>(&mut self, handler: &mut F) -> Result<(), Error> {
+        match self.resource_version.clone() {
+            Some(resource_version) => self.watch(&resource_version, handler),
+            None => self.list(handler),
+        }
+    }
+    // lists every pod, reporting the ones that went away since they were last seen
+    fn list<F: FnMut(PodChange)>(&mut self, handler: &mut F) -> Result<(), Error> {
+        let list = self.api.list_pods(self.field_selector.as_deref())?;
+        info!("listed {} pods", list.items.len());
+
+        let mut known = HashSet::new();
+        for pod in list.items {
+            known.insert(key(&pod));
+            handler(PodChange::Applied(pod));
+        }
+        for (namespace, name) in self.known.difference(&known) {
+            handler(PodChange::Deleted(namespace.clone(), name.clone()));
+        }
+
+        self.known = known;
+        self.resource_version = Some(list.metadata.resource_version);
+        Ok(())
+    }
+    // follows a watch from resource_version until the api server ends it
+    fn watch<F: FnMut(PodChange)>(&mut self, resource_version: &str, handler: &mut F) -> Result<(), Error> {
+        let api = self.api.clone();
+        let events = api.watch_pods(self.field_selector.as_deref(), resource_version, self.watch_timeout)?;
+        for event in events {
+            let change = match event? {
+                WatchEvent::Added(pod) | WatchEvent::Modified(pod) => {
+                    self.known.insert(key(&pod));
+                    self.seen(&pod);
+                    PodChange::Applied(pod)
+                }
+                WatchEvent::Deleted(pod) => {
+                    let (namespace, name) = key(&pod);
+                    self.known.remove(&(namespace.clone(), name.clone()));
+                    self.seen(&pod);
+                    PodChange::Deleted(namespace, name)
+                }
+                WatchEvent::Error(status) if status.code == GONE => {
+                    info!("pod watch expired, listing pods again: {}", status.message);
+                    self.resource_version = None;
+                    return Ok(());
+                }
+                WatchEvent::Error(status) => {
+                    self.resource_version = None;
+                    let code = StatusCode::from_u16(status.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                    return Err(Error::Status(code, status.message));
+                }
+            };
+            handler(change);
+        }
+        Ok(())
+    }
+    // moves the resource version to watch from past a pod
+    fn seen(&mut self, pod: &Pod) {
+        if let Some(ref version) = pod.metadata.resource_version {
+            self.resource_version = Some(version.clone());
+        }
+    }
+}
+
+// returns the namespace and name of a pod
+fn key(pod: &Pod) -> (String, String) {
+    (pod.metadata.namespace.clone(), pod.metadata.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::{mock_api, Reply};
+
+    fn pod(name: &str, version: &str, app: &str) -> String {
+        format!(
+            r#"{{"metadata": {{"name": "{}", "namespace": "default", "resourceVersion": "{}", "labels": {{"app": "{}"}}}}}}"#,
+            name, version, app
+        )
+    }
+
+    #[test]
+    fn follows_pods() {
+        let list = format!(
+            r#"{{"metadata": {{"resourceVersion": "10"}}, "items": [{}, {}]}}"#,
+            pod("web", "8", "web"),
+            pod("db", "9", "db"),
+        );
+        let events = vec![
+            format!(r#"{{"type": "MODIFIED", "object": {}}}"#, pod("web", "11", "frontend")),
+            format!(r#"{{"type": "ADDED", "object": {}}}"#, pod("cache", "12", "cache")),
+            format!(r#"{{"type": "DELETED", "object": {}}}"#, pod("db", "13", "db")),
+            r#"{"type": "ERROR", "object": {"kind": "Status", "code": 410, "message": "too old resource version"}}"#
+                .to_string(),
+        ];
+        let (url, requests) = mock_api(vec![Reply::Body("200 OK", list), Reply::Stream(events)]);
+
+        let api = Arc::new(ApiClient::new(url, None, None).unwrap());
+        let mut informer = Informer::new(api, Some("node-1".to_string()));
+        let mut changes = Vec::new();
+        let mut handler = |change| changes.push(match change {
+            PodChange::Applied(pod) => format!("applied {} {}", pod.metadata.name, pod.metadata.labels["app"]),
+            PodChange::Deleted(_, name) => format!("deleted {}", name),
+        });
+
+        informer.poll(&mut handler).unwrap();
+        assert_eq!(requests.recv().unwrap().0, "GET /api/v1/pods?fieldSelector=spec.nodeName%3Dnode-1 HTTP/1.1");
+        assert_eq!(informer.resource_version.as_deref(), Some("10"));
+
+        informer.poll(&mut handler).unwrap();
+        assert_eq!(
+            requests.recv().unwrap().0,
+            "GET /api/v1/pods?watch=1&resourceVersion=10&timeoutSeconds=300&fieldSelector=spec.nodeName%3Dnode-1 HTTP/1.1"
+        );
+        // the watch expired so the next poll lists the pods again
+        assert_eq!(informer.resource_version, None);
+
+        assert_eq!(changes, vec![
+            "applied web web",
+            "applied db db",
+            "applied web frontend",
+            "applied cache cache",
+            "deleted db",
+        ]);
+    }
+}
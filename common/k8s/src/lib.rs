@@ -5,21 +5,41 @@ extern crate log;
 #[macro_use]
 extern crate quick_error;
 
+use std::collections::HashMap;
+use std::env;
 use std::ffi::OsStr;
 use std::fs::{canonicalize, read_dir};
 use std::io;
 use std::ops::Deref;
 use std::path::PathBuf;
-use std::process::Command;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use chashmap::CHashMap;
+use crossbeam::scope;
 use inotify::{EventMask, Inotify, WatchMask};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 use http::types::body::{KeyValueMap, LineBuilder};
 use middleware::{Middleware, Status};
 
+use crate::api::ApiClient;
+use crate::exclusion::Exclusion;
+use crate::informer::{Informer, PodChange};
+use crate::selector::Selector;
+
+pub mod api;
+pub mod container_log;
+pub mod exclusion;
+pub mod informer;
+pub mod selector;
+
+#[cfg(test)]
+mod mock;
+
 lazy_static! {
     static ref K8S_REG: Regex = Regex::new(
         r#"^/var/log/containers/([a-z0-9A-Z\-.]+)_([a-z0-9A-Z\-.]+)_([a-z0-9A-Z\-.]+)-([a-z0-9]{64}).log$"#
@@ -33,56 +53,122 @@ quick_error! {
             from()
             display("{}", e)
         }
-        Utf(e: std::string::FromUtf8Error) {
-            from()
-            display("{}", e)
-        }
         Regex {
             from()
             display("failed to parse path")
         }
-        Serde(e: serde_json::Error){
-            from()
-            display("{}", e)
-        }
     }
 }
 
+// the namespace and name of a pod
+type PodKey = (String, String);
+
 pub struct K8s {
+    // None if the agent isn't running in a cluster, lines are then only rewritten to their symlink
+    api: Option<Arc<ApiClient>>,
+    // the node the agent runs on, only pods on this node are followed if set
+    node: Option<String>,
+    // how long the metadata of a deleted pod is kept for lines that are still being read
+    deletion_grace: Duration,
+    // the exclusion of pods in a namespace that don't override it with annotations
+    namespace_defaults: HashMap<String, Exclusion>,
+    // only lines from these namespaces are shipped if set
+    include_namespaces: Option<Vec<String>>,
+    // lines from these namespaces are never shipped
+    exclude_namespaces: Vec<String>,
+    // the labels a pod needs for it's lines to be shipped
+    selector: Selector,
+
     real_to_symlinks: CHashMap<PathBuf, PathBuf>,
     symlinks_to_real: CHashMap<PathBuf, PathBuf>,
+    // the container each symlink belongs to, parsed from the symlink's name
+    containers: CHashMap<PathBuf, Container>,
 
-    labels: CHashMap<PathBuf, KeyValueMap>,
-    annotations: CHashMap<PathBuf, KeyValueMap>,
+    pods: CHashMap<PodKey, Arc<Pod>>,
+    // the exclusion of each pod, it's annotations over it's namespace's default
+    exclusions: CHashMap<PodKey, Exclusion>,
+    // pods that were deleted along with when, evicted once the grace period is over
+    deleted: CHashMap<PodKey, Instant>,
+}
+
+impl Default for K8s {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl K8s {
+    /// Creates an instance that follows the pods on this node through the api server of the cluster the agent runs in
+    ///
+    /// The node is read from the NODE_NAME env var, without it every pod in the cluster is followed
     pub fn new() -> Self {
+        let api = match ApiClient::in_cluster() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                error!("failed to create kubernetes api client, pods won't have labels or annotations: {}", e);
+                None
+            }
+        };
+        let mut k8s = Self::with_api(api);
+        k8s.node = env::var("NODE_NAME").ok();
+        k8s
+    }
+    /// Creates an instance that follows pods with api
+    pub fn with_api(api: Option<ApiClient>) -> Self {
         K8s {
+            api: api.map(Arc::new),
+            node: None,
+            deletion_grace: Duration::from_secs(60),
+            namespace_defaults: HashMap::new(),
+            include_namespaces: None,
+            exclude_namespaces: Vec::new(),
+            selector: Selector::default(),
             real_to_symlinks: CHashMap::new(),
             symlinks_to_real: CHashMap::new(),
-            labels: CHashMap::new(),
-            annotations: CHashMap::new(),
+            containers: CHashMap::new(),
+            pods: CHashMap::new(),
+            exclusions: CHashMap::new(),
+            deleted: CHashMap::new(),
+        }
+    }
+    /// Sets how long the metadata of a deleted pod is kept
+    pub fn set_deletion_grace(&mut self, grace: Duration) {
+        self.deletion_grace = grace;
+    }
+    /// Sets the exclusion of pods in each namespace, a pod's annotations override it's namespace's default
+    pub fn set_namespace_defaults(&mut self, defaults: HashMap<String, Exclusion>) {
+        self.namespace_defaults = defaults;
+    }
+    /// Sets the namespaces lines are shipped from, every namespace not excluded if include isn't set
+    pub fn set_namespaces(&mut self, include: Option<Vec<String>>, exclude: Vec<String>) {
+        self.include_namespaces = include;
+        self.exclude_namespaces = exclude;
+    }
+    /// Sets the label selector a pod has to match for it's lines to be shipped
+    ///
+    /// Without an api client pods are never known, so a selector skips every kubernetes line
+    pub fn set_selector(&mut self, selector: Selector) {
+        if self.api.is_none() && !selector.requirements().is_empty() {
+            error!("a label selector is set without a kubernetes api client, every kubernetes line will be skipped");
         }
+        self.selector = selector;
     }
 
     fn create_inotify(&self) -> io::Result<Inotify> {
-        for file in read_dir("/var/log/containers")? {
-            if let Ok(file) = file {
-                let symlink = file.path();
-                if symlink.is_dir() {
-                    continue;
-                }
+        for file in read_dir("/var/log/containers")?.flatten() {
+            let symlink = file.path();
+            if symlink.is_dir() {
+                continue;
+            }
 
-                if let Ok(real) = canonicalize(&symlink) {
-                    info!("rewriting {:?} to {:?}", real, symlink);
-                    self.real_to_symlinks.insert(real.clone(), symlink.clone());
-                    self.symlinks_to_real.insert(symlink.clone(), real.clone());
-                    if let Err(e) = self.update_k8s_meta(symlink) {
-                        error!("error updating k8s meta: {}", e)
-                    }
+            if let Ok(real) = canonicalize(&symlink) {
+                info!("rewriting {:?} to {:?}", real, symlink);
+                self.real_to_symlinks.insert(real.clone(), symlink.clone());
+                self.symlinks_to_real.insert(symlink.clone(), real.clone());
+                if let Err(e) = self.update_k8s_meta(symlink) {
+                    error!("error updating k8s meta: {}", e)
                 }
-            };
+            }
         }
 
         let mut inotify = Inotify::init()?;
@@ -95,24 +181,100 @@ impl K8s {
         let captures = K8S_REG.captures(str).ok_or(Error::Regex)?;
         let name = captures.get(1).ok_or(Error::Regex)?.as_str();
         let namespace = captures.get(2).ok_or(Error::Regex)?.as_str();
-
-        let out = Command::new("kubectl")
-            .args(&["get", "pods", "-o", "json", "-n", namespace, name])
-            .output()?
-            .stdout;
-        let out = String::from_utf8(out)?;
-
-        let pod: Pod = serde_json::from_str(&out)?;
-
-        self.labels.insert(symlink.clone(), pod.metadata.labels);
-        self.annotations.insert(symlink.clone(), pod.metadata.annotations);
-
+        let key = (namespace.to_string(), name.to_string());
+        // the pod itself is supplied by the informer, until then lines only get the container's fields
+        self.containers.insert(symlink.clone(), Container {
+            pod: key,
+            name: captures.get(3).ok_or(Error::Regex)?.as_str().to_string(),
+            id: captures.get(4).ok_or(Error::Regex)?.as_str().to_string(),
+        });
         Ok(())
     }
-}
+    // stores the metadata of a pod along with it's exclusion
+    fn insert_pod(&self, key: PodKey, pod: Arc<Pod>) {
+        let mut exclusion = Exclusion::from_annotations(&pod.metadata.name, &pod.metadata.annotations);
+        if let Some(defaults) = self.namespace_defaults.get(&key.0) {
+            exclusion = exclusion.or(defaults);
+        }
+        self.exclusions.insert(key.clone(), exclusion);
+        self.pods.insert(key, pod);
+    }
+    // returns true if lines from container are shipped based on it's namespace and the labels of it's pod
+    fn selects(&self, container: &Container, pod: Option<&Pod>) -> bool {
+        let namespace = &container.pod.0;
+        if self.exclude_namespaces.contains(namespace) {
+            return false;
+        }
+        if let Some(ref include) = self.include_namespaces {
+            if !include.contains(namespace) {
+                return false;
+            }
+        }
+        if self.selector.requirements().is_empty() {
+            return true;
+        }
+        // without the pod's labels there is no telling if it's selected
+        pod.is_some_and(|p| self.selector.matches(&p.metadata.labels))
+    }
+    // returns true if a line from container is excluded by it's pod or namespace
+    fn excludes(&self, container: &Container, line: &str) -> bool {
+        match self.exclusions.get(&container.pod) {
+            Some(exclusion) => exclusion.excludes(&container.name, line),
+            // the pod's annotations aren't known yet
+            None => match self.namespace_defaults.get(&container.pod.0) {
+                Some(exclusion) => exclusion.excludes(&container.name, line),
+                None => false,
+            },
+        }
+    }
+    // updates the metadata of a pod, deleted pods are only marked so they can be evicted later
+    fn apply(&self, change: PodChange) {
+        match change {
+            PodChange::Applied(pod) => {
+                let key = (pod.metadata.namespace.clone(), pod.metadata.name.clone());
+                self.deleted.remove(&key);
+                self.insert_pod(key, Arc::new(pod));
+            }
+            PodChange::Deleted(namespace, name) => {
+                self.deleted.insert((namespace, name), Instant::now());
+            }
+        }
+    }
+    // evicts the metadata of pods that were deleted more than the grace period before now
+    fn evict_deleted(&self, now: Instant) {
+        self.deleted.retain(|key, deleted_at| {
+            if now.saturating_duration_since(*deleted_at) < self.deletion_grace {
+                return true;
+            }
+            info!("evicting metadata of deleted pod {}/{}", key.0, key.1);
+            self.pods.remove(key);
+            self.exclusions.remove(key);
+            false
+        });
+    }
+    // returns the meta fields of a line from a container, the pod is None until it's metadata has been fetched
+    fn meta(&self, container: &Container, pod: Option<&Pod>) -> Map<String, Value> {
+        let mut meta = Map::new();
+        meta.insert("namespace".to_string(), container.pod.0.clone().into());
+        meta.insert("pod_name".to_string(), container.pod.1.clone().into());
+        meta.insert("container_name".to_string(), container.name.clone().into());
+        meta.insert("container_id".to_string(), container.id.clone().into());
 
-impl Middleware for K8s {
-    fn run(&self) {
+        let node = pod.and_then(|p| p.spec.node_name.clone()).or_else(|| self.node.clone());
+        if let Some(node) = node {
+            meta.insert("node".to_string(), node.into());
+        }
+        if let Some(uid) = pod.and_then(|p| p.metadata.uid.clone()) {
+            meta.insert("pod_uid".to_string(), uid.into());
+        }
+        if let Some((kind, name)) = pod.and_then(Pod::owner) {
+            meta.insert("owner_kind".to_string(), kind.into());
+            meta.insert("owner_name".to_string(), name.into());
+        }
+        meta
+    }
+    // follows the container log symlinks, rewriting lines from the real files to their symlinks
+    fn follow_symlinks(&self) {
         let mut inotify = self.create_inotify().expect("Inotify::create()");
 
         let mut buff = [0u8; 8_192];
@@ -137,13 +299,29 @@ impl Middleware for K8s {
                     if let Some((symlink, real)) = handle_event_name(event.name) {
                         self.symlinks_to_real.remove(&symlink);
                         self.real_to_symlinks.remove(&real);
-                        self.labels.remove(&symlink);
-                        self.annotations.remove(&symlink);
+                        // the pod's metadata is evicted once the pod itself is deleted
+                        self.containers.remove(&symlink);
                     }
                 }
             }
         }
     }
+}
+
+impl Middleware for K8s {
+    fn run(&self) {
+        scope(|s| {
+            if let Some(ref api) = self.api {
+                let informer = Informer::new(api.clone(), self.node.clone());
+                s.spawn(move |_| informer.run(|change| self.apply(change)));
+                s.spawn(|_| loop {
+                    sleep(self.deletion_grace);
+                    self.evict_deleted(Instant::now());
+                });
+            }
+            self.follow_symlinks();
+        }).expect("K8s::run()");
+    }
 
     fn process(&self, mut line: LineBuilder) -> Status {
         if let Some(ref file) = line.file {
@@ -153,11 +331,29 @@ impl Middleware for K8s {
                 if let Some(file) = symlink.to_str() {
                     line = line.file(file);
                 }
-                if let Some(labels) = self.labels.get(symlink.deref()) {
-                    line = line.labels(labels.clone());
-                }
-                if let Some(annotations) = self.annotations.get(symlink.deref()) {
-                    line = line.annotations(annotations.clone());
+                if let Some(container) = self.containers.get(symlink.deref()) {
+                    let pod = self.pods.get(&container.pod).map(|p| p.clone());
+                    if !self.selects(&container, pod.as_deref())
+                        || self.excludes(&container, line.line.as_deref().unwrap_or("")) {
+                        metrics::K8S_LINES_EXCLUDED.inc();
+                        return Status::Skip(line);
+                    }
+                    if let Some(ref pod) = pod {
+                        line = line.labels(pod.metadata.labels.clone());
+                        line = line.annotations(pod.metadata.annotations.clone());
+                    }
+                    if line.app.is_none() {
+                        line = line.app(container.name.clone());
+                    }
+                    let meta = self.meta(&container, pod.as_deref());
+                    line.meta = Some(match line.meta.take() {
+                        // keep anything an earlier middleware set
+                        Some(Value::Object(mut existing)) => {
+                            existing.extend(meta);
+                            Value::Object(existing)
+                        }
+                        _ => Value::Object(meta),
+                    });
                 }
             }
         }
@@ -175,15 +371,210 @@ fn handle_event_name(name: Option<&OsStr>) -> Option<(PathBuf, PathBuf)> {
     None
 }
 
+// a container, as parsed from the name of it's log symlink
+struct Container {
+    pod: PodKey,
+    name: String,
+    id: String,
+}
+
+/// The parts of a pod the agent uses
 #[derive(Deserialize, Serialize, Debug)]
-struct Pod {
-    metadata: Metadata,
+pub struct Pod {
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub spec: Spec,
 }
 
+impl Pod {
+    /// Returns the kind and name of the workload that controls the pod, e.g Deployment and it's name
+    ///
+    /// Pods of a Deployment are owned by a ReplicaSet named after the Deployment and the pod-template-hash label,
+    /// so the Deployment is found without another request
+    pub fn owner(&self) -> Option<(String, String)> {
+        let owners = &self.metadata.owner_references;
+        let owner = owners.iter().find(|o| o.controller == Some(true)).or_else(|| owners.first())?;
+
+        if owner.kind == "ReplicaSet" {
+            if let Some(hash) = self.metadata.labels.get("pod-template-hash") {
+                if let Some(deployment) = owner.name.strip_suffix(&format!("-{}", hash)) {
+                    return Some(("Deployment".to_string(), deployment.to_string()));
+                }
+            }
+        }
+        Some((owner.kind.clone(), owner.name.clone()))
+    }
+}
+
+/// The spec of a pod
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Spec {
+    #[serde(rename = "nodeName")]
+    pub node_name: Option<String>,
+}
+
+/// A reference to the object that owns a pod, e.g a ReplicaSet, DaemonSet or Job
 #[derive(Deserialize, Serialize, Debug)]
-struct Metadata {
-    name: String,
-    namespace: String,
-    labels: KeyValueMap,
-    annotations: KeyValueMap,
-}
\ No newline at end of file
+pub struct OwnerReference {
+    pub kind: String,
+    pub name: String,
+    pub controller: Option<bool>,
+}
+
+/// The metadata of a pod, labels and annotations are empty if the pod has none
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Metadata {
+    pub name: String,
+    pub namespace: String,
+    #[serde(rename = "resourceVersion", default)]
+    pub resource_version: Option<String>,
+    #[serde(default)]
+    pub uid: Option<String>,
+    #[serde(rename = "ownerReferences", default)]
+    pub owner_references: Vec<OwnerReference>,
+    #[serde(default = "KeyValueMap::new")]
+    pub labels: KeyValueMap,
+    #[serde(default = "KeyValueMap::new")]
+    pub annotations: KeyValueMap,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REAL: &str = "/var/lib/docker/containers/abc/abc-json.log";
+
+    fn k8s() -> K8s {
+        let k8s = K8s::with_api(None);
+        let symlink = PathBuf::from("/var/log/containers/web-5d8f7c-x2x9z_default_app-abc.log");
+        k8s.real_to_symlinks.insert(PathBuf::from(REAL), symlink.clone());
+        k8s.containers.insert(symlink, Container {
+            pod: ("default".to_string(), "web-5d8f7c-x2x9z".to_string()),
+            name: "app".to_string(),
+            id: "abc".to_string(),
+        });
+        k8s
+    }
+
+    fn pod(labels: &str) -> Pod {
+        serde_json::from_str(&format!(r#"{{
+            "metadata": {{
+                "name": "web-5d8f7c-x2x9z",
+                "namespace": "default",
+                "uid": "0c1e6a2e",
+                "labels": {},
+                "ownerReferences": [{{"kind": "ReplicaSet", "name": "web-5d8f7c", "controller": true}}]
+            }},
+            "spec": {{"nodeName": "node-1"}}
+        }}"#, labels)).unwrap()
+    }
+
+    fn process(k8s: &K8s) -> LineBuilder {
+        match k8s.process(LineBuilder::new().file(REAL)) {
+            Status::Ok(line) => line,
+            Status::Skip(_) => panic!("line was skipped"),
+        }
+    }
+
+    #[test]
+    fn enriches_lines() {
+        let k8s = k8s();
+        // the container's fields are known from the symlink before the pod is
+        let line = process(&k8s);
+        assert_eq!(line.app.as_deref(), Some("app"));
+        assert_eq!(line.meta.unwrap(), serde_json::json!({
+            "namespace": "default",
+            "pod_name": "web-5d8f7c-x2x9z",
+            "container_name": "app",
+            "container_id": "abc",
+        }));
+
+        k8s.apply(PodChange::Applied(pod(r#"{"app": "web", "pod-template-hash": "5d8f7c"}"#)));
+        // meta set by an earlier middleware is kept
+        let line = LineBuilder::new().file(REAL).meta(serde_json::json!({"stream": "stdout"}));
+        let line = match k8s.process(line) {
+            Status::Ok(line) => line,
+            Status::Skip(_) => panic!("line was skipped"),
+        };
+        assert_eq!(line.meta.unwrap(), serde_json::json!({
+            "stream": "stdout",
+            "namespace": "default",
+            "pod_name": "web-5d8f7c-x2x9z",
+            "container_name": "app",
+            "container_id": "abc",
+            "node": "node-1",
+            "pod_uid": "0c1e6a2e",
+            "owner_kind": "Deployment",
+            "owner_name": "web",
+        }));
+    }
+
+    #[test]
+    fn keeps_pod_metadata_current() {
+        let mut k8s = k8s();
+        k8s.set_deletion_grace(Duration::from_secs(30));
+
+        k8s.apply(PodChange::Applied(pod(r#"{"app": "web"}"#)));
+        assert_eq!(process(&k8s).labels, Some(KeyValueMap::new().add("app", "web")));
+
+        // relabelled pods are picked up without the container restarting
+        k8s.apply(PodChange::Applied(pod(r#"{"app": "frontend"}"#)));
+        assert_eq!(process(&k8s).labels, Some(KeyValueMap::new().add("app", "frontend")));
+
+        // deleted pods keep their metadata until the grace period is over
+        k8s.apply(PodChange::Deleted("default".to_string(), "web-5d8f7c-x2x9z".to_string()));
+        k8s.evict_deleted(Instant::now());
+        assert!(process(&k8s).labels.is_some());
+        k8s.evict_deleted(Instant::now() + Duration::from_secs(31));
+        assert_eq!(process(&k8s).labels, None);
+    }
+
+    #[test]
+    fn excludes_lines() {
+        let mut k8s = k8s();
+        let mut defaults = HashMap::new();
+        defaults.insert("default".to_string(), Exclusion { exclude: Some(true), ..Default::default() });
+        k8s.set_namespace_defaults(defaults);
+        let skipped = |k8s: &K8s, line: &str| match k8s.process(LineBuilder::new().file(REAL).line(line)) {
+            Status::Ok(_) => false,
+            Status::Skip(_) => true,
+        };
+
+        // the namespace's default applies until the pod is known
+        assert!(skipped(&k8s, "hello"));
+
+        let mut pod = pod(r#"{"app": "web"}"#);
+        pod.metadata.annotations = KeyValueMap::new()
+            .add(exclusion::EXCLUDE, "false")
+            .add(exclusion::EXCLUDE_LINES, "^DEBUG");
+        k8s.apply(PodChange::Applied(pod));
+        assert!(!skipped(&k8s, "hello"));
+        assert!(skipped(&k8s, "DEBUG hello"));
+    }
+
+    #[test]
+    fn selects_lines() {
+        let mut k8s = k8s();
+        k8s.set_selector("app in (web,api),tier!=debug".parse().unwrap());
+        let selected = |k8s: &K8s| match k8s.process(LineBuilder::new().file(REAL).line("hello")) {
+            Status::Ok(_) => true,
+            Status::Skip(_) => false,
+        };
+
+        // the pod's labels aren't known yet
+        assert!(!selected(&k8s));
+        k8s.apply(PodChange::Applied(pod(r#"{"app": "web"}"#)));
+        assert!(selected(&k8s));
+        k8s.apply(PodChange::Applied(pod(r#"{"app": "web", "tier": "debug"}"#)));
+        assert!(!selected(&k8s));
+        k8s.apply(PodChange::Applied(pod(r#"{"app": "api"}"#)));
+        assert!(selected(&k8s));
+
+        k8s.set_namespaces(None, vec!["default".to_string()]);
+        assert!(!selected(&k8s));
+        k8s.set_namespaces(Some(vec!["kube-system".to_string()]), Vec::new());
+        assert!(!selected(&k8s));
+        k8s.set_namespaces(Some(vec!["default".to_string()]), Vec::new());
+        assert!(selected(&k8s));
+    }
+}
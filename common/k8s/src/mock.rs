@@ -0,0 +1,52 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread::spawn;
+
+use crossbeam::{unbounded, Receiver};
+
+// a canned response of the mock api server
+pub enum Reply {
+    // a status line and body, e.g "404 Not Found"
+    Body(&'static str, String),
+    // a 200 whose body is streamed one line at a time, like a watch
+    Stream(Vec<String>),
+}
+
+// serves a reply to each request in turn, sending every request line and authorization header back through the channel
+pub fn mock_api(replies: Vec<Reply>) -> (String, Receiver<(String, Option<String>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (sender, receiver) = unbounded();
+    spawn(move || {
+        for reply in replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let auth = request.lines()
+                .find_map(|l| l.strip_prefix("authorization: "))
+                .map(str::to_string);
+            sender.send((request.lines().next().unwrap().to_string(), auth)).unwrap();
+
+            match reply {
+                Reply::Body(status, body) => write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                ).unwrap(),
+                Reply::Stream(lines) => {
+                    write!(stream, "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").unwrap();
+                    for line in lines {
+                        writeln!(stream, "{}", line).unwrap();
+                        stream.flush().unwrap();
+                    }
+                }
+            }
+        }
+    });
+    (format!("http://{}", addr), receiver)
+}
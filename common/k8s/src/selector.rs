@@ -0,0 +1,247 @@
+use std::str::FromStr;
+
+use regex::Regex;
+
+use http::types::body::KeyValueMap;
+
+lazy_static! {
+    static ref SET_REG: Regex = Regex::new(r"^(\S+)\s+(in|notin)\s*\((.*)\)$").expect("Regex::new()");
+    static ref KEY_REG: Regex = Regex::new(r"^[A-Za-z0-9._/-]+$").expect("Regex::new()");
+    static ref VALUE_REG: Regex = Regex::new(r"^[A-Za-z0-9._-]*$").expect("Regex::new()");
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Syntax(requirement: String, reason: &'static str) {
+            display("invalid label selector requirement {:?}: {}", requirement, reason)
+        }
+    }
+}
+
+/// A single requirement of a label selector
+#[derive(Debug, Clone, PartialEq)]
+pub enum Requirement {
+    /// `key=value` or `key==value`
+    Equals(String, String),
+    /// `key!=value`, also met by pods without the label
+    NotEquals(String, String),
+    /// `key in (a,b)`
+    In(String, Vec<String>),
+    /// `key notin (a,b)`, also met by pods without the label
+    NotIn(String, Vec<String>),
+    /// `key`
+    Exists(String),
+    /// `!key`
+    DoesNotExist(String),
+}
+
+impl Requirement {
+    /// Returns true if labels meet the requirement
+    pub fn matches(&self, labels: &KeyValueMap) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+            Requirement::NotIn(key, values) => labels.get(key).is_none_or(|v| !values.contains(v)),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::DoesNotExist(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// A Kubernetes label selector, e.g `app in (web,api),tier!=debug`
+///
+/// Every requirement has to be met for a selector to match, an empty selector matches everything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+impl Selector {
+    /// Returns the requirements of the selector
+    pub fn requirements(&self) -> &[Requirement] {
+        &self.requirements
+    }
+    /// Returns true if labels meet every requirement
+    pub fn matches(&self, labels: &KeyValueMap) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+}
+
+impl FromStr for Selector {
+    type Err = Error;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let mut requirements = Vec::new();
+        for requirement in split(selector)? {
+            let requirement = requirement.trim();
+            if requirement.is_empty() {
+                if selector.trim().is_empty() {
+                    continue;
+                }
+                return Err(Error::Syntax(selector.to_string(), "empty requirement"));
+            }
+            requirements.push(parse(requirement)?);
+        }
+        Ok(Self { requirements })
+    }
+}
+
+// splits a selector on the commas between requirements, leaving the commas of sets alone
+fn split(selector: &str) -> Result<Vec<&str>, Error> {
+    let mut requirements = Vec::new();
+    let mut start = 0;
+    let mut in_set = false;
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' if in_set => return Err(Error::Syntax(selector.to_string(), "nested parentheses")),
+            '(' => in_set = true,
+            ')' if !in_set => return Err(Error::Syntax(selector.to_string(), "unopened parenthesis")),
+            ')' => in_set = false,
+            ',' if !in_set => {
+                requirements.push(&selector[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if in_set {
+        return Err(Error::Syntax(selector.to_string(), "unclosed parenthesis"));
+    }
+    requirements.push(&selector[start..]);
+    Ok(requirements)
+}
+
+// parses a single requirement
+fn parse(requirement: &str) -> Result<Requirement, Error> {
+    let syntax = |reason| Error::Syntax(requirement.to_string(), reason);
+
+    if let Some(captures) = SET_REG.captures(requirement) {
+        let key = key(&captures[1]).ok_or_else(|| syntax("invalid key"))?;
+        let mut values = Vec::new();
+        for value in captures[3].split(',') {
+            values.push(self::value(value.trim()).ok_or_else(|| syntax("invalid value"))?);
+        }
+        return Ok(match &captures[2] {
+            "in" => Requirement::In(key, values),
+            _ => Requirement::NotIn(key, values),
+        });
+    }
+
+    if let Some(key) = requirement.strip_prefix('!') {
+        return Ok(Requirement::DoesNotExist(self::key(key.trim()).ok_or_else(|| syntax("invalid key"))?));
+    }
+
+    for (operator, not) in &[("!=", true), ("==", false), ("=", false)] {
+        if let Some(i) = requirement.find(operator) {
+            let key = key(requirement[..i].trim()).ok_or_else(|| syntax("invalid key"))?;
+            let value = value(requirement[i + operator.len()..].trim()).ok_or_else(|| syntax("invalid value"))?;
+            return Ok(match not {
+                true => Requirement::NotEquals(key, value),
+                false => Requirement::Equals(key, value),
+            });
+        }
+    }
+
+    Ok(Requirement::Exists(key(requirement).ok_or_else(|| syntax("invalid key"))?))
+}
+
+fn key(key: &str) -> Option<String> {
+    if KEY_REG.is_match(key) {
+        Some(key.to_string())
+    } else {
+        None
+    }
+}
+
+fn value(value: &str) -> Option<String> {
+    if VALUE_REG.is_match(value) {
+        Some(value.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements(selector: &str) -> Vec<Requirement> {
+        selector.parse::<Selector>().unwrap().requirements().to_vec()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_requirements() {
+        assert_eq!(requirements("app=web"), vec![Requirement::Equals("app".into(), "web".into())]);
+        assert_eq!(requirements("app == web"), vec![Requirement::Equals("app".into(), "web".into())]);
+        assert_eq!(requirements("tier!=debug"), vec![Requirement::NotEquals("tier".into(), "debug".into())]);
+        assert_eq!(requirements("app in (a, b)"), vec![Requirement::In("app".into(), strings(&["a", "b"]))]);
+        assert_eq!(requirements("app notin (a)"), vec![Requirement::NotIn("app".into(), strings(&["a"]))]);
+        assert_eq!(requirements("canary"), vec![Requirement::Exists("canary".into())]);
+        assert_eq!(requirements("! canary"), vec![Requirement::DoesNotExist("canary".into())]);
+        // keys can have a prefix and values can be empty
+        assert_eq!(
+            requirements("app.kubernetes.io/name="),
+            vec![Requirement::Equals("app.kubernetes.io/name".into(), "".into())]
+        );
+    }
+
+    #[test]
+    fn parses_selectors() {
+        assert_eq!(requirements("app in (a,b),tier!=debug, canary"), vec![
+            Requirement::In("app".into(), strings(&["a", "b"])),
+            Requirement::NotEquals("tier".into(), "debug".into()),
+            Requirement::Exists("canary".into()),
+        ]);
+        assert_eq!(requirements(""), vec![]);
+        assert_eq!(requirements("  "), vec![]);
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        for selector in &[
+            "app=web,",
+            ",app=web",
+            "app in (a,b",
+            "app in a,b)",
+            "app in ((a))",
+            "app=we b",
+            "ap p=web",
+            "app in (a b)",
+            "=web",
+            "!",
+            "app=(web)",
+        ] {
+            assert!(selector.parse::<Selector>().is_err(), "{} was parsed", selector);
+        }
+    }
+
+    #[test]
+    fn matches_labels() {
+        let labels = KeyValueMap::new().add("app", "web").add("tier", "frontend");
+
+        let matches = |selector: &str| selector.parse::<Selector>().unwrap().matches(&labels);
+        assert!(matches(""));
+        assert!(matches("app=web"));
+        assert!(!matches("app=api"));
+        assert!(matches("tier!=debug"));
+        assert!(matches("missing!=debug"));
+        assert!(matches("app in (web,api)"));
+        assert!(!matches("app in (api)"));
+        assert!(!matches("missing in (web)"));
+        assert!(matches("app notin (api)"));
+        assert!(matches("missing notin (api)"));
+        assert!(!matches("app notin (web)"));
+        assert!(matches("app"));
+        assert!(!matches("missing"));
+        assert!(matches("!missing"));
+        assert!(!matches("!app"));
+        assert!(matches("app in (web),tier!=debug"));
+        assert!(!matches("app in (web),tier=debug"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often components heartbeat while they have nothing else to do
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// The health of the agent, served on `/healthz` and `/readyz`
+    pub static ref HEALTH: Health = Health::new();
+}
+
+/// Tracks whether the long running components are alive and whether the agent is shipping lines
+///
+/// A component is alive while it keeps heartbeating within the stall timeout. The agent is ready once every
+/// component is alive, the config loaded and either the last ingest request succeeded or one did within the
+/// ingest window.
+#[derive(Debug)]
+pub struct Health {
+    start: Instant,
+    // the time of the last heartbeat of each component, in millis since start
+    components: Mutex<HashMap<String, Arc<AtomicU64>>>,
+    config_loaded: AtomicBool,
+    // the time of the last successful ingest request, in millis since start
+    last_ingest: AtomicU64,
+    // set while ingest requests fail, cleared by the next one that succeeds
+    ingest_failing: AtomicBool,
+    stall_timeout: AtomicU64,
+    ingest_window: AtomicU64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            components: Mutex::new(HashMap::new()),
+            config_loaded: AtomicBool::new(false),
+            last_ingest: AtomicU64::new(0),
+            ingest_failing: AtomicBool::new(false),
+            stall_timeout: AtomicU64::new(30_000),
+            ingest_window: AtomicU64::new(5 * 60_000),
+        }
+    }
+    /// Registers a component, replacing any earlier one with the same name
+    pub fn register(&self, name: &str) -> Heartbeat {
+        let last = Arc::new(AtomicU64::new(self.now()));
+        self.components.lock().expect("components lock poisoned").insert(name.to_string(), last.clone());
+        Heartbeat { start: self.start, last }
+    }
+    /// Sets how long a component can go without heartbeating before it's considered stalled
+    pub fn set_stall_timeout(&self, timeout: Duration) {
+        self.stall_timeout.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+    /// Sets how long ingest requests can fail for before the agent is no longer ready
+    pub fn set_ingest_window(&self, window: Duration) {
+        self.ingest_window.store(window.as_millis() as u64, Ordering::Relaxed);
+    }
+    /// Records whether the last attempt to load the config succeeded
+    pub fn set_config_loaded(&self, loaded: bool) {
+        self.config_loaded.store(loaded, Ordering::Relaxed);
+    }
+    /// Records an ingest request that succeeded
+    pub fn ingest_succeeded(&self) {
+        self.last_ingest.store(self.now(), Ordering::Relaxed);
+        self.ingest_failing.store(false, Ordering::Relaxed);
+    }
+    /// Records an ingest request that failed
+    pub fn ingest_failed(&self) {
+        self.ingest_failing.store(true, Ordering::Relaxed);
+    }
+    /// Returns why the agent isn't alive, if it isn't
+    pub fn liveness(&self) -> Result<(), String> {
+        self.liveness_at(self.now())
+    }
+    /// Returns why the agent isn't ready, if it isn't
+    pub fn readiness(&self) -> Result<(), String> {
+        self.readiness_at(self.now())
+    }
+
+    fn liveness_at(&self, now: u64) -> Result<(), String> {
+        let timeout = self.stall_timeout.load(Ordering::Relaxed);
+        let mut stalled: Vec<String> = self.components.lock().expect("components lock poisoned")
+            .iter()
+            .map(|(name, last)| (name, now.saturating_sub(last.load(Ordering::Relaxed))))
+            .filter(|(_, since)| *since > timeout)
+            .map(|(name, since)| format!("{} hasn't heartbeat in {}ms", name, since))
+            .collect();
+        if stalled.is_empty() {
+            return Ok(());
+        }
+        stalled.sort();
+        Err(stalled.join("\n"))
+    }
+
+    fn readiness_at(&self, now: u64) -> Result<(), String> {
+        self.liveness_at(now)?;
+        if !self.config_loaded.load(Ordering::Relaxed) {
+            return Err("config failed to load".to_string());
+        }
+        let since = now.saturating_sub(self.last_ingest.load(Ordering::Relaxed));
+        if self.ingest_failing.load(Ordering::Relaxed) && since > self.ingest_window.load(Ordering::Relaxed) {
+            return Err(format!("no successful ingest request in {}ms", since));
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+/// Reports that a component is still running, see [Health::register](struct.Health.html#method.register)
+#[derive(Debug)]
+pub struct Heartbeat {
+    start: Instant,
+    last: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_stalled_components() {
+        let health = Health::new();
+        health.set_stall_timeout(Duration::from_secs(10));
+        let watcher = health.register("watcher");
+        health.register("tailer");
+        assert!(health.liveness_at(5_000).is_ok());
+
+        watcher.last.store(15_000, Ordering::Relaxed);
+        assert_eq!(health.liveness_at(20_000), Err("tailer hasn't heartbeat in 20000ms".to_string()));
+        assert!(health.readiness_at(20_000).is_err());
+    }
+
+    #[test]
+    fn tracks_readiness() {
+        let health = Health::new();
+        health.set_ingest_window(Duration::from_secs(60));
+        assert_eq!(health.readiness_at(0), Err("config failed to load".to_string()));
+        health.set_config_loaded(true);
+        assert!(health.readiness_at(0).is_ok());
+
+        // failures only matter once they outlast the window
+        health.ingest_failed();
+        assert!(health.readiness_at(30_000).is_ok());
+        assert!(health.readiness_at(90_000).is_err());
+        health.ingest_succeeded();
+        assert!(health.readiness().is_ok());
+        // an idle agent stays ready
+        assert!(health.readiness_at(u64::MAX).is_ok());
+    }
+}
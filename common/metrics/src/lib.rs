@@ -0,0 +1,142 @@
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate prometheus;
+
+use prometheus::{Encoder, IntCounterVec, IntGaugeVec, TextEncoder};
+
+pub use prometheus::{Histogram, IntCounter, IntGauge};
+
+pub mod health;
+pub mod server;
+
+// every metric is registered with the default registry, which is what the server exposes
+lazy_static! {
+    /// Lines read from each file
+    pub static ref FS_LINES: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_fs_lines_total", "Lines read from each file", &["file"]
+    ).unwrap();
+    /// Bytes read from each file
+    pub static ref FS_BYTES: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_fs_bytes_total", "Bytes read from each file", &["file"]
+    ).unwrap();
+    /// Files being tailed
+    pub static ref FS_FILES: IntGauge = register_int_gauge!(
+        "logdna_agent_fs_files", "Files being tailed"
+    ).unwrap();
+    /// Times a watcher backend lost changes and the watched dirs were rescanned
+    pub static ref FS_WATCH_OVERFLOWS: IntCounter = register_int_counter!(
+        "logdna_agent_fs_watch_overflows_total", "Times a watcher backend lost changes and the watched dirs were rescanned"
+    ).unwrap();
+    /// Items waiting in the input channel of each stage, see [channel](fn.channel.html)
+    pub static ref CHANNEL_QUEUED: IntGaugeVec = register_int_gauge_vec!(
+        "logdna_agent_channel_queued", "Items waiting in the input channel of each stage", &["stage"]
+    ).unwrap();
+    /// The capacity of the input channel of each stage
+    pub static ref CHANNEL_CAPACITY: IntGaugeVec = register_int_gauge_vec!(
+        "logdna_agent_channel_capacity", "The capacity of the input channel of each stage", &["stage"]
+    ).unwrap();
+    /// Lines that went through the middlewares, by whether they were sent on or skipped
+    pub static ref MIDDLEWARE_LINES: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_middleware_lines_total", "Lines that went through the middlewares", &["result"]
+    ).unwrap();
+    /// Lines dropped by each line content rule
+    pub static ref LINE_FILTER_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_line_filter_dropped_total", "Lines dropped by each line content rule", &["rule"]
+    ).unwrap();
+    /// Values redacted by each redaction rule
+    pub static ref REDACTIONS: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_redactions_total", "Values redacted by each redaction rule", &["rule"]
+    ).unwrap();
+    /// Kubernetes lines skipped because of exclusion annotations, namespace lists or the label selector
+    pub static ref K8S_LINES_EXCLUDED: IntCounter = register_int_counter!(
+        "logdna_agent_k8s_lines_excluded_total", "Kubernetes lines skipped by exclusions and selectors"
+    ).unwrap();
+    /// Lines buffered to be sent, whether or not they are delivered, see HTTP_REQUESTS for what was delivered
+    pub static ref HTTP_BUFFERED_LINES: IntCounter = register_int_counter!(
+        "logdna_agent_http_buffered_lines_total", "Lines buffered to be sent"
+    ).unwrap();
+    /// Bytes of line content buffered to be sent
+    pub static ref HTTP_BUFFERED_BYTES: IntCounter = register_int_counter!(
+        "logdna_agent_http_buffered_bytes_total", "Bytes of line content buffered to be sent"
+    ).unwrap();
+    /// Ingest requests by result, the response status or ok, timeout or error if there was no response
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_http_requests_total", "Ingest requests by result", &["result"]
+    ).unwrap();
+    /// Bodies dropped because of a response that isn't retried, by response status
+    pub static ref HTTP_BODIES_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_http_bodies_rejected_total", "Bodies dropped because of a response that isn't retried", &["status"]
+    ).unwrap();
+    /// How long ingest requests took, including ones that failed
+    pub static ref HTTP_REQUEST_DURATION: Histogram = register_histogram!(
+        "logdna_agent_http_request_duration_seconds", "How long ingest requests took"
+    ).unwrap();
+    /// Bodies that went through the retry queue by outcome, queued, resent, expired, exhausted, unreadable, evicted or discarded
+    pub static ref RETRY_BODIES: IntCounterVec = register_int_counter_vec!(
+        "logdna_agent_retry_bodies_total", "Bodies that went through the retry queue by outcome", &["outcome"]
+    ).unwrap();
+    /// Bodies in the retry spool
+    pub static ref RETRY_SPOOL_BODIES: IntGauge = register_int_gauge!(
+        "logdna_agent_retry_spool_bodies", "Bodies in the retry spool"
+    ).unwrap();
+    /// Bytes in the retry spool
+    pub static ref RETRY_SPOOL_BYTES: IntGauge = register_int_gauge!(
+        "logdna_agent_retry_spool_bytes", "Bytes in the retry spool"
+    ).unwrap();
+}
+
+/// Registers every metric, so the ones that haven't been touched yet are still exposed
+pub fn register() {
+    lazy_static::initialize(&FS_LINES);
+    lazy_static::initialize(&FS_BYTES);
+    lazy_static::initialize(&FS_FILES);
+    lazy_static::initialize(&FS_WATCH_OVERFLOWS);
+    lazy_static::initialize(&CHANNEL_QUEUED);
+    lazy_static::initialize(&CHANNEL_CAPACITY);
+    lazy_static::initialize(&MIDDLEWARE_LINES);
+    lazy_static::initialize(&LINE_FILTER_DROPPED);
+    lazy_static::initialize(&REDACTIONS);
+    lazy_static::initialize(&K8S_LINES_EXCLUDED);
+    lazy_static::initialize(&HTTP_BUFFERED_LINES);
+    lazy_static::initialize(&HTTP_BUFFERED_BYTES);
+    lazy_static::initialize(&HTTP_REQUESTS);
+    lazy_static::initialize(&HTTP_BODIES_REJECTED);
+    lazy_static::initialize(&HTTP_REQUEST_DURATION);
+    lazy_static::initialize(&RETRY_BODIES);
+    lazy_static::initialize(&RETRY_SPOOL_BODIES);
+    lazy_static::initialize(&RETRY_SPOOL_BYTES);
+}
+
+/// Records the capacity of a stage's input channel, returning the gauge to keep it's queue length in
+pub fn channel(stage: &str, capacity: usize) -> IntGauge {
+    CHANNEL_CAPACITY.with_label_values(&[stage]).set(capacity as i64);
+    CHANNEL_QUEUED.with_label_values(&[stage])
+}
+
+/// Renders every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_metrics() {
+        FS_LINES.with_label_values(&["/var/log/render.log"]).inc_by(3);
+        channel("render", 256).set(12);
+
+        let text = render();
+        assert!(text.contains("logdna_agent_fs_lines_total{file=\"/var/log/render.log\"} 3"));
+        assert!(text.contains("logdna_agent_channel_capacity{stage=\"render\"} 256"));
+        assert!(text.contains("logdna_agent_channel_queued{stage=\"render\"} 12"));
+    }
+}
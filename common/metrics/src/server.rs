@@ -0,0 +1,105 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+use futures::{future, Future, IntoFuture};
+use hyper::service::service_fn_ok;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prometheus::{Encoder, TextEncoder};
+use tokio::runtime::Runtime;
+
+use crate::health::HEALTH;
+
+/// Serves the metrics in the Prometheus text format on `GET /metrics` and the health checks on `GET /healthz`
+/// and `GET /readyz`, which answer 503 with the reason when they fail
+pub struct Server {
+    listener: TcpListener,
+}
+
+impl Server {
+    /// Binds to addr, so a port that is already in use is reported before the agent starts
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        crate::register();
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+    /// Returns the address the server is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    /// Serves requests until the process exits, this should be run on it's own thread
+    pub fn run(self) {
+        let mut runtime = Runtime::new().expect("Runtime::new()");
+        let listener = self.listener;
+        let result = runtime.block_on(future::lazy(move || {
+            // the listener has to be registered with the reactor of the runtime that polls it
+            hyper::Server::from_tcp(listener)
+                .map(|b| b.serve(|| service_fn_ok(handle)))
+                .into_future()
+                .flatten()
+        }));
+        if let Err(e) = result {
+            error!("metrics server stopped: {}", e);
+        }
+    }
+}
+
+fn handle(req: Request<Body>) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Body::from(crate::render()))
+            .expect("Response::builder()"),
+        (&Method::GET, "/healthz") => check(HEALTH.liveness()),
+        (&Method::GET, "/readyz") => check(HEALTH.readiness()),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("Response::builder()"),
+    }
+}
+
+// answers a health check with why it failed, if it did
+fn check(result: Result<(), String>) -> Response<Body> {
+    let (status, body) = match result {
+        Ok(()) => (StatusCode::OK, "ok".to_string()),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::from(body + "\n"))
+        .expect("Response::builder()")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread::spawn;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics() {
+        crate::HTTP_REQUESTS.with_label_values(&["serve"]).inc();
+        let server = Server::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        spawn(move || server.run());
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("logdna_agent_http_requests_total{result=\"serve\"} 1"));
+        assert!(get(addr, "/healthz").starts_with("HTTP/1.1 200 OK"));
+        // the config was never loaded
+        assert!(get(addr, "/readyz").starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(get(addr, "/other").starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,32 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use fs::rule::GlobRule;
+use middleware::timestamp::{TimestampParser, TimestampRule};
+
+const LINES: &[(&str, &str)] = &[
+    ("rfc3339", "2019-10-17T12:00:00.123456Z INFO starting server on :8080"),
+    ("syslog", "<30>Oct 17 12:00:00 host systemd[1]: Started Session 42 of user root."),
+    ("clf", r#"10.0.0.1 - - [17/Oct/2019:12:00:00 +0000] "GET /index.html HTTP/1.1" 200 612"#),
+    ("epoch", "1571313600.123 starting server on :8080"),
+    ("no timestamp", "starting server on :8080 with 4 workers and a 30s timeout"),
+];
+
+fn bench_timestamp(c: &mut Criterion) {
+    let parser = TimestampParser::new();
+    for (name, line) in LINES {
+        c.bench_function(&format!("parse {}", name), |b| b.iter(|| parser.parse(None, black_box(line))));
+    }
+
+    let mut parser = TimestampParser::new();
+    parser.add_rule(TimestampRule::new(
+        GlobRule::new("/var/log/app/*").unwrap(),
+        vec!["%d/%m/%Y %H:%M:%S%.3f".parse().unwrap()],
+    ));
+    let line = "17/10/2019 12:00:00.123 starting server on :8080";
+    c.bench_function("parse strftime", |b| {
+        b.iter(|| parser.parse(Some("/var/log/app/app.log"), black_box(line)))
+    });
+}
+
+criterion_group!(benches, bench_timestamp);
+criterion_main!(benches);
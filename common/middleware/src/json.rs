@@ -0,0 +1,217 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{Deserializer, Map, Value};
+
+use fs::rule::Rule;
+use http::timestamp;
+use http::types::body::LineBuilder;
+
+use crate::{Middleware, Status};
+
+// the keys each field is looked up under, the first one present is used
+const LEVEL_KEYS: &[&str] = &["level", "severity", "lvl", "log.level"];
+const TIMESTAMP_KEYS: &[&str] = &["timestamp", "time", "@timestamp", "ts"];
+const MESSAGE_KEYS: &[&str] = &["message", "msg", "@message"];
+const APP_KEYS: &[&str] = &["app", "service", "application"];
+
+/// How lines from the files that match a rule are parsed
+#[derive(Debug)]
+pub struct JsonRule {
+    files: Box<dyn Rule + Send + Sync>,
+    meta: bool,
+    max_bytes: usize,
+}
+
+impl JsonRule {
+    /// Creates a rule for lines from files, keys other than the extracted fields are dropped
+    pub fn new<T: Rule + Send + Sync + 'static>(files: T) -> Self {
+        Self {
+            files: Box::new(files),
+            meta: false,
+            max_bytes: 64 * 1024,
+        }
+    }
+    /// Attaches the keys that weren't extracted as meta and replaces the line with it's message
+    pub fn meta(mut self, meta: bool) -> Self {
+        self.meta = meta;
+        self
+    }
+    /// Sets the longest line that is parsed, longer lines are sent as is
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+}
+
+/// Extracts the level, timestamp, message and app of JSON lines
+///
+/// Only the first JSON object of a line is parsed so trailing garbage, e.g a stray newline or a second object,
+/// is ignored. Lines that don't start with an object are sent as is.
+#[derive(Debug, Default)]
+pub struct JsonParser {
+    rules: Vec<JsonRule>,
+}
+
+impl JsonParser {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+        }
+    }
+    /// Adds a rule, the first rule that matches a line's file is used
+    pub fn add_rule(&mut self, rule: JsonRule) {
+        self.rules.push(rule)
+    }
+    /// Returns true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Middleware for JsonParser {
+    fn run(&self) {}
+
+    fn process(&self, mut line: LineBuilder) -> Status {
+        let rule = match line.file {
+            Some(ref file) => match self.rules.iter().find(|r| r.files.matches(file)) {
+                Some(v) => v,
+                None => return Status::Ok(line),
+            },
+            None => return Status::Ok(line),
+        };
+        let mut object = match line.line.as_deref().and_then(|l| parse(l, rule.max_bytes)) {
+            Some(v) => v,
+            None => return Status::Ok(line),
+        };
+
+        if let Some(Value::String(level)) = take(&mut object, LEVEL_KEYS) {
+            line.level = Some(level.to_uppercase());
+        }
+        if let Some(time) = take(&mut object, TIMESTAMP_KEYS).and_then(|v| parse_time(&v)) {
+            line = timestamp::set(line, time);
+        }
+        if let Some(Value::String(app)) = take(&mut object, APP_KEYS) {
+            line.app = Some(app);
+        }
+        if !rule.meta {
+            return Status::Ok(line);
+        }
+
+        if let Some(Value::String(message)) = take(&mut object, MESSAGE_KEYS) {
+            line.line = Some(message);
+        }
+        match line.meta {
+            // keys set by earlier middlewares win
+            Some(Value::Object(ref mut meta)) => {
+                for (key, value) in object {
+                    meta.entry(key).or_insert(value);
+                }
+            }
+            None => line.meta = Some(Value::Object(object)),
+            Some(_) => {}
+        }
+        Status::Ok(line)
+    }
+}
+
+// parses the object at the start of a line, ignoring anything after it
+fn parse(line: &str, max_bytes: usize) -> Option<Map<String, Value>> {
+    let line = line.trim_start();
+    if !line.starts_with('{') || line.len() > max_bytes {
+        return None;
+    }
+    Deserializer::from_str(line).into_iter::<Map<String, Value>>().next()?.ok()
+}
+
+// removes the value of the first key present
+fn take(object: &mut Map<String, Value>, keys: &[&str]) -> Option<Value> {
+    keys.iter().find_map(|k| object.remove(*k))
+}
+
+// parses an RFC 3339 time or epoch seconds or milliseconds
+fn parse_time(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(time) => DateTime::parse_from_rfc3339(time).ok().map(|t| t.with_timezone(&Utc)),
+        Value::Number(epoch) => {
+            let epoch = epoch.as_f64()?;
+            // anything past the year 5138 in seconds is taken as milliseconds
+            let millis = if epoch > 1e11 { epoch } else { epoch * 1000.0 };
+            let millis = millis as i64;
+            Utc.timestamp_opt(millis.div_euclid(1000), (millis.rem_euclid(1000) * 1_000_000) as u32).single()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fs::rule::GlobRule;
+
+    fn parser(meta: bool) -> JsonParser {
+        let mut parser = JsonParser::new();
+        parser.add_rule(JsonRule::new(GlobRule::new("/var/log/app/*").unwrap()).meta(meta).max_bytes(256));
+        parser
+    }
+
+    fn process(parser: &JsonParser, file: &str, line: &str) -> LineBuilder {
+        match parser.process(LineBuilder::new().file(file).line(line)) {
+            Status::Ok(line) => line,
+            Status::Skip(_) => panic!("line was skipped"),
+        }
+    }
+
+    #[test]
+    fn extracts_fields() {
+        let raw = r#"{"level": "warn", "ts": 1569931200123, "msg": "disk full", "service": "api", "disk": "/dev/sda"}"#;
+
+        let line = process(&parser(false), "/var/log/app/api.log", raw);
+        assert_eq!(line.level.as_deref(), Some("WARN"));
+        assert_eq!(line.app.as_deref(), Some("api"));
+        assert_eq!(timestamp::get(&line), Some(1_569_931_200));
+        // without meta the line is kept whole
+        assert_eq!(line.line.as_deref(), Some(raw));
+
+        let line = process(&parser(true), "/var/log/app/api.log", raw);
+        assert_eq!(line.line.as_deref(), Some("disk full"));
+        assert_eq!(line.meta.unwrap(), serde_json::json!({"disk": "/dev/sda", "__timestamp": 1_569_931_200}));
+    }
+
+    #[test]
+    fn keeps_existing_meta() {
+        let line = LineBuilder::new()
+            .file("/var/log/app/api.log")
+            .line(r#"{"message": "hi", "stream": "json", "user": "jane"}"#)
+            .meta(serde_json::json!({"stream": "stdout"}));
+        let line = match parser(true).process(line) {
+            Status::Ok(line) => line,
+            Status::Skip(_) => panic!("line was skipped"),
+        };
+        assert_eq!(line.meta.unwrap(), serde_json::json!({"stream": "stdout", "user": "jane"}));
+    }
+
+    #[test]
+    fn tolerates_other_lines() {
+        let parser = parser(true);
+
+        let line = process(&parser, "/var/log/app/api.log", "{\"msg\": \"first\"} {\"msg\": \"second\"}\r");
+        assert_eq!(line.line.as_deref(), Some("first"));
+
+        for raw in &["plain text", "{not json", "[1, 2]", &format!(r#"{{"msg": "{}"}}"#, "a".repeat(256))] {
+            assert_eq!(process(&parser, "/var/log/app/api.log", raw).line.as_deref(), Some(*raw));
+        }
+        // files without a rule aren't parsed
+        let raw = r#"{"msg": "other"}"#;
+        assert_eq!(process(&parser, "/var/log/other.log", raw).line.as_deref(), Some(raw));
+    }
+
+    #[test]
+    fn parses_times() {
+        let time = |v| parse_time(&v).map(|t| t.timestamp_millis());
+        assert_eq!(time(serde_json::json!("2019-10-01T12:00:00.5+02:00")), Some(1_569_924_000_500));
+        assert_eq!(time(serde_json::json!(1_569_931_200)), Some(1_569_931_200_000));
+        assert_eq!(time(serde_json::json!(1_569_931_200.25)), Some(1_569_931_200_250));
+        assert_eq!(time(serde_json::json!(1_569_931_200_123u64)), Some(1_569_931_200_123));
+        assert_eq!(time(serde_json::json!("yesterday")), None);
+    }
+}
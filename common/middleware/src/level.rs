@@ -0,0 +1,215 @@
+use std::str::FromStr;
+
+use regex::{Error as RegexError, Regex};
+
+use http::types::body::LineBuilder;
+
+use crate::{Middleware, Status};
+
+// turns what a pattern captured into a level
+type Map = fn(&str) -> Option<String>;
+
+/// The built in patterns, in the order they are tried by default
+pub const BUILTINS: &[Builtin] = &[Builtin::KeyValue, Builtin::Syslog, Builtin::Glog, Builtin::Bracket, Builtin::Word];
+
+/// A built in pattern for a common way of logging the level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    /// `level=info`, `severity: warn` or `"level":"error"`
+    KeyValue,
+    /// The priority at the start of a syslog line, e.g `<11>`
+    Syslog,
+    /// The severity letter that starts glog lines, e.g `E1017 12:00:00.000000`
+    Glog,
+    /// A level in brackets, e.g `[warn]` as nginx error logs write it
+    Bracket,
+    /// An upper case level word, e.g `ERROR`
+    Word,
+}
+
+impl Builtin {
+    /// Creates the pattern
+    pub fn pattern(self) -> LevelPattern {
+        let (regex, map): (&str, Map) = match self {
+            Builtin::KeyValue => (r#"(?i)\b(?:level|severity|lvl)"?\s*[=:]\s*"?(?P<level>[a-z]+)"#, normalize),
+            Builtin::Syslog => (r"^<(?P<level>\d{1,3})>", syslog),
+            Builtin::Glog => (r"^(?P<level>[IWEF])\d{4} \d{2}:\d{2}:\d{2}", glog),
+            Builtin::Bracket => (
+                r"(?i)\[(?P<level>trace|debug|info|notice|warn|warning|error|err|crit|critical|alert|emerg|fatal)\]",
+                normalize,
+            ),
+            Builtin::Word => (
+                r"\b(?P<level>TRACE|DEBUG|INFO|NOTICE|WARN|WARNING|ERROR|CRITICAL|ALERT|EMERGENCY|FATAL|SEVERE|PANIC)\b",
+                normalize,
+            ),
+        };
+        LevelPattern {
+            regex: Regex::new(regex).expect("Regex::new()"),
+            level: None,
+            map,
+        }
+    }
+}
+
+impl FromStr for Builtin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "key_value" => Ok(Builtin::KeyValue),
+            "syslog" => Ok(Builtin::Syslog),
+            "glog" => Ok(Builtin::Glog),
+            "bracket" => Ok(Builtin::Bracket),
+            "word" => Ok(Builtin::Word),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// A pattern that finds the level of a line
+#[derive(Debug)]
+pub struct LevelPattern {
+    regex: Regex,
+    // the level of every line that matches, instead of the one captured
+    level: Option<String>,
+    map: Map,
+}
+
+impl LevelPattern {
+    /// Creates a pattern from a regex with a `level` capture group, or any regex if level is set
+    ///
+    /// Captured levels are normalized, e.g warning to WARN, and unknown ones are upper cased
+    pub fn new(regex: &str, level: Option<String>) -> Result<Self, RegexError> {
+        Ok(Self {
+            regex: Regex::new(regex)?,
+            level: level.map(|l| l.to_uppercase()),
+            map: |level| normalize(level).or_else(|| Some(level.to_uppercase())),
+        })
+    }
+    // returns the level of a line if the pattern matches it
+    fn find(&self, line: &str) -> Option<String> {
+        if let Some(ref level) = self.level {
+            return match self.regex.is_match(line) {
+                true => Some(level.clone()),
+                false => None,
+            };
+        }
+        // later matches can still have a level, e.g lvl=dbug ... lvl=debug
+        self.regex.captures_iter(line).find_map(|c| (self.map)(c.name("level")?.as_str()))
+    }
+}
+
+/// Sets the level of lines that don't have one from patterns in the line
+///
+/// Patterns are tried in the order they were added and the first one that finds a level is used, lines that no
+/// pattern matches are left as is
+#[derive(Debug, Default)]
+pub struct LevelDetector {
+    patterns: Vec<LevelPattern>,
+}
+
+impl LevelDetector {
+    pub fn new() -> Self {
+        Self {
+            patterns: Vec::new(),
+        }
+    }
+    /// Creates a detector with every built in pattern
+    pub fn builtin() -> Self {
+        let mut detector = Self::new();
+        BUILTINS.iter().for_each(|b| detector.add_pattern(b.pattern()));
+        detector
+    }
+    /// Adds a pattern, tried after the ones already added
+    pub fn add_pattern(&mut self, pattern: LevelPattern) {
+        self.patterns.push(pattern)
+    }
+    /// Returns true if there are no patterns
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+    /// Returns the level of a line
+    pub fn detect(&self, line: &str) -> Option<String> {
+        self.patterns.iter().find_map(|p| p.find(line))
+    }
+}
+
+impl Middleware for LevelDetector {
+    fn run(&self) {}
+
+    fn process(&self, mut line: LineBuilder) -> Status {
+        if line.level.is_none() {
+            line.level = line.line.as_deref().and_then(|l| self.detect(l));
+        }
+        Status::Ok(line)
+    }
+}
+
+// maps the common names of levels to the ones the agent sends
+fn normalize(level: &str) -> Option<String> {
+    let level = match level.to_lowercase().as_str() {
+        "trace" => "TRACE",
+        "debug" | "dbug" => "DEBUG",
+        "info" | "information" => "INFO",
+        "notice" => "NOTICE",
+        "warn" | "warning" => "WARN",
+        "error" | "err" | "eror" => "ERROR",
+        "crit" | "critical" => "CRITICAL",
+        "alert" => "ALERT",
+        "emerg" | "emergency" => "EMERGENCY",
+        "fatal" | "severe" | "panic" => "FATAL",
+        _ => return None,
+    };
+    Some(level.to_string())
+}
+
+// the severity is the lowest 3 bits of a syslog priority
+fn syslog(priority: &str) -> Option<String> {
+    let priority: u8 = priority.parse().ok()?;
+    if priority > 191 {
+        return None;
+    }
+    let level = ["EMERGENCY", "ALERT", "CRITICAL", "ERROR", "WARN", "NOTICE", "INFO", "DEBUG"][(priority % 8) as usize];
+    Some(level.to_string())
+}
+
+fn glog(severity: &str) -> Option<String> {
+    let level = match severity {
+        "I" => "INFO",
+        "W" => "WARN",
+        "E" => "ERROR",
+        "F" => "FATAL",
+        _ => return None,
+    };
+    Some(level.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_precedence() {
+        // key value pairs win over words in the message
+        assert_eq!(LevelDetector::builtin().detect("level=info msg=\"retrying after ERROR\"").as_deref(), Some("INFO"));
+
+        let mut detector = LevelDetector::new();
+        detector.add_pattern(LevelPattern::new(r"^!!", Some("alert".to_string())).unwrap());
+        detector.add_pattern(LevelPattern::new(r"^(?P<level>[a-z]+):", None).unwrap());
+        detector.add_pattern(Builtin::Word.pattern());
+        assert_eq!(detector.detect("!! ERROR").as_deref(), Some("ALERT"));
+        assert_eq!(detector.detect("warning: ERROR").as_deref(), Some("WARN"));
+        assert_eq!(detector.detect("audit: ERROR").as_deref(), Some("AUDIT"));
+        assert_eq!(detector.detect("no level here"), None);
+    }
+
+    #[test]
+    fn keeps_existing_levels() {
+        let detector = LevelDetector::builtin();
+        let line = LineBuilder::new().line("ERROR failed").level("DEBUG");
+        match detector.process(line) {
+            Status::Ok(line) => assert_eq!(line.level.as_deref(), Some("DEBUG")),
+            Status::Skip(_) => panic!("line was skipped"),
+        }
+    }
+}
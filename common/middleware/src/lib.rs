@@ -1,9 +1,22 @@
 use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
 
-use crossbeam::{bounded, Receiver, Sender};
-use crossbeam::scope;
+use crossbeam::{bounded, select, tick, Receiver, Sender};
 
 use http::types::body::LineBuilder;
+use metrics::health::{HEALTH, HEARTBEAT_INTERVAL};
+use metrics::{IntCounter, IntGauge};
+
+pub mod json;
+pub mod level;
+pub mod line_filter;
+pub mod multiline;
+pub mod redact;
+pub mod timestamp;
+
+// How often the middlewares are asked for the lines they held back, e.g partial multiline events that timed out
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
 
 pub enum Status {
     Ok(LineBuilder),
@@ -13,6 +26,12 @@ pub enum Status {
 pub trait Middleware: Send + Sync + 'static {
     fn run(&self);
     fn process(&self, line: LineBuilder) -> Status;
+    /// Returns the lines the middleware held back that are ready to continue, or every one of them if all is set
+    ///
+    /// Called periodically, and with all set once no more lines can arrive
+    fn flush(&self, _all: bool) -> Vec<LineBuilder> {
+        Vec::new()
+    }
 }
 
 pub struct Executor {
@@ -21,16 +40,30 @@ pub struct Executor {
 
     line_sender: Sender<LineBuilder>,
     line_receiver: Receiver<LineBuilder>,
+    // the number of lines waiting to be processed
+    queued: IntGauge,
+    sent: IntCounter,
+    skipped: IntCounter,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Executor {
     pub fn new() -> Executor {
-        let (s, r) = bounded(256);
+        let capacity = 256;
+        let (s, r) = bounded(capacity);
         Executor {
             middlewares: Vec::new(),
             senders: Vec::new(),
             line_sender: s,
             line_receiver: r,
+            queued: metrics::channel("executor", capacity),
+            sent: metrics::MIDDLEWARE_LINES.with_label_values(&["sent"]),
+            skipped: metrics::MIDDLEWARE_LINES.with_label_values(&["skipped"]),
         }
     }
 
@@ -46,46 +79,132 @@ impl Executor {
         self.line_sender.clone()
     }
 
-    pub fn run(self) {
-        scope(|s| {
-            s.spawn(|_| self.process());
-            s.spawn(|s| {
-                for middleware in &self.middlewares {
-                    let middleware = middleware.clone();
-                    s.spawn(move |_| middleware.run());
-                }
-            });
-        }).expect("Executor::run()");
+    /// Runs the executor until every sender of lines has been dropped and all lines have been processed
+    ///
+    /// The middlewares' background work runs detached, so it doesn't hold up shutdown
+    pub fn run(mut self) {
+        // drop our own sender so the channel disconnects once everyone else's is gone
+        self.line_sender = bounded(0).0;
+
+        for middleware in &self.middlewares {
+            let middleware = middleware.clone();
+            spawn(move || middleware.run());
+        }
+
+        self.process();
     }
 
     fn process(&self) {
+        let heartbeat = HEALTH.register("executor");
+        let heartbeat_tick = tick(HEARTBEAT_INTERVAL);
+        let flush_tick = tick(FLUSH_INTERVAL);
+
         loop {
-            let mut line = self.line_receiver.recv().unwrap();
-            let mut skipped = false;
-
-            for middleware in &self.middlewares {
-                match middleware.process(line) {
-                    Status::Ok(v) => {
-                        line = v;
-                    }
-                    Status::Skip(v) => {
-                        line = v;
-                        skipped = true;
-                        break;
-                    }
-                }
+            let line = select! {
+                recv(self.line_receiver) -> line => match line {
+                    Ok(v) => v,
+                    // no more lines can arrive
+                    Err(_) => break,
+                },
+                recv(heartbeat_tick) -> _ => {
+                    heartbeat.beat();
+                    continue;
+                },
+                recv(flush_tick) -> _ => {
+                    self.flush(false);
+                    continue;
+                },
             };
+            self.queued.set(self.line_receiver.len() as i64);
+            self.forward(line, &self.middlewares);
+        }
 
-            if skipped {
-                continue;
+        self.flush(true);
+    }
+    // sends the lines the middlewares held back on through the middlewares after them
+    fn flush(&self, all: bool) {
+        for (i, middleware) in self.middlewares.iter().enumerate() {
+            for line in middleware.flush(all) {
+                self.forward(line, &self.middlewares[i + 1..]);
             }
-
-            match self.senders.len() {
-                0 => { self.senders.get(0).unwrap().send(line).unwrap() }
-                _ => {
-                    self.senders.iter().for_each(|s| s.send(line.clone()).unwrap())
+        }
+    }
+    // runs a line through middlewares, sending it on unless one of them skips it
+    fn forward(&self, mut line: LineBuilder, middlewares: &[Arc<dyn Middleware>]) {
+        for middleware in middlewares {
+            match middleware.process(line) {
+                Status::Ok(v) => {
+                    line = v;
+                }
+                Status::Skip(_) => {
+                    self.skipped.inc();
+                    return;
                 }
             }
+        };
+        self.sent.inc();
+
+        match self.senders.len() {
+            0 => { self.senders.first().unwrap().send(line).unwrap() }
+            _ => {
+                self.senders.iter().for_each(|s| s.send(line.clone()).unwrap())
+            }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crossbeam::unbounded;
+
+    // holds back every line until it's flushed
+    struct Hold(Mutex<Vec<LineBuilder>>);
+
+    impl Middleware for Hold {
+        fn run(&self) {}
+
+        fn process(&self, line: LineBuilder) -> Status {
+            self.0.lock().unwrap().push(line.clone());
+            Status::Skip(line)
+        }
+
+        fn flush(&self, all: bool) -> Vec<LineBuilder> {
+            match all {
+                true => self.0.lock().unwrap().drain(..).collect(),
+                false => Vec::new(),
+            }
+        }
+    }
+
+    // appends to every line
+    struct Append;
+
+    impl Middleware for Append {
+        fn run(&self) {}
+
+        fn process(&self, line: LineBuilder) -> Status {
+            let value = format!("{} appended", line.line.as_deref().unwrap_or(""));
+            Status::Ok(line.line(value))
+        }
+    }
+
+    #[test]
+    fn flushes_held_lines_on_shutdown() {
+        let mut executor = Executor::new();
+        executor.register(Hold(Mutex::new(Vec::new())));
+        executor.register(Append);
+        let (sender, receiver) = unbounded();
+        executor.add_sender(sender);
+        executor.sender().send(LineBuilder::new().line("held")).unwrap();
+
+        // the executor's own sender is dropped when it runs, so it stops once the held line is flushed
+        executor.run();
+        let lines: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].line.as_deref(), Some("held appended"));
+    }
 }
\ No newline at end of file
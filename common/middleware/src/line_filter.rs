@@ -0,0 +1,182 @@
+use fs::rule::Rule;
+use http::types::body::LineBuilder;
+use metrics::IntCounter;
+
+use crate::{Middleware, Status};
+
+/// A rule matched against the content of lines, optionally only for lines from some files
+#[derive(Debug)]
+pub struct LineRule {
+    name: String,
+    rule: Box<dyn Rule + Send + Sync>,
+    // the files the rule applies to, every file if None
+    files: Option<Box<dyn Rule + Send + Sync>>,
+    // the lines this rule dropped, labelled with the rule's name
+    dropped: IntCounter,
+}
+
+impl LineRule {
+    /// Creates a rule that applies to lines from every file, name identifies it in the drop counts
+    pub fn new<N: Into<String>, T: Rule + Send + Sync + 'static>(name: N, rule: T) -> Self {
+        let name = name.into();
+        Self {
+            dropped: metrics::LINE_FILTER_DROPPED.with_label_values(&[&name]),
+            name,
+            rule: Box::new(rule),
+            files: None,
+        }
+    }
+    /// Limits the rule to lines from files that match files
+    pub fn files<T: Rule + Send + Sync + 'static>(mut self, files: T) -> Self {
+        self.files = Some(Box::new(files));
+        self
+    }
+    /// Returns the name of the rule
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    // returns true if the rule applies to lines from file
+    fn applies(&self, file: Option<&str>) -> bool {
+        match self.files {
+            Some(ref files) => file.is_some_and(|f| files.matches(f)),
+            None => true,
+        }
+    }
+}
+
+/// Drops lines by their content, with the same semantics as the rules for file paths
+///
+/// A line has to match one of the inclusion rules that apply to it's file, if there are any, and none of the
+/// exclusion rules. Every rule counts the lines it dropped, a line that matched no inclusion rule is counted
+/// against each inclusion rule that applied to it.
+#[derive(Debug, Default)]
+pub struct LineFilter {
+    inclusion: Vec<LineRule>,
+    exclusion: Vec<LineRule>,
+}
+
+impl LineFilter {
+    pub fn new() -> Self {
+        Self {
+            inclusion: Vec::new(),
+            exclusion: Vec::new(),
+        }
+    }
+    /// Adds an inclusion rule
+    pub fn add_inclusion(&mut self, rule: LineRule) {
+        self.inclusion.push(rule)
+    }
+    /// Adds an exclusion rule
+    pub fn add_exclusion(&mut self, rule: LineRule) {
+        self.exclusion.push(rule)
+    }
+    /// Returns true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.inclusion.is_empty() && self.exclusion.is_empty()
+    }
+    /// Getter for inclusion list
+    pub fn inclusion_list(&self) -> &[LineRule] {
+        &self.inclusion
+    }
+    /// Getter for exclusion list
+    pub fn exclusion_list(&self) -> &[LineRule] {
+        &self.exclusion
+    }
+    /// Returns true if a line from file passes the rules, counting the drop otherwise
+    pub fn passes(&self, file: Option<&str>, line: &str) -> bool {
+        let mut applied = self.inclusion.iter().filter(|r| r.applies(file)).peekable();
+        if applied.peek().is_some() {
+            let applied: Vec<_> = applied.collect();
+            if !applied.iter().any(|r| r.rule.matches(line)) {
+                applied.iter().for_each(|r| r.dropped.inc());
+                return false;
+            }
+        }
+
+        for rule in &self.exclusion {
+            if rule.applies(file) && rule.rule.matches(line) {
+                rule.dropped.inc();
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Middleware for LineFilter {
+    fn run(&self) {}
+
+    fn process(&self, line: LineBuilder) -> Status {
+        let passes = match line.line {
+            Some(ref value) => self.passes(line.file.as_deref(), value),
+            None => true,
+        };
+        match passes {
+            true => Status::Ok(line),
+            false => Status::Skip(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fs::rule::{GlobRule, RegexRule};
+
+    const NGINX: Option<&str> = Some("/var/log/nginx/access.log");
+    const APP: Option<&str> = Some("/var/log/app.log");
+
+    // the drop counts are shared by every filter, so each test names it's rules differently
+    fn dropped(rule: &str) -> u64 {
+        metrics::LINE_FILTER_DROPPED.with_label_values(&[rule]).get()
+    }
+
+    #[test]
+    fn filters_lines() {
+        let mut filter = LineFilter::new();
+        filter.add_exclusion(LineRule::new("filters_healthz", RegexRule::new("GET /healthz").unwrap()));
+        filter.add_exclusion(LineRule::new("filters_debug", GlobRule::new("DEBUG *").unwrap()));
+
+        assert!(filter.passes(APP, "GET /index.html 200"));
+        assert!(!filter.passes(APP, "10.0.0.1 GET /healthz 200"));
+        assert!(!filter.passes(None, "DEBUG connecting"));
+        assert!(!filter.passes(NGINX, "DEBUG connecting"));
+
+        assert_eq!(dropped("filters_healthz"), 1);
+        assert_eq!(dropped("filters_debug"), 2);
+    }
+
+    #[test]
+    fn scopes_rules_to_files() {
+        let mut filter = LineFilter::new();
+        filter.add_exclusion(
+            LineRule::new("scoped_healthz", RegexRule::new("GET /healthz").unwrap())
+                .files(GlobRule::new("/var/log/nginx/*").unwrap())
+        );
+        filter.add_inclusion(
+            LineRule::new("scoped_errors", RegexRule::new("ERROR").unwrap())
+                .files(GlobRule::new("/var/log/app.log").unwrap())
+        );
+
+        assert!(!filter.passes(NGINX, "GET /healthz 200"));
+        assert!(filter.passes(APP, "ERROR GET /healthz 200"));
+        assert!(!filter.passes(APP, "INFO started"));
+        // inclusion rules only drop lines from the files they apply to
+        assert!(filter.passes(NGINX, "INFO started"));
+        assert!(filter.passes(None, "INFO started"));
+
+        assert_eq!(dropped("scoped_healthz"), 1);
+        assert_eq!(dropped("scoped_errors"), 1);
+    }
+
+    #[test]
+    fn skips_filtered_lines() {
+        let mut filter = LineFilter::new();
+        filter.add_exclusion(LineRule::new("skips_healthz", RegexRule::new("healthz").unwrap()));
+
+        let line = |value| LineBuilder::new().file("/var/log/app.log").line(value);
+        assert!(matches!(filter.process(line("GET /healthz")), Status::Skip(_)));
+        assert!(matches!(filter.process(line("GET /")), Status::Ok(_)));
+    }
+}
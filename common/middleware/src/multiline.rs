@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+use fs::multiline::{Aggregator, MultilineRules};
+use http::types::body::LineBuilder;
+
+use crate::{Middleware, Status};
+
+/// Merges consecutive lines of a file into multiline events, e.g stack traces, see fs::multiline
+///
+/// Lines are held back until their event is complete, which is when a line that doesn't belong to it arrives,
+/// it reaches it's max lines or no line has been added to it for it's timeout.
+/// Register it after the container log middleware, so lines written by a container runtime are merged by
+/// the message the container logged.
+pub struct MultilineMerger {
+    aggregator: Mutex<Aggregator>,
+}
+
+impl MultilineMerger {
+    pub fn new(rules: MultilineRules) -> Self {
+        Self {
+            aggregator: Mutex::new(Aggregator::new(rules)),
+        }
+    }
+    /// Returns true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        !self.aggregator.lock().expect("aggregator lock poisoned").is_enabled()
+    }
+}
+
+impl Middleware for MultilineMerger {
+    fn run(&self) {}
+
+    fn process(&self, line: LineBuilder) -> Status {
+        let mut aggregator = self.aggregator.lock().expect("aggregator lock poisoned");
+        match aggregator.push(line.clone(), Instant::now()) {
+            Some(v) => Status::Ok(v),
+            None => Status::Skip(line),
+        }
+    }
+
+    fn flush(&self, all: bool) -> Vec<LineBuilder> {
+        let mut aggregator = self.aggregator.lock().expect("aggregator lock poisoned");
+        match all {
+            true => aggregator.flush_all(),
+            false => aggregator.flush_expired(Instant::now()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fs::multiline::{Multiline, Preset};
+    use fs::rule::GlobRule;
+
+    #[test]
+    fn holds_lines_until_event_completes() {
+        let mut rules = MultilineRules::new();
+        rules.add(GlobRule::new("*.log").unwrap(), Multiline::preset(Preset::Java));
+        let merger = MultilineMerger::new(rules);
+        let process = |line: &str| match merger.process(LineBuilder::new().file("/var/log/app.log").line(line)) {
+            Status::Ok(line) => line.line,
+            Status::Skip(_) => None,
+        };
+
+        assert_eq!(process("java.lang.RuntimeException: boom"), None);
+        assert_eq!(process("\tat com.example.App.main(App.java:5)"), None);
+        assert_eq!(
+            process("next").as_deref(),
+            Some("java.lang.RuntimeException: boom\n\tat com.example.App.main(App.java:5)")
+        );
+        assert!(merger.flush(false).is_empty());
+        assert_eq!(merger.flush(true)[0].line.as_deref(), Some("next"));
+    }
+}
@@ -0,0 +1,404 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+use fs::rule::Rule;
+use http::timestamp as logged_at;
+use http::types::body::LineBuilder;
+
+use crate::{Middleware, Status};
+
+// epoch seconds outside 2000 to 2100 are more likely ids or counters than times
+const MIN_EPOCH: i64 = 946_684_800;
+const MAX_EPOCH: i64 = 4_102_444_800;
+
+const MONTHS: [&[u8]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+
+/// A format timestamps are written in
+///
+/// Every format but CLF is only looked for at the start of a line, after a syslog priority or an opening bracket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    /// `2019-10-17T12:00:00.123Z`, a space can separate the date and time and without an offset UTC is assumed
+    Rfc3339,
+    /// `Oct 17 12:00:00`, without a year so the latest one that isn't in the future is assumed
+    Syslog,
+    /// `[17/Oct/2019:12:00:00 +0000]` as written by Apache and nginx, anywhere in the line
+    Clf,
+    /// Seconds or milliseconds since the epoch, e.g `1571313600.123` or `1571313600123`
+    Epoch,
+    /// A strftime format, e.g `%d/%m/%Y %H:%M:%S`, without an offset UTC is assumed
+    Strftime(String),
+}
+
+/// The built in formats, in the order they are tried by default
+pub const BUILTINS: &[Format] = &[Format::Rfc3339, Format::Syslog, Format::Clf, Format::Epoch];
+
+impl Format {
+    // returns the time of a line written in this format
+    fn parse(&self, line: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Format::Rfc3339 => rfc3339(start(line).as_bytes()),
+            Format::Syslog => syslog(start(line).as_bytes(), now),
+            Format::Clf => clf(line),
+            Format::Epoch => epoch(start(line).as_bytes()),
+            Format::Strftime(format) => strftime(start(line), format),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc3339" => Ok(Format::Rfc3339),
+            "syslog" => Ok(Format::Syslog),
+            "clf" => Ok(Format::Clf),
+            "epoch" => Ok(Format::Epoch),
+            _ if s.contains('%') => Ok(Format::Strftime(s.to_string())),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+/// The formats of lines from files that match a rule
+#[derive(Debug)]
+pub struct TimestampRule {
+    files: Box<dyn Rule + Send + Sync>,
+    formats: Vec<Format>,
+}
+
+impl TimestampRule {
+    pub fn new<T: Rule + Send + Sync + 'static>(files: T, formats: Vec<Format>) -> Self {
+        Self {
+            files: Box::new(files),
+            formats,
+        }
+    }
+}
+
+/// Sets the timestamp of lines to the time in the line, so lines that are sent late keep the time they were logged at
+///
+/// Lines from files with a rule try the rule's formats first, then every line tries the default formats. Lines
+/// without a recognised time, or that already have a timestamp, are left as is and get the time they are sent at.
+#[derive(Debug)]
+pub struct TimestampParser {
+    rules: Vec<TimestampRule>,
+    defaults: Vec<Format>,
+}
+
+impl Default for TimestampParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimestampParser {
+    /// Creates a parser that tries the built in formats
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            defaults: BUILTINS.to_vec(),
+        }
+    }
+    /// Adds a rule, the first rule that matches a line's file is used
+    pub fn add_rule(&mut self, rule: TimestampRule) {
+        self.rules.push(rule)
+    }
+    /// Sets the formats tried for every line
+    pub fn set_defaults(&mut self, formats: Vec<Format>) {
+        self.defaults = formats;
+    }
+    /// Returns true if no format is ever tried
+    pub fn is_empty(&self) -> bool {
+        self.defaults.is_empty() && self.rules.iter().all(|r| r.formats.is_empty())
+    }
+    /// Returns the time of a line from file
+    pub fn parse(&self, file: Option<&str>, line: &str) -> Option<DateTime<Utc>> {
+        self.parse_at(file, line, Utc::now())
+    }
+    fn parse_at(&self, file: Option<&str>, line: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let rule = file.and_then(|f| self.rules.iter().find(|r| r.files.matches(f)));
+        rule.into_iter()
+            .flat_map(|r| r.formats.iter())
+            .chain(self.defaults.iter())
+            .find_map(|f| f.parse(line, now))
+    }
+}
+
+impl Middleware for TimestampParser {
+    fn run(&self) {}
+
+    fn process(&self, mut line: LineBuilder) -> Status {
+        if logged_at::get(&line).is_some() {
+            return Status::Ok(line);
+        }
+        if let Some(time) = line.line.as_deref().and_then(|l| self.parse(line.file.as_deref(), l)) {
+            line = logged_at::set(line, time);
+        }
+        Status::Ok(line)
+    }
+}
+
+// skips a syslog priority and version, e.g <34>1, or an opening bracket
+fn start(line: &str) -> &str {
+    let mut line = line;
+    if line.starts_with('<') {
+        if let Some(end) = line.bytes().take(5).position(|b| b == b'>') {
+            line = &line[end + 1..];
+            if line.starts_with("1 ") {
+                line = &line[2..];
+            }
+        }
+    }
+    line.strip_prefix('[').unwrap_or(line)
+}
+
+// parses n digits at the start of b
+fn digits(b: &[u8], n: usize) -> Option<u32> {
+    if b.len() < n {
+        return None;
+    }
+    b[..n].iter().try_fold(0, |v, d| match d {
+        b'0'..=b'9' => Some(v * 10 + u32::from(d - b'0')),
+        _ => None,
+    })
+}
+
+// parses a fraction of a second, returning the nanoseconds and the digits read
+fn fraction(b: &[u8]) -> (u32, usize) {
+    let len = b.iter().take_while(|d| d.is_ascii_digit()).count();
+    let mut nanos = 0;
+    for i in 0..9 {
+        nanos = nanos * 10 + b.get(i).filter(|_| i < len).map_or(0, |d| u32::from(d - b'0'));
+    }
+    (nanos, len)
+}
+
+fn month(b: &[u8]) -> Option<u32> {
+    let month = b.get(..3)?;
+    MONTHS.iter().position(|m| *m == month).map(|m| m as u32 + 1)
+}
+
+// parses HH:MM:SS
+fn time(b: &[u8]) -> Option<(u32, u32, u32)> {
+    if b.len() < 8 || b[2] != b':' || b[5] != b':' {
+        return None;
+    }
+    Some((digits(b, 2)?, digits(&b[3..], 2)?, digits(&b[6..], 2)?))
+}
+
+fn utc(date: NaiveDate, (hour, minute, second): (u32, u32, u32), nanos: u32, offset: i64) -> Option<DateTime<Utc>> {
+    let time = date.and_hms_nano_opt(hour, minute, second, nanos)?;
+    Some(DateTime::from_utc(time - Duration::seconds(offset), Utc))
+}
+
+fn rfc3339(b: &[u8]) -> Option<DateTime<Utc>> {
+    if b.len() < 19 || b[4] != b'-' || b[7] != b'-' || !matches!(b[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    let date = NaiveDate::from_ymd_opt(digits(b, 4)? as i32, digits(&b[5..], 2)?, digits(&b[8..], 2)?)?;
+    let time = time(&b[11..])?;
+
+    let mut i = 19;
+    let mut nanos = 0;
+    if matches!(b.get(i), Some(b'.') | Some(b',')) {
+        let (n, len) = fraction(&b[i + 1..]);
+        nanos = n;
+        i += len + 1;
+    }
+    let offset = match b.get(i) {
+        Some(b'Z') | Some(b'z') => 0,
+        Some(sign @ b'+') | Some(sign @ b'-') => {
+            let b = &b[i + 1..];
+            let hours = digits(b, 2)?;
+            let minutes = match b.get(2) {
+                Some(b':') => digits(&b[3..], 2)?,
+                _ => digits(&b[2..], 2)?,
+            };
+            let offset = i64::from(hours * 3600 + minutes * 60);
+            if *sign == b'-' { -offset } else { offset }
+        }
+        _ => 0,
+    };
+    utc(date, time, nanos, offset)
+}
+
+fn syslog(b: &[u8], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if b.len() < 15 || b[3] != b' ' || b[6] != b' ' {
+        return None;
+    }
+    let month = month(b)?;
+    // single digit days are padded with a space
+    let day = match b[4] {
+        b' ' => digits(&b[5..], 1)?,
+        _ => digits(&b[4..], 2)?,
+    };
+    let time = time(&b[7..])?;
+
+    let nanos = match b.get(15) {
+        Some(b'.') => fraction(&b[16..]).0,
+        _ => 0,
+    };
+    let parsed = utc(NaiveDate::from_ymd_opt(now.year(), month, day)?, time, nanos, 0)?;
+    // lines from late december read in january are from last year
+    if parsed > now + Duration::days(1) {
+        return utc(NaiveDate::from_ymd_opt(now.year() - 1, month, day)?, time, nanos, 0);
+    }
+    Some(parsed)
+}
+
+// finds a time like [17/Oct/2019:12:00:00 +0000] anywhere in the line
+fn clf(line: &str) -> Option<DateTime<Utc>> {
+    line.match_indices('[').find_map(|(i, _)| {
+        let b = &line.as_bytes()[i + 1..];
+        if b.len() < 27 || b[2] != b'/' || b[6] != b'/' || b[11] != b':' || b[20] != b' ' || b[26] != b']' {
+            return None;
+        }
+        let date = NaiveDate::from_ymd_opt(digits(&b[7..], 4)? as i32, month(&b[3..])?, digits(b, 2)?)?;
+        let offset = i64::from(digits(&b[22..], 2)? * 3600 + digits(&b[24..], 2)? * 60);
+        let offset = match b[21] {
+            b'+' => offset,
+            b'-' => -offset,
+            _ => return None,
+        };
+        utc(date, time(&b[12..])?, 0, offset)
+    })
+}
+
+fn epoch(b: &[u8]) -> Option<DateTime<Utc>> {
+    let len = b.iter().take_while(|d| d.is_ascii_digit()).count();
+    if len != 10 && len != 13 {
+        return None;
+    }
+    let number: i64 = std::str::from_utf8(&b[..len]).ok()?.parse().ok()?;
+    let (seconds, nanos) = match len {
+        10 => {
+            let nanos = match b.get(10) {
+                Some(b'.') => fraction(&b[11..]).0,
+                _ => 0,
+            };
+            (number, nanos)
+        }
+        _ => (number / 1000, (number % 1000) as u32 * 1_000_000),
+    };
+    if !(MIN_EPOCH..=MAX_EPOCH).contains(&seconds) {
+        return None;
+    }
+    Utc.timestamp_opt(seconds, nanos).single()
+}
+
+// parses the start of a line with a strftime format, taking as many whitespace separated parts as the format has
+fn strftime(line: &str, format: &str) -> Option<DateTime<Utc>> {
+    let parts = format.split_whitespace().count();
+    let mut end = 0;
+    for (i, part) in line.split_whitespace().take(parts).enumerate() {
+        if i + 1 == parts {
+            end = part.as_ptr() as usize - line.as_ptr() as usize + part.len();
+        }
+    }
+    if end == 0 {
+        return None;
+    }
+
+    // the time is often followed by punctuation, e.g 17/10/2019 12:00:00: message
+    let candidates = [&line[..end], line[..end].trim_end_matches([']', ',', ':', '|'])];
+    candidates.iter().find_map(|time| {
+        if let Ok(time) = DateTime::parse_from_str(time, format) {
+            return Some(time.with_timezone(&Utc));
+        }
+        NaiveDateTime::parse_from_str(time, format).ok().map(|t| DateTime::from_utc(t, Utc))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fs::rule::GlobRule;
+
+    fn millis(line: &str) -> Option<i64> {
+        let now = Utc.ymd(2019, 10, 17).and_hms(13, 0, 0);
+        TimestampParser::new().parse_at(None, line, now).map(|t| t.timestamp_millis())
+    }
+
+    #[test]
+    fn parses_builtin_formats() {
+        let expected = Some(1_571_313_600_000);
+        assert_eq!(millis("2019-10-17T12:00:00Z starting"), expected);
+        assert_eq!(millis("2019-10-17 14:00:00+02:00 starting"), expected);
+        assert_eq!(millis("[2019-10-17T07:00:00-0500] starting"), expected);
+        assert_eq!(millis("2019-10-17 12:00:00,250 INFO starting"), Some(1_571_313_600_250));
+        assert_eq!(millis("<34>1 2019-10-17T12:00:00.123456Z host app - - starting"), Some(1_571_313_600_123));
+
+        assert_eq!(millis("Oct 17 12:00:00 host app[1]: starting"), expected);
+        assert_eq!(millis("<13>Oct  7 12:00:00 host app[1]: starting"), Some(1_570_449_600_000));
+        // syslog times in the future are from last year
+        assert_eq!(millis("Dec 31 12:00:00 host app[1]: starting"), Some(1_546_257_600_000));
+
+        assert_eq!(millis(r#"10.0.0.1 - - [17/Oct/2019:14:00:00 +0200] "GET / HTTP/1.1" 200 612"#), expected);
+
+        assert_eq!(millis("1571313600 starting"), expected);
+        assert_eq!(millis("1571313600.5 starting"), Some(1_571_313_600_500));
+        assert_eq!(millis("1571313600250 starting"), Some(1_571_313_600_250));
+    }
+
+    #[test]
+    fn falls_back_gracefully() {
+        for line in &[
+            "",
+            "starting",
+            "2019-13-17T12:00:00Z month out of range",
+            "2019-10-17 date without a time",
+            "Foo 17 12:00:00 not a month",
+            "[17/Oct/2019:12:00:00] clf without an offset",
+            "12345 not an epoch",
+            "0000000001 too early",
+            "9999999999 too late",
+        ] {
+            assert_eq!(millis(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_strftime_formats_per_file() {
+        let mut parser = TimestampParser::new();
+        parser.set_defaults(vec![Format::Rfc3339]);
+        parser.add_rule(TimestampRule::new(
+            GlobRule::new("/var/log/app/*").unwrap(),
+            vec!["%d/%m/%Y %H:%M:%S".parse().unwrap(), "%b %d, %Y %I:%M:%S %p %z".parse().unwrap()],
+        ));
+
+        let app = Some("/var/log/app/app.log");
+        let parse = |file, line| parser.parse(file, line).map(|t| t.timestamp());
+        assert_eq!(parse(app, "17/10/2019 12:00:00: starting"), Some(1_571_313_600));
+        assert_eq!(parse(app, "[17/10/2019 12:00:00] starting"), Some(1_571_313_600));
+        assert_eq!(parse(app, "Oct 17, 2019 02:00:00 PM +0200 starting"), Some(1_571_313_600));
+        // the defaults still apply
+        assert_eq!(parse(app, "2019-10-17T12:00:00Z starting"), Some(1_571_313_600));
+        // formats of a rule only apply to it's files
+        assert_eq!(parse(Some("/var/log/other.log"), "17/10/2019 12:00:00 starting"), None);
+        assert_eq!(parse(app, "17/10/2019"), None);
+
+        assert!("%Y".parse::<Format>().is_ok());
+        assert!("iso8601".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn keeps_existing_timestamps() {
+        let parser = TimestampParser::new();
+        let line = logged_at::set(LineBuilder::new().line("2019-10-17T12:00:00Z"), Utc.timestamp(1, 0));
+        match parser.process(line) {
+            Status::Ok(line) => assert_eq!(logged_at::get(&line), Some(1)),
+            Status::Skip(_) => panic!("line was skipped"),
+        }
+
+        match parser.process(LineBuilder::new().line("2019-10-17T12:00:00Z")) {
+            Status::Ok(line) => assert_eq!(logged_at::get(&line), Some(1_571_313_600)),
+            Status::Skip(_) => panic!("line was skipped"),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use middleware::level::LevelDetector;
+
+#[test]
+fn detects_corpus_levels() {
+    let detector = LevelDetector::builtin();
+
+    let corpus = include_str!("corpus/levels.txt");
+    let mut failures = Vec::new();
+    for line in corpus.lines().filter(|l| !l.starts_with('#')) {
+        let (expected, line) = line.split_at(line.find('\t').expect("corpus line without a tab"));
+        let expected = match expected {
+            "-" => None,
+            level => Some(level),
+        };
+
+        let detected = detector.detect(&line[1..]);
+        if detected.as_deref() != expected {
+            failures.push(format!("{:?} was detected as {:?} instead of {:?}", &line[1..], detected, expected));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}